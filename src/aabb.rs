@@ -3,6 +3,7 @@ use vec3::*;
 use ray::*;
 
 #[derive(Clone, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct AABB {
     min: Vec3,
     max: Vec3
@@ -78,6 +79,7 @@ impl AABB {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     #[test]
@@ -0,0 +1,208 @@
+use crate::*;
+use vec3::*;
+use sampler::SamplerKind;
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+// Progressive, adaptive rendering. The image is rendered in passes of a few
+// samples per pixel, so it can be saved or stopped between any two of them.
+// Every pixel keeps a running mean and variance of its samples' luminance.
+// With a threshold, passes after the first go only to pixels whose estimate is
+// still noisier than it, the noisiest first, until they converge or the
+// image's sample budget is spent.
+
+#[derive(Copy, Clone)]
+pub struct PixelEstimate {
+    pub sum: Color3,
+    pub samples: u32,
+    pub mean: f64,
+    pub m2: f64
+}
+
+impl PixelEstimate {
+    pub fn new() -> Self {
+        Self {
+            sum: Vec3::new(),
+            samples: 0,
+            mean: 0.0,
+            m2: 0.0
+        }
+    }
+
+    pub fn add(&mut self, col: &Color3) {
+        self.sum += *col;
+        self.samples += 1;
+
+        //Welford's update of the luminance statistics
+        let y = spectrum::luminance(col);
+        let delta = y - self.mean;
+        self.mean += delta / self.samples as f64;
+        self.m2 += delta * (y - self.mean);
+    }
+
+    pub fn color(&self) -> Color3 {
+        if self.samples == 0 { Vec3::new() } else { self.sum / self.samples as f64 }
+    }
+
+    // Standard error of the mean luminance relative to the mean. Dark pixels
+    // are measured against a floor so they don't soak up the whole budget.
+    pub fn relative_error(&self) -> f64 {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+        let variance = self.m2 / (self.samples - 1) as f64;
+        (variance / self.samples as f64).sqrt() / self.mean.max(0.01)
+    }
+}
+
+pub struct AdaptiveSampling {
+    pub sampler: SamplerKind,
+    pub samples_per_pixel: u32,
+    pub threshold: Option<f64>,
+    pub pass: u32,
+    pub max_samples: u32,
+    pub time_limit: Option<Duration>
+}
+
+impl AdaptiveSampling {
+    // Every pixel takes samples_per_pixel samples, in passes of up to 16.
+    pub fn new(sampler: SamplerKind, samples_per_pixel: u32) -> Self {
+        Self {
+            sampler,
+            samples_per_pixel,
+            threshold: None,
+            pass: samples_per_pixel.min(16),
+            max_samples: samples_per_pixel,
+            time_limit: None
+        }
+    }
+
+    pub fn with_pass(mut self, samples: u32) -> Self {
+        self.pass = samples;
+        self
+    }
+
+    // Pixels keep sampling, up to max_samples, while their relative error is
+    // above threshold. samples_per_pixel stays the average budget.
+    pub fn with_threshold(mut self, threshold: f64, max_samples: u32) -> Self {
+        self.threshold = Some(threshold);
+        self.max_samples = max_samples;
+        self
+    }
+
+    // Checked between passes, so the render may overrun it by one pass. A
+    // resumed render counts the time spent before it stopped.
+    pub fn with_time_limit(mut self, limit: Option<Duration>) -> Self {
+        self.time_limit = limit;
+        self
+    }
+
+    // sample(x, y) traces one camera sample through pixel (x, y), drawing from
+    // the installed sampler. on_samples(n) is called as pixels finish passes,
+    // and on_pass with the image so far after every pass.
+    pub fn render(&self, width: u32, height: u32, sample: impl Fn(u32, u32) -> Color3 + Sync, on_samples: impl Fn(u32) + Sync, on_pass: impl FnMut(&[PixelEstimate])) -> Vec<PixelEstimate> {
+        self.resume(vec![PixelEstimate::new(); (width*height) as usize], Duration::ZERO, width, sample, on_samples, on_pass)
+    }
+
+    // Carries on a render from the pixels it had after some pass, elapsed
+    // time into it. Which pixels the next pass samples depends only on them,
+    // so the result is the same as if the render had never stopped.
+    pub fn resume(&self, mut pixels: Vec<PixelEstimate>, elapsed: Duration, width: u32, sample: impl Fn(u32, u32) -> Color3 + Sync, on_samples: impl Fn(u32) + Sync, mut on_pass: impl FnMut(&[PixelEstimate])) -> Vec<PixelEstimate> {
+        let start = Instant::now();
+
+        while let Some(active) = self.next_pass(&pixels) {
+            pixels.par_iter_mut().enumerate().filter(|(i, _)| active[*i]).for_each(|(i, px)| {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                let count = self.pass.min(self.max_samples - px.samples);
+
+                //Sample indices carry on from earlier passes, and the sampler
+                //stratifies over every sample the pixel may take
+                sampler::scoped(sampler::create(self.sampler, self.max_samples, sampler::seed()), || for _ in 0..count {
+                    sampler::start_pixel_sample(x, y, px.samples);
+                    px.add(&sample(x, y));
+                });
+                on_samples(count);
+            });
+            on_pass(&pixels);
+
+            if self.time_limit.is_some_and(|limit| elapsed + start.elapsed() >= limit) {
+                break;
+            }
+        }
+
+        pixels
+    }
+
+    // Pixels the next pass samples, or None once the render is done.
+    fn next_pass(&self, pixels: &[PixelEstimate]) -> Option<Vec<bool>> {
+        let mut noisy: Vec<(usize, f64)> = pixels.iter().enumerate()
+            .filter(|(_, px)| px.samples < self.max_samples)
+            .map(|(i, px)| (i, px.relative_error()))
+            .filter(|&(_, e)| self.threshold.is_none_or(|t| e > t))
+            .collect();
+
+        //The remaining budget goes to the noisiest pixels first, but the
+        //first pass always covers the whole image
+        if self.threshold.is_some() {
+            let budget = self.samples_per_pixel as u64 * pixels.len() as u64;
+            let taken = pixels.iter().map(|px| px.samples as u64).sum::<u64>();
+            let unsampled = pixels.iter().filter(|px| px.samples == 0).count();
+            noisy.sort_by(|a, b| b.1.total_cmp(&a.1));
+            noisy.truncate(usize::max((budget.saturating_sub(taken) / self.pass as u64) as usize, unsampled));
+        }
+        if noisy.is_empty() {
+            return None;
+        }
+
+        let mut active = vec![false; pixels.len()];
+        for (i, _) in noisy {
+            active[i] = true;
+        }
+        Some(active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noisy_pixels_get_more_samples() {
+        let adaptive = AdaptiveSampling::new(SamplerKind::Independent, 16).with_pass(8).with_threshold(0.05, 256);
+        let pixels = adaptive.render(4, 4, |x, _| {
+            if x == 0 { Vec3::from_f64(1.0, 1.0, 1.0) * (2.0*util::random_double()) } else { Vec3::from_f64(0.5, 0.5, 0.5) }
+        }, |_| {}, |_| {});
+
+        for (i, px) in pixels.iter().enumerate() {
+            if i % 4 == 0 { assert!(px.samples > 16) } else { assert_eq!(px.samples, 8) }
+        }
+        assert!(pixels.iter().map(|px| px.samples).sum::<u32>() <= 16*16);
+    }
+
+    #[test]
+    fn passes_cover_the_whole_image() {
+        let adaptive = AdaptiveSampling::new(SamplerKind::Stratified, 20).with_pass(8);
+        let mut passes = Vec::new();
+        let pixels = adaptive.render(3, 2, |_, _| Vec3::new(), |_| {}, |p| passes.push(p[5].samples));
+
+        assert_eq!(passes, [8, 16, 20]);
+        assert!(pixels.iter().all(|px| px.samples == 20));
+
+        let stopped = adaptive.with_time_limit(Some(Duration::ZERO)).render(3, 2, |_, _| Vec3::new(), |_| {}, |_| {});
+        assert!(stopped.iter().all(|px| px.samples == 8));
+    }
+
+    #[test]
+    fn strata_span_all_passes() {
+        let adaptive = AdaptiveSampling::new(SamplerKind::Stratified, 16).with_pass(4);
+        let drawn = std::sync::Mutex::new(Vec::new());
+        adaptive.render(1, 1, |_, _| {
+            drawn.lock().unwrap().push(util::random_double());
+            Vec3::new()
+        }, |_| {}, |_| {});
+
+        let mut strata: Vec<usize> = drawn.into_inner().unwrap().iter().map(|u| (u*16.0) as usize).collect();
+        strata.sort();
+        assert_eq!(strata, (0..16).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::*;
+use vec3::*;
+use hittable::HitRecord;
+use material::Material;
+use light::{Light, LightSample, LightEmission};
+use onb::ONB;
+use light_tree::LightBounds;
+use aabb::AABB;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// An emissive triangle, sampled uniformly by area. Emission comes from the
+// triangle's own material so textures and black bodies work unchanged.
+pub struct TriangleLight {
+    v: [Vec3; 3],
+    uv: [(f64, f64); 3],
+    n: Vec3,
+    area: f64,
+    material: Arc<dyn Material>,
+    // Mean emitted luminance of each side, zero for a side that doesn't emit
+    front: f64,
+    back: f64
+}
+
+// Subdivisions per edge of the barycentric lattice emission is averaged over.
+const EMISSION_LATTICE: usize = 4;
+
+impl TriangleLight {
+    pub fn new(v: [Vec3; 3], uv: [(f64, f64); 3], material: Arc<dyn Material>) -> Self {
+        let c = Vec3::cross(&(v[1] - v[0]), &(v[2] - v[0]));
+
+        let mut light = Self {
+            v,
+            uv,
+            n: Vec3::unit(&c),
+            area: 0.5*c.length(),
+            material,
+            front: 0.0,
+            back: 0.0
+        };
+
+        light.front = light.mean_emission(true);
+        light.back = light.mean_emission(false);
+        light
+    }
+
+    // Averaged over a lattice of points that includes the vertices and edges,
+    // so textured emitters aren't judged by a single spot.
+    fn mean_emission(&self, front_face: bool) -> f64 {
+        let n = EMISSION_LATTICE;
+        let mut total = 0.0;
+        let mut count = 0;
+
+        for i in 0..=n {
+            for j in 0..=(n - i) {
+                let b = [i as f64 / n as f64, j as f64 / n as f64, (n - i - j) as f64 / n as f64];
+                total += spectrum::luminance(&self.material.emitted(&self.record(b, front_face))).max(0.0);
+                count += 1;
+            }
+        }
+
+        total / count as f64
+    }
+
+    fn sides(&self) -> f64 {
+        if self.front > 0.0 && self.back > 0.0 { 2.0 } else { 1.0 }
+    }
+
+    fn record(&self, b: [f64; 3], front_face: bool) -> HitRecord {
+        let mut rec = HitRecord::new();
+        rec.p = b[0]*self.v[0] + b[1]*self.v[1] + b[2]*self.v[2];
+        rec.u = b[0]*self.uv[0].0 + b[1]*self.uv[1].0 + b[2]*self.uv[2].0;
+        rec.v = b[0]*self.uv[0].1 + b[1]*self.uv[1].1 + b[2]*self.uv[2].1;
+        rec.front_face = front_face;
+        rec.normal = if front_face { self.n } else { -self.n };
+        rec.geo_normal = rec.normal;
+        rec.material = self.material.clone();
+        rec
+    }
+}
+
+impl Light for TriangleLight {
+    fn sample_li(&self, p: &Point3, lambdas: Option<[f64; 3]>) -> Option<LightSample> {
+        let (u1, u2) = util::random_2d();
+        let su = u1.sqrt();
+        let b1 = u2*su;
+        let b = [1.0 - su, b1, su - b1];
+
+        let q = b[0]*self.v[0] + b[1]*self.v[1] + b[2]*self.v[2];
+        let d = q - *p;
+        let dist2 = d.length_squared();
+        let distance = dist2.sqrt();
+        let wi = d / distance;
+
+        let cos_light = Vec3::dot(&self.n, &-wi);
+        if cos_light.abs() < 1e-8 {
+            return None;
+        }
+
+        let rec = self.record(b, cos_light > 0.0);
+        let li = match lambdas {
+            Some(l) => self.material.emitted_spectral(&rec, &l),
+            None => self.material.emitted(&rec)
+        };
+        if li.near_zero() {
+            return None;
+        }
+
+        Some(LightSample {
+            wi,
+            li,
+            distance,
+            pdf: dist2 / (cos_light.abs() * self.area),
+            n: self.n
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let (front, back) = (self.front, self.back);
+
+        let min = Vec3::from_f64(self.v.iter().map(|v| v.x()).fold(f64::INFINITY, f64::min),
+                                self.v.iter().map(|v| v.y()).fold(f64::INFINITY, f64::min),
+                                self.v.iter().map(|v| v.z()).fold(f64::INFINITY, f64::min));
+        let max = Vec3::from_f64(self.v.iter().map(|v| v.x()).fold(f64::NEG_INFINITY, f64::max),
+                                self.v.iter().map(|v| v.y()).fold(f64::NEG_INFINITY, f64::max),
+                                self.v.iter().map(|v| v.z()).fold(f64::NEG_INFINITY, f64::max));
+
+        Some(LightBounds {
+            bounds: AABB::new(min, max),
+            phi: PI*self.area*(front + back),
+            w: if front > 0.0 || back == 0.0 { self.n } else { -self.n },
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: front > 0.0 && back > 0.0
+        })
+    }
+
+    fn is_area(&self) -> bool {
+        true
+    }
+
+    // Cosine-weighted from a uniform point, on a random side if both emit.
+    fn sample_le(&self, lambdas: Option<[f64; 3]>) -> Option<LightEmission> {
+        let (u1, u2) = util::random_2d();
+        let su = u1.sqrt();
+        let b1 = u2*su;
+        let b = [1.0 - su, b1, su - b1];
+
+        let front = if self.front > 0.0 && self.back > 0.0 { util::random_double() < 0.5 } else { self.front > 0.0 };
+        let rec = self.record(b, front);
+        let dir = ONB::from_w(&rec.normal).local_to_world(&util::random_cosine_direction());
+
+        let le = match lambdas {
+            Some(l) => self.material.emitted_spectral(&rec, &l),
+            None => self.material.emitted(&rec)
+        };
+
+        Some(LightEmission {
+            p: rec.p,
+            n: self.n,
+            dir,
+            le,
+            pdf_pos: 1.0 / self.area,
+            pdf_dir: Vec3::dot(&dir, &rec.normal).max(0.0) / (PI*self.sides())
+        })
+    }
+
+    fn pdf_le(&self, _p: &Point3, dir: &Vec3) -> (f64, f64) {
+        let cos = Vec3::dot(&Vec3::unit(dir), &self.n);
+        let emits = if cos > 0.0 { self.front } else { self.back };
+        if emits <= 0.0 {
+            return (1.0 / self.area, 0.0);
+        }
+
+        (1.0 / self.area, cos.abs() / (PI*self.sides()))
+    }
+
+    fn pdf_li(&self, p: &Point3, rec: &HitRecord) -> f64 {
+        let d = rec.p - *p;
+        let dist2 = d.length_squared();
+        let cos_light = Vec3::dot(&rec.geo_normal, &Vec3::unit(&d)).abs();
+        if cos_light < 1e-8 {
+            return 0.0;
+        }
+
+        dist2 / (cos_light * self.area)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diffuse_light::DiffuseLight;
+    use texture::Texture;
+
+    // Emits only close to the first vertex, which has uv (0, 0)
+    struct Corner;
+
+    impl Texture for Corner {
+        fn value(&self, u: f64, v: f64, _p: &Point3) -> Color3 {
+            if u + v < 0.2 { Vec3::from_f64(1.0, 1.0, 1.0) } else { Vec3::new() }
+        }
+    }
+
+    #[test]
+    fn emission_away_from_the_centroid_counts() {
+        let v = [Vec3::new(), Vec3::from_f64(1.0, 0.0, 0.0), Vec3::from_f64(0.0, 1.0, 0.0)];
+        let uv = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+        let light = TriangleLight::new(v, uv, Arc::new(DiffuseLight::new(Arc::new(Corner))));
+
+        assert!(light.front > 0.0 && light.back == 0.0);
+        let bounds = light.bounds().unwrap();
+        assert!(bounds.phi > 0.0 && !bounds.two_sided);
+
+        let (_, pdf_dir) = light.pdf_le(&Vec3::new(), &Vec3::from_f64(0.0, 0.0, 1.0));
+        assert!(pdf_dir > 0.0);
+    }
+}
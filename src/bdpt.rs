@@ -0,0 +1,717 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use hittable::*;
+use scene::Scene;
+use camera::Camera;
+use film::SplatFilm;
+use light::Light;
+use distribution::Distribution1D;
+use integrator::{to_channels, bsdf_channels, power_heuristic};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Bidirectional path tracing (Veach 1997, following the structure of pbrt-v3).
+// A camera subpath and a light subpath are traced for every camera sample and
+// joined with every connection strategy, weighted with the balance heuristic.
+// Light paths that reach the camera directly are splatted onto the film.
+//
+// Subpaths end where they would enter a participating medium; the path
+// integrator handles volumes and subsurface scattering. The environment is not
+// a light source for light subpaths, it is only hit by camera paths or sampled
+// from them.
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Camera,
+    Light,
+    Surface,
+    Environment
+}
+
+#[derive(Clone)]
+struct Vertex {
+    kind: Kind,
+    p: Point3,
+    // Geometric normal for surfaces and area lights, zero otherwise
+    n: Vec3,
+    // Unit direction towards the previous vertex of the subpath; for the
+    // environment, the direction back into the scene
+    wo: Vec3,
+    rec: Option<HitRecord>,
+    light: Option<Arc<dyn Light>>,
+    beta: Color3,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    delta: bool,
+    // A dispersive interface earlier on this subpath kept only the hero wavelength
+    collapsed: bool
+}
+
+impl Vertex {
+    fn new(kind: Kind, p: Point3, beta: Color3) -> Self {
+        Self {
+            kind,
+            p,
+            n: Vec3::new(),
+            wo: Vec3::new(),
+            rec: None,
+            light: None,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            collapsed: false
+        }
+    }
+
+    // The light this vertex emits from, if any.
+    fn emitter(&self) -> Option<&Arc<dyn Light>> {
+        match self.kind {
+            Kind::Light => self.light.as_ref(),
+            Kind::Surface => self.rec.as_ref().and_then(|r| r.area_light.as_ref()),
+            _ => None
+        }
+    }
+
+    fn direction_to(&self, next: &Vertex) -> Vec3 {
+        if next.kind == Kind::Environment {
+            return -next.wo;
+        }
+        Vec3::unit(&(next.p - self.p))
+    }
+}
+
+// Turns a solid angle density at from into an area density at to.
+fn convert_density(pdf: f64, from: &Vertex, to: &Vertex) -> f64 {
+    if to.kind == Kind::Environment {
+        return pdf;
+    }
+
+    let d = to.p - from.p;
+    let dist2 = d.length_squared();
+    if dist2 == 0.0 {
+        return 0.0;
+    }
+
+    let cos = if to.n.near_zero() { 1.0 } else { Vec3::dot(&to.n, &(d / dist2.sqrt())).abs() };
+    pdf * cos / dist2
+}
+
+// BSDF with |cos| against wi, as Material::eval, but for light arriving along
+// wi from a light subpath: the cosine belongs to the outgoing side instead.
+fn eval_importance(rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Color3 {
+    let cos_wi = Vec3::dot(wi, &rec.normal).abs();
+    if cos_wi < 1e-8 {
+        return Vec3::new();
+    }
+
+    rec.material.eval(wo, wi, rec) * (Vec3::dot(wo, &rec.normal).abs() / cos_wi)
+}
+
+pub struct BdptIntegrator {
+    pub max_depth: usize,
+    lights: Vec<Arc<dyn Light>>,
+    choice: Option<Distribution1D>,
+    index: HashMap<usize, usize>
+}
+
+fn key(light: &Arc<dyn Light>) -> usize {
+    Arc::as_ptr(light) as *const () as usize
+}
+
+impl BdptIntegrator {
+    // Lights are chosen in proportion to their power. Lights at infinity have no
+    // power bound and get the average weight; choosing one yields an empty light
+    // subpath, but they are still reached by sampling them from camera vertices.
+    pub fn new(scene: &Scene, max_depth: usize) -> Self {
+        let lights = scene.lights.lights().to_vec();
+        let powers: Vec<Option<f64>> = lights.iter().map(|l| l.bounds().map(|b| b.phi)).collect();
+
+        let bounded: Vec<f64> = powers.iter().flatten().cloned().collect();
+        let average = if bounded.is_empty() { 1.0 } else { bounded.iter().sum::<f64>() / bounded.len() as f64 };
+        let weights: Vec<f64> = powers.iter().map(|p| p.unwrap_or(average)).collect();
+
+        let choice = if weights.iter().sum::<f64>() > 0.0 { Some(Distribution1D::new(&weights)) } else { None };
+        let index = lights.iter().enumerate().map(|(i, l)| (key(l), i)).collect();
+
+        Self {
+            max_depth,
+            lights,
+            choice,
+            index
+        }
+    }
+
+    fn choose_light(&self) -> Option<(Arc<dyn Light>, f64)> {
+        let choice = self.choice.as_ref()?;
+        let (_, _, i) = choice.sample(util::random_double());
+        Some((self.lights[i].clone(), choice.pdf_at(i) / choice.count() as f64))
+    }
+
+    fn light_pmf(&self, light: &Arc<dyn Light>) -> f64 {
+        match (&self.choice, self.index.get(&key(light))) {
+            (Some(choice), Some(&i)) => choice.pdf_at(i) / choice.count() as f64,
+            _ => 0.0
+        }
+    }
+
+    // Area density of v producing next, given it was reached from prev.
+    fn pdf(&self, camera: &Camera, v: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let wi = v.direction_to(next);
+        let pdf = match v.kind {
+            Kind::Camera => camera.pdf_we(&v.p, &wi).1,
+            Kind::Light => return self.pdf_light(v, next),
+            Kind::Surface => {
+                let prev = match prev {
+                    Some(prev) => prev,
+                    None => return self.pdf_light(v, next)
+                };
+                let rec = v.rec.as_ref().unwrap();
+                rec.material.pdf(&v.direction_to(prev), &wi, rec)
+            },
+            Kind::Environment => 0.0
+        };
+
+        convert_density(pdf, v, next)
+    }
+
+    // Area density of a light subpath leaving the emitter at v towards next.
+    fn pdf_light(&self, v: &Vertex, next: &Vertex) -> f64 {
+        match v.emitter() {
+            Some(light) => convert_density(light.pdf_le(&v.p, &v.direction_to(next)).1, v, next),
+            None => 0.0
+        }
+    }
+
+    // Density of a light subpath starting at v.
+    fn pdf_light_origin(&self, v: &Vertex, next: &Vertex) -> f64 {
+        match v.emitter() {
+            Some(light) => self.light_pmf(light) * light.pdf_le(&v.p, &v.direction_to(next)).0,
+            None => 0.0
+        }
+    }
+
+    // Extends a subpath from its last vertex. pdf is the solid angle density
+    // of the first ray; camera subpaths record where they escape to the environment.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk(&self, scene: &Scene, mut r: Ray, mut beta: Color3, pdf: f64, max_vertices: usize, camera_path: bool, path: &mut Vec<Vertex>) {
+        let mut pdf_fwd = pdf;
+        let mut collapsed = path.last().map(|v| v.collapsed).unwrap_or(false);
+
+        while path.len() < max_vertices {
+            let prev = path.len() - 1;
+            let mut rec = HitRecord::new();
+
+            if !scene.world.hit(r, 0.001, f64::INFINITY, &mut rec) {
+                if camera_path {
+                    let mut v = Vertex::new(Kind::Environment, r.origin(), beta);
+                    v.wo = -Vec3::unit(&r.direction());
+                    v.pdf_fwd = pdf_fwd;
+                    v.collapsed = collapsed;
+                    path.push(v);
+                }
+                break;
+            }
+
+            let mut v = Vertex::new(Kind::Surface, rec.p, beta);
+            v.n = rec.geo_normal;
+            v.wo = -Vec3::unit(&r.direction());
+            v.pdf_fwd = convert_density(pdf_fwd, &path[prev], &v);
+            v.collapsed = collapsed;
+
+            let mut attenuation = Vec3::new();
+            let mut scattered = Ray::new(&Vec3::new(), &Vec3::new());
+            let mat = rec.material.clone();
+            let lobe = if path.len() + 1 == max_vertices { None } else { mat.scatter(&r, &rec, &mut attenuation, &mut scattered) };
+            let Some(lobe) = lobe else {
+                v.rec = Some(rec);
+                path.push(v);
+                break;
+            };
+
+            let mut attenuation = bsdf_channels(attenuation, &rec, &r);
+            let scattered = match (r.wavelengths(), scattered.wavelengths()) {
+                (Some(l), Some(s)) => {
+                    if s != l {
+                        attenuation = Vec3::from_f64(3.0*attenuation.x(), 0.0, 0.0);
+                        collapsed = true;
+                    }
+                    scattered
+                },
+                (l, _) => scattered.with_wavelengths(l)
+            };
+
+            let wi = Vec3::unit(&scattered.direction());
+            //A delta lobe carries no density, even when the material has other lobes
+            v.delta = lobe.is_specular();
+            pdf_fwd = if v.delta { 0.0 } else { mat.pdf(&v.wo, &wi, &rec) };
+            let pdf_rev = if v.delta { 0.0 } else { mat.pdf(&wi, &v.wo, &rec) };
+
+            let entering = Vec3::dot(&wi, &rec.geo_normal) < 0.0 && rec.front_face && mat.interior().is_some();
+            v.rec = Some(rec);
+
+            let rev = convert_density(pdf_rev, &v, &path[prev]);
+            path[prev].pdf_rev = rev;
+            path.push(v);
+
+            beta *= attenuation;
+            if entering || beta.near_zero() {
+                break;
+            }
+            r = scattered;
+        }
+    }
+
+    fn camera_subpath(&self, scene: &Scene, camera: &Camera, r: &Ray) -> Vec<Vertex> {
+        let mut v = Vertex::new(Kind::Camera, r.origin(), Vec3::from_f64(1.0, 1.0, 1.0));
+        v.n = camera.forward();
+
+        let pdf_dir = camera.pdf_we(&r.origin(), &r.direction()).1;
+        let mut path = vec![v];
+        self.random_walk(scene, *r, Vec3::from_f64(1.0, 1.0, 1.0), pdf_dir, self.max_depth + 2, true, &mut path);
+        path
+    }
+
+    fn light_subpath(&self, scene: &Scene, lambdas: Option<[f64; 3]>) -> Vec<Vertex> {
+        let (light, pmf) = match self.choose_light() {
+            Some(c) if c.0.bounds().is_some() => c,
+            _ => return Vec::new()
+        };
+        let le = match light.sample_le(lambdas) {
+            Some(le) if le.pdf_pos > 0.0 && le.pdf_dir > 0.0 && !le.le.near_zero() => le,
+            _ => return Vec::new()
+        };
+
+        let mut v = Vertex::new(Kind::Light, le.p, le.le / (pmf * le.pdf_pos));
+        v.n = le.n;
+        v.light = Some(light.clone());
+        v.pdf_fwd = pmf * le.pdf_pos;
+
+        let cos = if le.n.near_zero() { 1.0 } else { Vec3::dot(&le.n, &le.dir).abs() };
+        let beta = v.beta * (cos / le.pdf_dir);
+
+        let r = Ray::new(&le.p, &le.dir).with_wavelengths(lambdas);
+        let mut path = vec![v];
+        self.random_walk(scene, r, beta, le.pdf_dir, self.max_depth + 1, false, &mut path);
+        path
+    }
+
+    fn unoccluded(scene: &Scene, a: &Vertex, b: &Vertex) -> bool {
+        let d = b.p - a.p;
+        let distance = d.length();
+        let origin = match &a.rec {
+            Some(rec) => rec.spawn_ray(&d).origin(),
+            None => a.p
+        };
+        scene.unoccluded(&origin, &(d / distance), distance)
+    }
+
+    // Contribution of the path made of the first s light and t camera vertices.
+    // Connections to the camera are splatted and return zero.
+    #[allow(clippy::too_many_arguments)]
+    fn connect(&self, scene: &Scene, camera: &Camera, film: &SplatFilm, cam: &[Vertex], lig: &[Vertex], s: usize, t: usize, r: &Ray) -> Color3 {
+        let pt = &cam[t - 1];
+        let mut sampled: Option<Vertex> = None;
+        let mut splat = (0.0, 0.0);
+        let mut l;
+
+        if s == 0 {
+            let rec = match &pt.rec {
+                Some(rec) => rec,
+                None => return Vec3::new()
+            };
+            l = pt.beta * match r.wavelengths() {
+                Some(lambdas) => rec.material.emitted_spectral(rec, &lambdas),
+                None => rec.material.emitted(rec)
+            };
+
+            // Emitters that aren't registered lights can only be found this way
+            if l.near_zero() || rec.area_light.is_none() {
+                return l;
+            }
+        } else if t == 1 {
+            let qs = &lig[s - 1];
+            if qs.delta || qs.kind != Kind::Surface {
+                return Vec3::new();
+            }
+            let cs = match camera.sample_wi(&qs.p) {
+                Some(cs) => cs,
+                None => return Vec3::new()
+            };
+
+            let mut v = Vertex::new(Kind::Camera, cs.lens, Vec3::from_f64(1.0, 1.0, 1.0) * (cs.importance / cs.pdf));
+            v.n = camera.forward();
+
+            let rec = qs.rec.as_ref().unwrap();
+            l = qs.beta * bsdf_channels(eval_importance(rec, &cs.wi, &qs.wo), rec, r) * v.beta;
+            if l.near_zero() || !BdptIntegrator::unoccluded(scene, qs, &v) {
+                return Vec3::new();
+            }
+            sampled = Some(v);
+            splat = (cs.u, cs.v);
+        } else if s == 1 {
+            if pt.delta || pt.kind != Kind::Surface {
+                return Vec3::new();
+            }
+            let (light, pmf) = match self.choose_light() {
+                Some(c) => c,
+                None => return Vec3::new()
+            };
+            let ls = match light.sample_li(&pt.p, r.wavelengths()) {
+                Some(ls) if ls.pdf > 0.0 => ls,
+                _ => return Vec3::new()
+            };
+
+            let distance = if ls.distance.is_finite() { ls.distance } else { 1e10 };
+            let mut v = Vertex::new(Kind::Light, pt.p + ls.wi*distance, ls.li / (ls.pdf * pmf));
+            v.n = ls.n;
+            v.light = Some(light.clone());
+            v.pdf_fwd = if light.is_delta() { pmf } else { self.pdf_light_origin(&v, pt) };
+
+            let rec = pt.rec.as_ref().unwrap();
+            l = pt.beta * bsdf_channels(rec.material.eval(&pt.wo, &ls.wi, rec), rec, r) * v.beta;
+            if l.near_zero() || !scene.unoccluded(&rec.spawn_ray(&ls.wi).origin(), &ls.wi, ls.distance) {
+                return Vec3::new();
+            }
+            sampled = Some(v);
+        } else {
+            let qs = &lig[s - 1];
+            if qs.delta || pt.delta || qs.kind != Kind::Surface || pt.kind != Kind::Surface {
+                return Vec3::new();
+            }
+
+            let d = pt.p - qs.p;
+            let dist2 = d.length_squared();
+            let dir = d / dist2.sqrt();
+            let (qrec, prec) = (qs.rec.as_ref().unwrap(), pt.rec.as_ref().unwrap());
+
+            let fq = bsdf_channels(eval_importance(qrec, &dir, &qs.wo), qrec, r);
+            let fp = bsdf_channels(prec.material.eval(&pt.wo, &-dir, prec), prec, r);
+            l = qs.beta * fq * fp * pt.beta / dist2;
+            if l.near_zero() || !BdptIntegrator::unoccluded(scene, qs, pt) {
+                return Vec3::new();
+            }
+        }
+
+        // Each subpath that kept only its hero wavelength already tripled it
+        if s > 0 && pt.collapsed && lig[s - 1].collapsed {
+            l /= 3.0;
+        }
+
+        let weight = self.mis_weight(camera, cam, lig, sampled.as_ref(), s, t);
+        l = l * weight;
+
+        if t == 1 {
+            let c = match r.wavelengths() {
+                Some(lambdas) => spectrum::to_rgb(&l, &lambdas),
+                None => l
+            };
+            film.add(splat.0, splat.1, &c);
+            return Vec3::new();
+        }
+
+        l
+    }
+
+    // Balance heuristic over every strategy that could have produced this path.
+    fn mis_weight(&self, camera: &Camera, cam: &[Vertex], lig: &[Vertex], sampled: Option<&Vertex>, s: usize, t: usize) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let mut c: Vec<Vertex> = cam[..t].to_vec();
+        let mut q: Vec<Vertex> = lig[..s].to_vec();
+        if let Some(v) = sampled {
+            if t == 1 { c[0] = v.clone(); } else { q[0] = v.clone(); }
+        }
+
+        // The connected endpoints can't be delta, and their reverse densities
+        // are those of the strategy that didn't sample them
+        c[t - 1].delta = false;
+        if s > 0 {
+            q[s - 1].delta = false;
+        }
+
+        let pt_rev = if s > 0 {
+            self.pdf(camera, &q[s - 1], if s > 1 { Some(&q[s - 2]) } else { None }, &c[t - 1])
+        } else {
+            self.pdf_light_origin(&c[t - 1], &c[t - 2])
+        };
+        let pt_minus_rev = if t > 1 {
+            if s > 0 {
+                Some(self.pdf(camera, &c[t - 1], Some(&q[s - 1]), &c[t - 2]))
+            } else {
+                Some(self.pdf_light(&c[t - 1], &c[t - 2]))
+            }
+        } else {
+            None
+        };
+        let qs_rev = if s > 0 {
+            Some(self.pdf(camera, &c[t - 1], if t > 1 { Some(&c[t - 2]) } else { None }, &q[s - 1]))
+        } else {
+            None
+        };
+        let qs_minus_rev = if s > 1 {
+            Some(self.pdf(camera, &q[s - 1], Some(&c[t - 1]), &q[s - 2]))
+        } else {
+            None
+        };
+
+        c[t - 1].pdf_rev = pt_rev;
+        if let Some(p) = pt_minus_rev {
+            c[t - 2].pdf_rev = p;
+        }
+        if let Some(p) = qs_rev {
+            q[s - 1].pdf_rev = p;
+        }
+        if let Some(p) = qs_minus_rev {
+            q[s - 2].pdf_rev = p;
+        }
+
+        let remap = |p: f64| if p != 0.0 { p } else { 1.0 };
+        let mut sum = 0.0;
+
+        // Lights at infinity never start light subpaths, so only s = 1 exists
+        // among the strategies with fewer camera vertices
+        let light_paths = match (s, q.first()) {
+            (0, _) => true,
+            (_, Some(v)) => v.light.as_ref().map(|l| l.bounds().is_some()).unwrap_or(true),
+            _ => true
+        };
+
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap(c[i].pdf_rev) / remap(c[i].pdf_fwd);
+            let s_i = s + t - i;
+            if s_i >= 2 && !light_paths {
+                break;
+            }
+            if !c[i].delta && !c[i - 1].delta {
+                sum += ri;
+            }
+        }
+
+        let mut ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap(q[i].pdf_rev) / remap(q[i].pdf_fwd);
+            let delta_light = if i > 0 {
+                q[i - 1].delta
+            } else {
+                q[0].light.as_ref().map(|l| l.is_delta()).unwrap_or(false)
+            };
+            if !q[i].delta && !delta_light {
+                sum += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+    // The environment is only reached by camera paths: either by escaping, or
+    // by sampling it from a vertex. The two are weighted against each other.
+    fn environment(&self, scene: &Scene, cam: &[Vertex], r: &Ray) -> Color3 {
+        let mut l = Vec3::new();
+
+        for t in 2..=cam.len() {
+            let pt = &cam[t - 1];
+            let prev = &cam[t - 2];
+
+            if pt.kind == Kind::Environment {
+                let dir = -pt.wo;
+                let weight = if t == 2 || prev.delta { 1.0 } else { power_heuristic(pt.pdf_fwd, scene.environment.pdf(&dir)) };
+                l += pt.beta * to_channels(scene.environment.radiance(&dir), r) * weight;
+
+                //A visible sun is otherwise only reached by connecting to it,
+                //which needs a vertex that isn't specular
+                if t == 2 || prev.delta {
+                    for light in scene.lights.infinite() {
+                        l += pt.beta * light.le(&dir, r.wavelengths());
+                    }
+                }
+                continue;
+            }
+
+            if pt.delta || pt.kind != Kind::Surface {
+                continue;
+            }
+            let rec = pt.rec.as_ref().unwrap();
+            let (wi, light_pdf) = scene.environment.sample();
+            if light_pdf <= 0.0 {
+                continue;
+            }
+            let f = rec.material.eval(&pt.wo, &wi, rec);
+            if !f.near_zero() && scene.unoccluded(&rec.spawn_ray(&wi).origin(), &wi, f64::INFINITY) {
+                let weight = power_heuristic(light_pdf, rec.material.pdf(&pt.wo, &wi, rec));
+                l += pt.beta * bsdf_channels(f, rec, r) * to_channels(scene.environment.radiance(&wi), r) * (weight / light_pdf);
+            }
+        }
+
+        l
+    }
+
+    pub fn li(&self, r: &Ray, scene: &Scene, camera: &Camera, film: &SplatFilm) -> Color3 {
+        let cam = self.camera_subpath(scene, camera, r);
+        let lig = self.light_subpath(scene, r.wavelengths());
+
+        let mut l = self.environment(scene, &cam, r);
+        for t in 1..=cam.len() {
+            if cam[t - 1].kind == Kind::Environment {
+                continue;
+            }
+            for s in 0..=lig.len() {
+                let depth = s + t;
+                if depth < 2 || depth > self.max_depth + 2 || (s == 1 && t == 1) {
+                    continue;
+                }
+                l += self.connect(scene, camera, film, &cam, &lig, s, t, r);
+            }
+        }
+
+        l
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+    use hittable_list::HittableList;
+    use triangle::Triangle;
+    use lambertian::Lambertian;
+    use diffuse_light::DiffuseLight;
+    use material::Material;
+    use light::PointLight;
+    use environment::Gradient;
+    use texture::solid;
+
+    // Two triangles at height y with their normal facing down
+    fn plane(world: &mut HittableList, y: f64, mat: Arc<dyn Material>) {
+        let (a, b, c, d) = (Vec3::from_f64(-10.0, y, -10.0), Vec3::from_f64(10.0, y, -10.0), Vec3::from_f64(10.0, y, 10.0), Vec3::from_f64(-10.0, y, 10.0));
+        world.add(Arc::new(Triangle::new(a, b, c, mat.clone())));
+        world.add(Arc::new(Triangle::new(a, c, d, mat)));
+    }
+
+    fn under_black_sky(world: HittableList) -> Scene {
+        let mut scene = Scene::new(bvh::BVH::new(world));
+        scene.set_environment(Arc::new(Gradient::new(Vec3::new(), Vec3::new())));
+        scene
+    }
+
+    #[test]
+    fn diffuse_floor_converges_to_the_point_light_irradiance() {
+        let (albedo, intensity) = (0.5, 4.0);
+        let mut world = HittableList::new();
+        plane(&mut world, 0.0, Arc::new(Lambertian::new(Vec3::from_f64(albedo, albedo, albedo))));
+        let mut scene = under_black_sky(world);
+        scene.add_light(Arc::new(PointLight::new(Vec3::from_f64(0.0, 1.0, 0.0), Vec3::from_f64(intensity, intensity, intensity))));
+
+        //Light tracing splats and connections to the light share the image, so
+        //camera rays stay inside it
+        let (width, height, spp) = (4, 4, 256);
+        let camera = Camera::new(Vec3::from_f64(0.0, 0.3, 0.0), Vec3::new(), Vec3::from_f64(0.0, 0.0, 1.0), 1.0, 5.0, 0.0, 0.3);
+        let bdpt = BdptIntegrator::new(&scene, 5);
+        let film = SplatFilm::new(width, height);
+
+        let mut sum = Vec3::new();
+        for y in 0..height {
+            for x in 0..width {
+                sampler::scoped(sampler::stream(1, y*width + x, 0), || for _ in 0..spp {
+                    let (dx, dy) = util::random_2d();
+                    let r = camera.get_ray((x as f64 + dx) / width as f64, (y as f64 + dy) / height as f64);
+                    sum += bdpt.li(&r, &scene, &camera, &film);
+                });
+            }
+        }
+        let splats = (0..(width*height) as usize).fold(Vec3::new(), |a, i| a + film.get(i));
+
+        let mean = (sum + splats).y() / (width*height*spp) as f64;
+        let expected = albedo / PI * intensity;
+        assert!((mean - expected).abs() < 0.03*expected, "{} vs {}", mean, expected);
+    }
+
+    // The vertices of the path through points, as the camera subpath of its
+    // first t points and the light subpath of its last s, with every density
+    // the random walks would have recorded.
+    fn subpaths(scene: &Scene, camera: &Camera, points: &[Point3]) -> (Vec<Vertex>, Vec<Vertex>) {
+        let k = points.len() - 1;
+        let mut recs = vec![None];
+        for i in 1..=k {
+            let mut rec = HitRecord::new();
+            assert!(scene.world.hit(Ray::new(&points[i - 1], &(points[i] - points[i - 1])), 0.001, f64::INFINITY, &mut rec));
+            assert!((rec.p - points[i]).length() < 1e-9);
+            recs.push(Some(rec));
+        }
+        let light = recs[k].as_ref().unwrap().area_light.clone().unwrap();
+        let dir = |from: usize, to: usize| Vec3::unit(&(points[to] - points[from]));
+        let bsdf_pdf = |at: usize, wo: usize, wi: usize| {
+            let rec = recs[at].as_ref().unwrap();
+            rec.material.pdf(&dir(at, wo), &dir(at, wi), rec)
+        };
+
+        let mut cam = vec![Vertex::new(Kind::Camera, points[0], Vec3::from_f64(1.0, 1.0, 1.0))];
+        cam[0].n = camera.forward();
+        for i in 1..=k {
+            let mut v = Vertex::new(Kind::Surface, points[i], Vec3::from_f64(1.0, 1.0, 1.0));
+            v.rec = recs[i].clone();
+            v.n = v.rec.as_ref().unwrap().geo_normal;
+            v.wo = dir(i, i - 1);
+            let pdf = if i == 1 { camera.pdf_we(&points[0], &dir(0, 1)).1 } else { bsdf_pdf(i - 1, i - 2, i) };
+            v.pdf_fwd = convert_density(pdf, &cam[i - 1], &v);
+            cam.push(v);
+        }
+        //The reverse density next to the light is left for mis_weight to fill in
+        for i in 1..k - 1 {
+            cam[i].pdf_rev = convert_density(bsdf_pdf(i + 1, i + 2, i), &cam[i + 1], &cam[i]);
+        }
+
+        let mut lig = Vec::new();
+        let mut v = Vertex::new(Kind::Light, points[k], Vec3::from_f64(1.0, 1.0, 1.0));
+        v.n = cam[k].n;
+        v.light = Some(light.clone());
+        v.pdf_fwd = BdptIntegrator::new(scene, 8).light_pmf(&light) * light.pdf_le(&points[k], &dir(k, k - 1)).0;
+        lig.push(v);
+        for j in 1..k {
+            let i = k - j;
+            let mut v = cam[i].clone();
+            v.wo = dir(i, i + 1);
+            let pdf = if j == 1 { light.pdf_le(&points[k], &dir(k, i)).1 } else { bsdf_pdf(i + 1, i + 2, i) };
+            v.pdf_fwd = convert_density(pdf, &lig[j - 1], &v);
+            v.pdf_rev = if i >= 2 { convert_density(bsdf_pdf(i - 1, i - 2, i), &cam[i - 1], &v) } else { 0.0 };
+            lig.push(v);
+        }
+        lig[0].pdf_rev = if k >= 2 { convert_density(bsdf_pdf(k - 1, k - 2, k), &cam[k - 1], &cam[k]) } else { 0.0 };
+
+        (cam, lig)
+    }
+
+    #[test]
+    fn mis_weights_of_one_path_sum_to_one() {
+        //A floor, a wall at x = 1 and an emitting ceiling at y = 2
+        let grey: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
+        let mut world = HittableList::new();
+        plane(&mut world, 0.0, grey.clone());
+        world.add(Arc::new(Triangle::new(Vec3::from_f64(1.0, -10.0, -10.0), Vec3::from_f64(1.0, 10.0, -10.0), Vec3::from_f64(1.0, 0.0, 10.0), grey)));
+        plane(&mut world, 2.0, Arc::new(DiffuseLight::new(solid(Vec3::from_f64(1.0, 1.0, 1.0)))));
+        let scene = under_black_sky(world);
+
+        let points = [
+            Vec3::from_f64(0.0, 1.0, 0.0),
+            Vec3::from_f64(0.3, 0.0, 0.1),
+            Vec3::from_f64(1.0, 0.8, 0.3),
+            Vec3::from_f64(0.2, 2.0, -0.4)
+        ];
+        let camera = Camera::new(points[0], points[1], Vec3::from_f64(0.0, 1.0, 0.0), 1.0, 40.0, 0.0, 1.0);
+        let bdpt = BdptIntegrator::new(&scene, 8);
+
+        //Each prefix of the path ending on the ceiling is a path of its own
+        for k in [2, 3] {
+            let path = [&points[..k], &points[3..]].concat();
+            let (cam, lig) = subpaths(&scene, &camera, &path);
+
+            let weights: Vec<f64> = (0..=k).map(|s| bdpt.mis_weight(&camera, &cam, &lig, None, s, k + 1 - s)).collect();
+            assert!(weights.iter().all(|&w| w > 0.0 && w < 1.0), "{:?}", weights);
+            assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-9, "{:?}", weights);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::*;
+use vec3::*;
+use std::f64::consts::PI;
+
+// Radiance in the renderer is W/(sr m^2 nm) per wavelength, which the film
+// maps to RGB 1.0 for a flat unit spectrum (about 73000 cd/m^2).
+
+// Peak luminous efficacy, lm/W at 555nm.
+pub const LUMINOUS_EFFICACY: f64 = 683.0;
+
+// Planck's law: spectral radiance of a black body, W/(sr m^2 nm).
+pub fn planck(lambda: f64, kelvin: f64) -> f64 {
+    const H: f64 = 6.62607015e-34;
+    const C: f64 = 2.99792458e8;
+    const KB: f64 = 1.380649e-23;
+
+    let l = lambda * 1e-9;
+    let le = 2.0*H*C*C / (l.powi(5) * ((H*C / (l*KB*kelvin)).exp() - 1.0));
+
+    le * 1e-9
+}
+
+// Black body emission scaled to a given luminance.
+#[derive(Copy, Clone)]
+pub struct Blackbody {
+    kelvin: f64,
+    scale: f64,
+    rgb: Color3
+}
+
+impl Blackbody {
+    // luminance in cd/m^2 (nits).
+    pub fn new(kelvin: f64, luminance: f64) -> Self {
+        let xyz = spectrum::integrate_xyz(|l| planck(l, kelvin));
+        let scale = luminance / (LUMINOUS_EFFICACY * xyz.y());
+
+        Self {
+            kelvin,
+            scale,
+            rgb: spectrum::xyz_to_film_rgb(&(xyz * scale))
+        }
+    }
+
+    // Luminance of a Lambertian emitter of the given area (m^2) sending out
+    // the given luminous flux.
+    pub fn from_lumens(kelvin: f64, lumens: f64, area: f64) -> Self {
+        Blackbody::new(kelvin, lumens / (PI * area))
+    }
+
+    // Electrical power and luminous efficacy (lm/W, ~15 for incandescent,
+    // ~100 for LED) of the source.
+    pub fn from_watts(kelvin: f64, watts: f64, efficacy: f64, area: f64) -> Self {
+        Blackbody::from_lumens(kelvin, watts * efficacy, area)
+    }
+
+    pub fn value(&self, lambda: f64) -> f64 {
+        self.scale * planck(lambda, self.kelvin)
+    }
+
+    pub fn values(&self, lambdas: &[f64; 3]) -> Vec3 {
+        Vec3::from_f64(self.value(lambdas[0]), self.value(lambdas[1]), self.value(lambdas[2]))
+    }
+
+    pub fn rgb(&self) -> Color3 {
+        self.rgb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warm_is_redder_than_cool() {
+        let warm = Blackbody::new(2700.0, 1000.0).rgb();
+        let cool = Blackbody::new(6500.0, 1000.0).rgb();
+
+        assert!(warm.x() / warm.z() > cool.x() / cool.z());
+    }
+
+    #[test]
+    fn luminance_matches() {
+        let bb = Blackbody::new(4000.0, 5000.0);
+        let y = spectrum::integrate_xyz(|l| bb.value(l)).y() * LUMINOUS_EFFICACY;
+
+        assert!((y - 5000.0).abs() < 1e-6 * 5000.0);
+    }
+}
@@ -4,6 +4,7 @@ use aabb::*;
 use std::sync::Arc;
 use hittable::*;
 use hittable_list::*;
+use light::Light;
 use std::cmp::Ordering;
 
 fn box_compare(a: Arc<dyn Hittable>, b: Arc<dyn Hittable>, axis: usize) -> Ordering {
@@ -13,6 +14,7 @@ fn box_compare(a: Arc<dyn Hittable>, b: Arc<dyn Hittable>, axis: usize) -> Order
     box_a.min()[axis].partial_cmp(&box_b.min()[axis]).unwrap()
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct BVH {
     left: Arc<dyn Hittable>,
     right: Arc<dyn Hittable>,
@@ -27,7 +29,11 @@ impl BVH {
 
     fn new_internal(h: &mut HittableList, start: usize, end: usize) -> Self {
         let span = end - start;
-        let axis = util::random_int(0, 2);
+
+        //Split along the longest side of the span's bounds
+        let bounds = h.list[start..end].iter().map(|o| o.bounding_box()).reduce(AABB::union).flatten().expect("Span has no bounding box");
+        let extent = bounds.max() - bounds.min();
+        let axis = if extent.x() >= extent.y() && extent.x() >= extent.z() { 0 } else if extent.y() >= extent.z() { 1 } else { 2 };
 
         let left;
         let right;
@@ -39,7 +45,7 @@ impl BVH {
 
             },
             2 => {
-                if box_compare(h.list[start].clone(), h.list[start+1].clone(), axis as usize) == Ordering::Less {
+                if box_compare(h.list[start].clone(), h.list[start+1].clone(), axis) == Ordering::Less {
                     left = h.list[start].clone();
                     right = h.list[start+1].clone();
                 } else {
@@ -49,7 +55,7 @@ impl BVH {
 
             },
             _ => {
-                h.list[start..end].sort_by(|a, b| box_compare(a.clone(), b.clone(), axis as usize));
+                h.list[start..end].sort_by(|a, b| box_compare(a.clone(), b.clone(), axis));
 
                 let mid = start + span/2;
                 left = Arc::new(BVH::new_internal(h, start, mid));
@@ -81,13 +87,19 @@ impl Hittable for BVH {
         let r_max = if hit_left {hit_record.t} else { max };
         let hit_right = self.right.hit(ray, min, r_max, hit_record);
 
-        return hit_left || hit_right;
+        hit_left || hit_right
     }
 
     fn bounding_box(&self) -> Option<AABB> {
         Some(self.bb.clone())
     }
-}
 
-unsafe impl Send for BVH {}
-unsafe impl Sync for BVH {}
+    fn lights(&self) -> Vec<Arc<dyn Light>> {
+        let mut lights = self.left.lights();
+        //Single-object leaves store the same child twice
+        if !Arc::ptr_eq(&self.left, &self.right) {
+            lights.extend(self.right.lights());
+        }
+        lights
+    }
+}
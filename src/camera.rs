@@ -1,6 +1,7 @@
 use crate::*;
-use vec3::Vec3;
+use vec3::*;
 use ray::Ray;
+use std::f64::consts::PI;
 
 pub struct Camera {
     origin: Vec3,
@@ -9,7 +10,24 @@ pub struct Camera {
     vertical: Vec3,
     u: Vec3,
     v: Vec3,
-    lens_radius: f64
+    w: Vec3,
+    lens_radius: f64,
+    focus_dist: f64,
+    // Viewport area at unit distance
+    image_area: f64
+}
+
+// A point on the lens that sees p, for connecting light paths to the camera.
+pub struct CameraSample {
+    // Image coordinates in [0, 1], as passed to get_ray()
+    pub u: f64,
+    pub v: f64,
+    pub lens: Point3,
+    // Unit direction from p towards the lens
+    pub wi: Vec3,
+    pub importance: f64,
+    // Solid angle density of wi at p
+    pub pdf: f64
 }
 
 impl Camera {
@@ -37,8 +55,79 @@ impl Camera {
             lower_left,
             u,
             v,
-            lens_radius: aperture/2.0
+            w,
+            lens_radius: aperture/2.0,
+            focus_dist,
+            image_area: viewport_width*viewport_height
+        }
+    }
+
+    fn lens_area(&self) -> f64 {
+        if self.lens_radius > 0.0 { PI*self.lens_radius*self.lens_radius } else { 1.0 }
+    }
+
+    // Image coordinates of a point on the plane of focus.
+    fn image_coords(&self, q: &Point3) -> Option<(f64, f64)> {
+        let rel = *q - self.lower_left;
+        let u = Vec3::dot(&rel, &self.horizontal) / self.horizontal.length_squared();
+        let v = Vec3::dot(&rel, &self.vertical) / self.vertical.length_squared();
+
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
         }
+        Some((u, v))
+    }
+
+    // Importance of the thin lens camera for a ray leaving the lens along dir,
+    // normalized so get_ray() samples it with weight one.
+    fn importance(&self, cos: f64) -> f64 {
+        1.0 / (self.image_area * self.lens_area() * cos.powi(4))
+    }
+
+    // Position (area) and direction (solid angle) densities of get_ray()
+    // producing a ray from lens along dir.
+    pub fn pdf_we(&self, lens: &Point3, dir: &Vec3) -> (f64, f64) {
+        let dir = Vec3::unit(dir);
+        let cos = -Vec3::dot(&dir, &self.w);
+        if cos <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let q = *lens + dir*(self.focus_dist / cos);
+        if self.image_coords(&q).is_none() {
+            return (0.0, 0.0);
+        }
+
+        (1.0 / self.lens_area(), 1.0 / (self.image_area * cos.powi(3)))
+    }
+
+    pub fn sample_wi(&self, p: &Point3) -> Option<CameraSample> {
+        let rd = self.lens_radius*util::random_unit_disk();
+        let lens = self.origin + self.u*rd.x() + self.v*rd.y();
+
+        let d = *p - lens;
+        let distance = d.length();
+        let dir = d / distance;
+        let cos = -Vec3::dot(&dir, &self.w);
+        if cos <= 0.0 {
+            return None;
+        }
+
+        let (u, v) = self.image_coords(&(lens + dir*(self.focus_dist / cos)))?;
+
+        Some(CameraSample {
+            u,
+            v,
+            lens,
+            wi: -dir,
+            importance: self.importance(cos),
+            pdf: distance*distance / (cos * self.lens_area())
+        })
+    }
+
+    // Direction the camera looks along.
+    pub fn forward(&self) -> Vec3 {
+        -self.w
     }
 
     pub fn get_ray(&self, u: f64, v: f64) -> Ray {
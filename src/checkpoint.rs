@@ -0,0 +1,371 @@
+use crate::*;
+use vec3::*;
+use adaptive::PixelEstimate;
+use render_settings::{IntegratorKind, SceneKind};
+use sampler::SamplerKind;
+use std::io;
+use std::time::Duration;
+
+// Everything a progressive render needs to carry on after the pass it was
+// saved at: every pixel's running estimate and the light paths splatted so
+// far. Samplers derive their numbers from the seed, pixel and sample index,
+// so the pixels' sample counts are all the sampler state there is, and a
+// resumed render comes out bit-identical to one that never stopped.
+pub struct Checkpoint {
+    pub settings: CheckpointSettings,
+    pub elapsed: Duration,
+    pub pixels: Vec<PixelEstimate>,
+    pub splats: Vec<[i64; 3]>
+}
+
+// Everything that decides which samples a render takes and what they return.
+// A checkpoint only resumes a render with the same settings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckpointSettings {
+    pub scene: SceneKind,
+    pub width: u32,
+    pub height: u32,
+    pub seed: u64,
+    pub integrator: IntegratorKind,
+    pub sampler: SamplerKind,
+    pub samples_per_pixel: u32,
+    pub pass: u32,
+    pub threshold: Option<f64>,
+    pub max_samples: u32,
+    pub max_depth: usize,
+    // Path integrator bounce limits: diffuse, specular, transmission, volume
+    pub path_limits: [u32; 4],
+    pub rr_depth: u32,
+    pub spectral: bool,
+    pub dispersion: bool,
+    // The --env map, None for the physical sky.
+    pub environment: Option<String>,
+    pub environment_rotation: f64,
+    pub environment_intensity: f64,
+    pub ies: Option<String>
+}
+
+const MAGIC: &[u8; 8] = b"RTCKPT04";
+
+// Enums are stored as their position in these lists.
+const SCENES: [SceneKind; 2] = [SceneKind::Random, SceneKind::Showcase];
+const INTEGRATORS: [IntegratorKind; 4] = [IntegratorKind::Path, IntegratorKind::Bdpt, IntegratorKind::Sppm, IntegratorKind::Mlt];
+const SAMPLERS: [SamplerKind; 5] = [SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol, SamplerKind::BlueNoise];
+
+// Bytes per pixel: the estimate's five floats and sample count, and the splat.
+const PIXEL_BYTES: usize = 5*8 + 4 + 3*8;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Paths are stored as a presence flag and the length-prefixed UTF-8 bytes.
+fn write_path(out: &mut Vec<u8>, path: &Option<String>) {
+    out.push(path.is_some() as u8);
+    let bytes = path.as_deref().unwrap_or("").as_bytes();
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn read_path(r: &mut Reader) -> io::Result<Option<String>> {
+    let [present] = r.take()?;
+    let len = u32::from_le_bytes(r.take()?) as usize;
+    let path = String::from_utf8(r.take_slice(len)?.to_vec()).map_err(|_| invalid("path is not UTF-8".to_string()))?;
+    Ok(if present != 0 { Some(path) } else { None })
+}
+
+impl CheckpointSettings {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(SCENES.iter().position(|&k| k == self.scene).unwrap() as u8);
+        out.extend_from_slice(&self.width.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.push(INTEGRATORS.iter().position(|&k| k == self.integrator).unwrap() as u8);
+        out.push(SAMPLERS.iter().position(|&k| k == self.sampler).unwrap() as u8);
+        out.extend_from_slice(&self.samples_per_pixel.to_le_bytes());
+        out.extend_from_slice(&self.pass.to_le_bytes());
+        out.push(self.threshold.is_some() as u8);
+        out.extend_from_slice(&self.threshold.unwrap_or(0.0).to_le_bytes());
+        out.extend_from_slice(&self.max_samples.to_le_bytes());
+        out.extend_from_slice(&(self.max_depth as u64).to_le_bytes());
+        for limit in self.path_limits {
+            out.extend_from_slice(&limit.to_le_bytes());
+        }
+        out.extend_from_slice(&self.rr_depth.to_le_bytes());
+        out.push(self.spectral as u8);
+        out.push(self.dispersion as u8);
+        write_path(out, &self.environment);
+        out.extend_from_slice(&self.environment_rotation.to_le_bytes());
+        out.extend_from_slice(&self.environment_intensity.to_le_bytes());
+        write_path(out, &self.ies);
+    }
+
+    fn read(r: &mut Reader) -> io::Result<Self> {
+        let [scene] = r.take()?;
+        let scene = *SCENES.get(scene as usize).ok_or_else(|| invalid(format!("unknown scene {}", scene)))?;
+        let width = u32::from_le_bytes(r.take()?);
+        let height = u32::from_le_bytes(r.take()?);
+        let seed = u64::from_le_bytes(r.take()?);
+        let [integrator, sampler] = r.take()?;
+        let integrator = *INTEGRATORS.get(integrator as usize).ok_or_else(|| invalid(format!("unknown integrator {}", integrator)))?;
+        let sampler = *SAMPLERS.get(sampler as usize).ok_or_else(|| invalid(format!("unknown sampler {}", sampler)))?;
+        let samples_per_pixel = u32::from_le_bytes(r.take()?);
+        let pass = u32::from_le_bytes(r.take()?);
+        let [has_threshold] = r.take()?;
+        let threshold = f64::from_le_bytes(r.take()?);
+        let max_samples = u32::from_le_bytes(r.take()?);
+        let max_depth = u64::from_le_bytes(r.take()?) as usize;
+        let mut path_limits = [0; 4];
+        for limit in path_limits.iter_mut() {
+            *limit = u32::from_le_bytes(r.take()?);
+        }
+        let rr_depth = u32::from_le_bytes(r.take()?);
+        let [spectral, dispersion] = r.take()?;
+        let environment = read_path(r)?;
+        let environment_rotation = f64::from_le_bytes(r.take()?);
+        let environment_intensity = f64::from_le_bytes(r.take()?);
+        let ies = read_path(r)?;
+
+        Ok(Self {
+            scene,
+            width,
+            height,
+            seed,
+            integrator,
+            sampler,
+            samples_per_pixel,
+            pass,
+            threshold: if has_threshold != 0 { Some(threshold) } else { None },
+            max_samples,
+            max_depth,
+            path_limits,
+            rr_depth,
+            spectral: spectral != 0,
+            dispersion: dispersion != 0,
+            environment,
+            environment_rotation,
+            environment_intensity,
+            ies
+        })
+    }
+}
+
+impl Checkpoint {
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = Vec::with_capacity(64 + self.pixels.len()*PIXEL_BYTES);
+        out.extend_from_slice(MAGIC);
+        self.settings.write(&mut out);
+        out.extend_from_slice(&self.elapsed.as_nanos().to_le_bytes());
+
+        //Floats go out as their bits, so nothing is lost to rounding
+        for px in &self.pixels {
+            for v in [px.sum.x(), px.sum.y(), px.sum.z(), px.mean, px.m2] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            out.extend_from_slice(&px.samples.to_le_bytes());
+        }
+        for s in &self.splats {
+            for v in s {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+
+        //Written aside and renamed, so a crash mid-write keeps the previous checkpoint
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, out)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    // Fails unless the checkpoint was saved by a render with these settings.
+    pub fn load(path: &str, settings: &CheckpointSettings) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let mut r = Reader(&bytes);
+
+        if &r.take::<8>()? != MAGIC {
+            return Err(invalid("not a checkpoint".to_string()));
+        }
+        let saved = CheckpointSettings::read(&mut r)?;
+        if saved != *settings {
+            return Err(invalid(format!("saved with different settings: {:?}", saved)));
+        }
+        let nanos = u128::from_le_bytes(r.take()?);
+        let elapsed = Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32);
+
+        //The rest is exactly one record per pixel, checked before allocating for them
+        let n = (saved.width as usize).checked_mul(saved.height as usize);
+        if n.and_then(|n| n.checked_mul(PIXEL_BYTES)) != Some(r.0.len()) {
+            return Err(invalid(format!("size doesn't match a {}x{} image", saved.width, saved.height)));
+        }
+        let n = n.unwrap();
+        let mut pixels = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut v = [0.0; 5];
+            for x in v.iter_mut() {
+                *x = f64::from_le_bytes(r.take()?);
+            }
+            let samples = u32::from_le_bytes(r.take()?);
+            pixels.push(PixelEstimate { sum: Vec3::from_f64(v[0], v[1], v[2]), samples, mean: v[3], m2: v[4] });
+        }
+
+        let mut splats = Vec::with_capacity(n);
+        for _ in 0..n {
+            splats.push([i64::from_le_bytes(r.take()?), i64::from_le_bytes(r.take()?), i64::from_le_bytes(r.take()?)]);
+        }
+
+        Ok(Self {
+            settings: saved,
+            elapsed,
+            pixels,
+            splats
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.take_slice(N)?.try_into().unwrap())
+    }
+
+    fn take_slice(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.0.len() < n {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "checkpoint is truncated"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use adaptive::AdaptiveSampling;
+
+    fn settings() -> CheckpointSettings {
+        CheckpointSettings {
+            scene: SceneKind::Showcase,
+            width: 5,
+            height: 3,
+            seed: 0,
+            integrator: IntegratorKind::Path,
+            sampler: SamplerKind::Sobol,
+            samples_per_pixel: 24,
+            pass: 4,
+            threshold: Some(0.1),
+            max_samples: 64,
+            max_depth: 10,
+            path_limits: [16, 32, 32, 256],
+            rr_depth: 3,
+            spectral: false,
+            dispersion: false,
+            environment: Some("sky.hdr".to_string()),
+            environment_rotation: 90.0,
+            environment_intensity: 1.0,
+            ies: None
+        }
+    }
+
+    // A checkpoint path of its own for each test and process, removed when dropped.
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("{}_{}.ckpt", name, std::process::id()));
+            TempFile(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn blank(settings: CheckpointSettings) -> Checkpoint {
+        let n = (settings.width*settings.height) as usize;
+        Checkpoint { settings, elapsed: Duration::ZERO, pixels: vec![PixelEstimate::new(); n], splats: vec![[0; 3]; n] }
+    }
+
+    #[test]
+    fn resumed_render_matches_uninterrupted() {
+        let sample = |x: u32, y: u32| Vec3::from_f64(util::random_double(), x as f64, y as f64) * util::random_double();
+        let adaptive = AdaptiveSampling::new(SamplerKind::Sobol, 24).with_pass(4).with_threshold(0.1, 64);
+        let full = adaptive.render(5, 3, sample, |_| {}, |_| {});
+
+        //Stop after the first pass and go through a file
+        let stopped = AdaptiveSampling::new(SamplerKind::Sobol, 24).with_pass(4).with_threshold(0.1, 64)
+            .with_time_limit(Some(Duration::ZERO))
+            .render(5, 3, sample, |_| {}, |_| {});
+        let file = TempFile::new("resume_test");
+        let path = &file.0;
+        Checkpoint { settings: settings(), elapsed: Duration::from_millis(1500), pixels: stopped, splats: vec![[1, -2, 3]; 15] }.save(path).unwrap();
+        let loaded = Checkpoint::load(path, &settings()).unwrap();
+
+        assert_eq!(loaded.elapsed, Duration::from_millis(1500));
+        assert_eq!(loaded.splats[14], [1, -2, 3]);
+        let resumed = adaptive.resume(loaded.pixels, loaded.elapsed, 5, sample, |_| {}, |_| {});
+        for (a, b) in full.iter().zip(&resumed) {
+            assert_eq!(a.samples, b.samples);
+            assert_eq!((a.sum.x(), a.sum.y(), a.sum.z(), a.m2), (b.sum.x(), b.sum.y(), b.sum.z(), b.m2));
+        }
+    }
+
+    #[test]
+    fn refuses_other_settings() {
+        let file = TempFile::new("settings_test");
+        let path = &file.0;
+        blank(settings()).save(path).unwrap();
+
+        let others = [
+            CheckpointSettings { integrator: IntegratorKind::Bdpt, ..settings() },
+            CheckpointSettings { sampler: SamplerKind::Halton, ..settings() },
+            CheckpointSettings { samples_per_pixel: 32, ..settings() },
+            CheckpointSettings { pass: 8, ..settings() },
+            CheckpointSettings { threshold: None, ..settings() },
+            CheckpointSettings { max_samples: 128, ..settings() },
+            CheckpointSettings { max_depth: 5, ..settings() },
+            CheckpointSettings { path_limits: [4, 32, 32, 256], ..settings() },
+            CheckpointSettings { rr_depth: 5, ..settings() },
+            CheckpointSettings { spectral: true, ..settings() },
+            CheckpointSettings { dispersion: true, ..settings() },
+            CheckpointSettings { environment: None, ..settings() },
+            CheckpointSettings { environment: Some("studio.hdr".to_string()), ..settings() },
+            CheckpointSettings { environment_rotation: 0.0, ..settings() },
+            CheckpointSettings { environment_intensity: 2.0, ..settings() },
+            CheckpointSettings { ies: Some("lamp.ies".to_string()), ..settings() },
+            CheckpointSettings { scene: SceneKind::Random, ..settings() },
+            CheckpointSettings { seed: 1, ..settings() },
+            CheckpointSettings { width: 3, height: 5, ..settings() }
+        ];
+        for other in &others {
+            assert!(Checkpoint::load(path, other).is_err(), "{:?}", other);
+        }
+        assert!(Checkpoint::load(path, &settings()).is_ok());
+    }
+
+    #[test]
+    fn rejects_files_of_the_wrong_size() {
+        let file = TempFile::new("size_test");
+        let path = &file.0;
+        blank(settings()).save(path).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+
+        for len in [bytes.len() - 1, bytes.len() + 1] {
+            let mut changed = bytes.clone();
+            changed.resize(len, 0);
+            std::fs::write(path, &changed).unwrap();
+            assert!(Checkpoint::load(path, &settings()).is_err());
+        }
+
+        //A header claiming a huge image fails without trying to allocate for it
+        let huge = CheckpointSettings { width: u32::MAX, height: u32::MAX, ..settings() };
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        huge.write(&mut header);
+        header.extend_from_slice(&0u128.to_le_bytes());
+        std::fs::write(path, &header).unwrap();
+        assert!(Checkpoint::load(path, &huge).is_err());
+    }
+}
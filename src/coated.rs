@@ -0,0 +1,202 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use medium::Medium;
+use microfacet::GGX;
+
+// Thin dielectric coat (car paint, varnish) over an arbitrary base material.
+// Light reaching the base has crossed the coat twice, so the base is weighted by
+// the Fresnel transmission in both directions and by the coat's absorption
+// along the refracted paths. Internal reflections inside the coat are dropped,
+// which keeps the layer energy-conserving at the cost of a slightly darker base.
+pub struct Coated<M: Material> {
+    inner: M,
+    ior: f64,
+    distribution: GGX,
+    absorption: Color3,
+    thickness: f64
+}
+
+impl<M: Material> Coated<M> {
+    pub fn new(inner: M, ior: f64) -> Self {
+        Self {
+            inner,
+            ior,
+            distribution: GGX::isotropic(0.0),
+            absorption: Vec3::new(),
+            thickness: 0.0
+        }
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Self {
+        self.distribution = GGX::isotropic(roughness);
+        self
+    }
+
+    // absorption is the coefficient per unit of thickness, in scene units.
+    pub fn with_absorption(mut self, absorption: Color3, thickness: f64) -> Self {
+        self.absorption = absorption;
+        self.thickness = thickness;
+        self
+    }
+
+    fn transmittance(&self, cos: f64) -> Color3 {
+        if self.thickness <= 0.0 {
+            return Vec3::from_f64(1.0, 1.0, 1.0);
+        }
+
+        let sin2_t = (1.0 - cos*cos) / (self.ior*self.ior);
+        let cos_t = (1.0 - sin2_t).max(1e-4).sqrt();
+        let d = self.thickness / cos_t;
+
+        Vec3::from_f64((-self.absorption.x()*d).exp(), (-self.absorption.y()*d).exp(), (-self.absorption.z()*d).exp())
+    }
+
+    // Weight applied to the base for light entering along wi and leaving along
+    // wo, in the channels the base reports in.
+    fn base_weight(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        let mut w = self.transmittance(wo.z());
+        if wi.z() > 0.0 {
+            w *= self.transmittance(wi.z()) * (1.0 - fresnel::dielectric(wi.z(), self.ior));
+        }
+
+        if self.inner.is_spectral() { material::rec_channels(w, rec) } else { w }
+    }
+}
+
+impl<M: Material> Material for Coated<M> {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        let frame = rec.shading_frame();
+        let wo_world = -Vec3::unit(&ray.direction());
+        let wo = frame.world_to_local(&wo_world);
+        if !rec.front_face || wo.z() <= 0.0 {
+            return self.inner.scatter(ray, rec, attenuation, scattered);
+        }
+
+        //The coat is picked with probability F(wo), the base otherwise
+        let fo = fresnel::dielectric(wo.z(), self.ior);
+        let lobe = if util::random_double() < fo {
+            let (u1, u2) = util::random_2d();
+            let wh = self.distribution.sample_wh(&wo, u1, u2);
+            let wi = Vec3::reflect(&-wo, &wh);
+            if wi.z() <= 0.0 {
+                return None;
+            }
+            *scattered = Ray::new(&rec.p, &frame.local_to_world(&wi));
+            Lobe::Reflection
+        } else {
+            let lobe = self.inner.scatter(ray, rec, attenuation, scattered)?;
+
+            //A delta lobe of the base can't be weighed against the coat. Picking
+            //it with probability 1 - F(wo) cancels that factor of its weight.
+            if lobe.is_specular() {
+                let wi = frame.world_to_local(&Vec3::unit(&scattered.direction()));
+                *attenuation *= self.base_weight(&wo, &wi, rec);
+                return Some(lobe);
+            }
+            lobe
+        };
+
+        //Either lobe could have produced wi, so it's weighted by the whole mixture
+        let wi = scattered.direction();
+        let pdf = self.pdf(&wo_world, &wi, rec);
+        if pdf <= 0.0 {
+            return None;
+        }
+        *attenuation = self.eval(&wo_world, &wi, rec) / pdf;
+        Some(lobe)
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        let frame = rec.shading_frame();
+        let lo = frame.world_to_local(&Vec3::unit(wo));
+        let li = frame.world_to_local(&Vec3::unit(wi));
+        if !rec.front_face || lo.z() <= 0.0 {
+            return self.inner.eval(wo, wi, rec);
+        }
+
+        let fo = fresnel::dielectric(lo.z(), self.ior);
+        let base = self.inner.eval(wo, wi, rec) * self.base_weight(&lo, &li, rec) * (1.0 - fo);
+
+        if li.z() <= 0.0 {
+            return base;
+        }
+
+        let wh = Vec3::unit(&(lo + li));
+        let f = fresnel::dielectric(Vec3::dot(&lo, &wh), self.ior);
+        let coat = f * self.distribution.d(&wh) * self.distribution.g(&lo, &li) / (4.0*lo.z());
+
+        base + Vec3::from_f64(coat, coat, coat)
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        let frame = rec.shading_frame();
+        let lo = frame.world_to_local(&Vec3::unit(wo));
+        let li = frame.world_to_local(&Vec3::unit(wi));
+        if !rec.front_face || lo.z() <= 0.0 {
+            return self.inner.pdf(wo, wi, rec);
+        }
+
+        let fo = fresnel::dielectric(lo.z(), self.ior);
+        let base = (1.0 - fo) * self.inner.pdf(wo, wi, rec);
+
+        if li.z() <= 0.0 {
+            return base;
+        }
+
+        let wh = Vec3::unit(&(lo + li));
+        base + fo * self.distribution.pdf(&lo, &wh) / (4.0*Vec3::dot(&lo, &wh))
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color3 {
+        self.inner.emitted(rec)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.inner.is_emissive()
+    }
+
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        self.inner.emitted_spectral(rec, lambdas)
+    }
+
+    fn opacity(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        self.inner.opacity(u, v, p)
+    }
+
+    fn has_cutout(&self) -> bool {
+        self.inner.has_cutout()
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        self.inner.interior()
+    }
+
+    fn is_spectral(&self) -> bool {
+        self.inner.is_spectral()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::tests::*;
+    use lambertian::Lambertian;
+    use conductor::Conductor;
+
+    // Every sampled direction has to be reproducible through eval() and pdf(),
+    // whichever lobe produced it.
+    #[test]
+    fn sample_matches_eval_over_pdf() {
+        let diffuse = Coated::new(Lambertian::new(Vec3::from_f64(0.8, 0.3, 0.2)), 1.5).with_roughness(0.2);
+        let metal = Coated::new(Conductor::gold(0.4), 1.5).with_roughness(0.1).with_absorption(Vec3::from_f64(0.5, 0.2, 0.1), 0.3);
+        let mats: [&dyn Material; 2] = [&diffuse, &metal];
+
+        let setups = [(flat_hit(true), Vec3::from_f64(1.0, -0.3, -1.0))];
+
+        for mat in mats {
+            check_sample_matches_eval_over_pdf(mat, &setups, 2000);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use microfacet::GGX;
+use thin_film::ThinFilm;
+
+// Rough metal described by its complex index of refraction. Reflection off
+// GGX microfacets is sampled from the visible normals, so the weight reduces
+// to F * G2 / G1 and stays below one.
+pub struct Conductor {
+    eta: Vec3,
+    k: Vec3,
+    distribution: GGX,
+    film: Option<ThinFilm>
+}
+
+impl Material for Conductor {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&-Vec3::unit(&ray.direction()));
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        let (u1, u2) = util::random_2d();
+        let wh = self.distribution.sample_wh(&wo, u1, u2);
+        let wi = Vec3::reflect(&-wo, &wh);
+        if wi.z() <= 0.0 {
+            return None;
+        }
+
+        let f = self.fresnel(rec, Vec3::dot(&wo, &wh));
+
+        *attenuation = f * (self.distribution.g(&wo, &wi) / self.distribution.g1(&wo));
+        *scattered = Ray::new(&rec.p, &frame.local_to_world(&wi));
+        Some(Lobe::Reflection)
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&Vec3::unit(wo));
+        let wi = frame.world_to_local(&Vec3::unit(wi));
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3::new();
+        }
+
+        let wh = wo + wi;
+        if wh.near_zero() {
+            return Vec3::new();
+        }
+        let wh = Vec3::unit(&wh);
+
+        let f = self.fresnel(rec, Vec3::dot(&wo, &wh));
+        f * (self.distribution.d(&wh) * self.distribution.g(&wo, &wi) / (4.0*wo.z()))
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&Vec3::unit(wo));
+        let wi = frame.world_to_local(&Vec3::unit(wi));
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+
+        let wh = wo + wi;
+        if wh.near_zero() {
+            return 0.0;
+        }
+        let wh = Vec3::unit(&wh);
+
+        self.distribution.pdf(&wo, &wh) / (4.0*Vec3::dot(&wo, &wh))
+    }
+
+    // Only a film makes the reflectance depend on the ray's wavelengths
+    fn is_spectral(&self) -> bool {
+        self.film.is_some()
+    }
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, roughness: f64) -> Self {
+        Self {
+            eta,
+            k,
+            distribution: GGX::isotropic(roughness),
+            film: None
+        }
+    }
+
+    // Separate roughness along the tangent and bitangent, e.g. for brushed
+    // metal. Works on the presets too.
+    pub fn with_anisotropic_roughness(mut self, roughness_u: f64, roughness_v: f64) -> Self {
+        self.distribution = GGX::from_roughness(roughness_u, roughness_v);
+        self
+    }
+
+    pub fn with_thin_film(mut self, film: ThinFilm) -> Self {
+        self.film = Some(film);
+        self
+    }
+
+    fn fresnel(&self, rec: &HitRecord, cos_i: f64) -> Color3 {
+        match &self.film {
+            Some(film) => film.reflectance(rec, cos_i, &self.eta, &self.k),
+            None => fresnel::conductor(cos_i, &self.eta, &self.k)
+        }
+    }
+
+    // Presets are the measured spectral data sampled at roughly 650, 550 and 450nm.
+    pub fn gold(roughness: f64) -> Self {
+        Conductor::new(Vec3::from_f64(0.143119, 0.374957, 1.44248),
+                        Vec3::from_f64(3.98316, 2.38572, 1.60322),
+                        roughness)
+    }
+
+    pub fn copper(roughness: f64) -> Self {
+        Conductor::new(Vec3::from_f64(0.200438, 0.924033, 1.10221),
+                        Vec3::from_f64(3.91295, 2.45285, 2.14219),
+                        roughness)
+    }
+
+    pub fn aluminium(roughness: f64) -> Self {
+        Conductor::new(Vec3::from_f64(1.65746, 0.880369, 0.521229),
+                        Vec3::from_f64(9.22387, 6.26952, 4.837),
+                        roughness)
+    }
+
+    pub fn silver(roughness: f64) -> Self {
+        Conductor::new(Vec3::from_f64(0.155265, 0.116723, 0.138342),
+                        Vec3::from_f64(4.82835, 3.12225, 2.14696),
+                        roughness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::tests::*;
+
+    // Normal incidence reflectance of the presets against the linear F0 values
+    // tabulated in Real-Time Rendering, 4th edition, table 9.2.
+    #[test]
+    fn presets_reflect_f0_at_normal_incidence() {
+        let presets = [
+            (Conductor::gold(0.0), Vec3::from_f64(1.000, 0.782, 0.344)),
+            (Conductor::copper(0.0), Vec3::from_f64(0.955, 0.638, 0.538)),
+            (Conductor::aluminium(0.0), Vec3::from_f64(0.913, 0.922, 0.924)),
+            (Conductor::silver(0.0), Vec3::from_f64(0.972, 0.960, 0.915))
+        ];
+        let ray = Ray::new(&Vec3::from_f64(0.0, 0.0, 1.0), &Vec3::from_f64(0.0, 0.0, -1.0));
+
+        for (mat, f0) in presets {
+            let mut attenuation = Vec3::new();
+            let mut scattered = Ray::new(&Vec3::new(), &Vec3::new());
+            assert!(mat.scatter(&ray, &flat_hit(true), &mut attenuation, &mut scattered).is_some());
+
+            assert!(scattered.direction().z() > 0.999);
+            for c in 0..3 {
+                assert!((attenuation[c] - f0[c]).abs() < 0.04, "{:?} vs {:?}", attenuation, f0);
+            }
+        }
+    }
+
+    #[test]
+    fn sample_matches_eval_over_pdf() {
+        let rough = Conductor::copper(0.4);
+        let brushed = Conductor::aluminium(0.0).with_anisotropic_roughness(0.05, 0.5);
+        let mats: [&dyn Material; 2] = [&rough, &brushed];
+
+        let setups = [(flat_hit(true), Vec3::from_f64(1.0, -0.3, -1.0))];
+
+        for mat in mats {
+            check_sample_matches_eval_over_pdf(mat, &setups, 2000);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use medium::Medium;
+use texture::Texture;
+use std::sync::Arc;
+
+// Gives any material an opacity texture, e.g. the alpha of a leaf card. Hits
+// where the texture is transparent are rejected during traversal.
+pub struct Cutout {
+    inner: Arc<dyn Material>,
+    opacity: Arc<dyn Texture>
+}
+
+impl Cutout {
+    pub fn new(inner: Arc<dyn Material>, opacity: Arc<dyn Texture>) -> Self {
+        Self {
+            inner,
+            opacity
+        }
+    }
+}
+
+impl Material for Cutout {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        self.inner.scatter(ray, rec, attenuation, scattered)
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        self.inner.eval(wo, wi, rec)
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        self.inner.pdf(wo, wi, rec)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color3 {
+        self.inner.emitted(rec)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.inner.is_emissive()
+    }
+
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        self.inner.emitted_spectral(rec, lambdas)
+    }
+
+    fn opacity(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        self.opacity.value(u, v, p).x().clamp(0.0, 1.0) * self.inner.opacity(u, v, p)
+    }
+
+    fn has_cutout(&self) -> bool {
+        true
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        self.inner.interior()
+    }
+
+    fn is_spectral(&self) -> bool {
+        self.inner.is_spectral()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hittable::*;
+    use sphere::Sphere;
+    use triangle::Triangle;
+    use lambertian::Lambertian;
+
+    // Opaque only below the z = 0 plane
+    struct LowerHalf;
+
+    impl Texture for LowerHalf {
+        fn value(&self, _u: f64, _v: f64, p: &Point3) -> Color3 {
+            if p.z() < 0.0 { Vec3::from_f64(1.0, 1.0, 1.0) } else { Vec3::new() }
+        }
+    }
+
+    fn cutout(opacity: Arc<dyn Texture>) -> Arc<dyn Material> {
+        Arc::new(Cutout::new(Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5))), opacity))
+    }
+
+    #[test]
+    fn alpha_masks_skip_and_accept_hits() {
+        let ray = Ray::new(&Vec3::from_f64(0.2, 0.1, 5.0), &Vec3::from_f64(0.0, 0.0, -1.0));
+        let tri = |alpha: f64| Triangle::new(Vec3::from_f64(-1.0, -1.0, 0.0), Vec3::from_f64(1.0, -1.0, 0.0), Vec3::from_f64(0.0, 1.0, 0.0), cutout(texture::constant(alpha)));
+
+        let mut rec = HitRecord::new();
+        assert!(!Sphere::new(Vec3::new(), 1.0, cutout(texture::constant(0.0))).hit(ray, 0.001, f64::INFINITY, &mut rec));
+        assert!(!tri(0.0).hit(ray, 0.001, f64::INFINITY, &mut rec));
+
+        assert!(Sphere::new(Vec3::new(), 1.0, cutout(texture::constant(1.0))).hit(ray, 0.001, f64::INFINITY, &mut rec));
+        assert!(rec.front_face && rec.p.z() > 0.0);
+        assert!(tri(1.0).hit(ray, 0.001, f64::INFINITY, &mut rec));
+        assert!((rec.t - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cut_out_front_reveals_the_far_side() {
+        let ray = Ray::new(&Vec3::from_f64(0.2, 0.1, 5.0), &Vec3::from_f64(0.0, 0.0, -1.0));
+        let mut rec = HitRecord::new();
+
+        assert!(Sphere::new(Vec3::new(), 1.0, cutout(Arc::new(LowerHalf))).hit(ray, 0.001, f64::INFINITY, &mut rec));
+        assert!(!rec.front_face && rec.p.z() < 0.0);
+    }
+
+    #[test]
+    fn wrappers_report_a_cutout_inside() {
+        let opaque: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
+        assert!(!opaque.has_cutout());
+        assert!(cutout(texture::constant(1.0)).has_cutout());
+
+        let mix = mix::MixMaterial::new(opaque.clone(), cutout(Arc::new(LowerHalf)), texture::constant(0.5));
+        assert!(mix.has_cutout());
+        assert!(!mix::MixMaterial::new(opaque.clone(), opaque, texture::constant(0.5)).has_cutout());
+    }
+}
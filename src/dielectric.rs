@@ -1,17 +1,63 @@
 use crate::*;
 use vec3::*;
 use ray::*;
-use material::Material;
+use material::{Material, Lobe};
 
 pub struct Dieletric {
-    ir: f64
+    ir: f64,
+    dispersion: Option<Dispersion>
 }
 
+// Wavelength dependent index of refraction, wavelengths in nm.
+#[derive(Copy, Clone)]
+pub enum Dispersion {
+    // n = a + b / lambda^2, lambda in um
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + sum b_i lambda^2 / (lambda^2 - c_i), lambda in um
+    Sellmeier { b: [f64; 3], c: [f64; 3] }
+}
+
+impl Dispersion {
+    pub fn ior(&self, lambda: f64) -> f64 {
+        let l2 = (lambda * 1e-3) * (lambda * 1e-3);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i]*l2 / (l2 - c[i])).sum::<f64>();
+                n2.sqrt()
+            }
+        }
+    }
+
+    pub fn bk7() -> Self {
+        Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653]
+        }
+    }
+
+    pub fn diamond() -> Self {
+        Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030625, 0.011236, 0.0]
+        }
+    }
+}
+
+// Wavelength the fixed index of a dispersive glass is quoted at (sodium D line).
+const RGB_IOR_WAVELENGTH: f64 = 589.3;
+
 impl Material for Dieletric {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
         *attenuation = Vec3::from_f64(1.0, 1.0, 1.0);
 
-        let refract_ratio = if rec.front_face { 1.0/self.ir } else {self.ir};
+        let ir = match (&self.dispersion, ray.wavelengths()) {
+            (Some(d), Some(l)) => d.ior(l[0]),
+            (Some(d), None) => d.ior(RGB_IOR_WAVELENGTH),
+            _ => self.ir
+        };
+
+        let refract_ratio = if rec.front_face { 1.0/ir } else {ir};
         let unit_dir = Vec3::unit(&ray.direction());
 
         let cos_theta = Vec3::dot(&-unit_dir, &rec.normal);
@@ -20,14 +66,20 @@ impl Material for Dieletric {
         let should_reflect = refract_ratio * sin_theta > 1.0;
 
 
-        let direction = if should_reflect || Dieletric::reflectance(cos_theta, refract_ratio) > util::random_double() {
-            Vec3::reflect(&unit_dir, &rec.normal)
+        let (direction, lobe) = if should_reflect || Dieletric::reflectance(cos_theta, refract_ratio) > util::random_double() {
+            (Vec3::reflect(&unit_dir, &rec.normal), Lobe::SpecularReflection)
         } else {
-            util::refract(&unit_dir, &rec.normal, refract_ratio)
+            (util::refract(&unit_dir, &rec.normal, refract_ratio), Lobe::SpecularTransmission)
         };
 
         *scattered = Ray::new(&rec.p, &direction);
-        true
+
+        //The path now only makes sense for the hero wavelength
+        if let (Some(_), Some(l)) = (&self.dispersion, ray.wavelengths()) {
+            *scattered = scattered.with_wavelengths(Some([l[0]; 3]));
+        }
+
+        Some(lobe)
     }
 }
 
@@ -35,10 +87,16 @@ impl Material for Dieletric {
 impl Dieletric {
     pub fn new(ir: f64) -> Self {
         Self {
-            ir: ir
+            ir,
+            dispersion: None
         }
     }
 
+    pub fn with_dispersion(mut self, dispersion: Dispersion) -> Self {
+        self.dispersion = Some(dispersion);
+        self
+    }
+
     fn reflectance(cosine: f64, k: f64) -> f64 {
         let r0 = (1.0-k)/(1.0+k);
         let r0 = r0*r0;
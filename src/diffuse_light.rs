@@ -0,0 +1,68 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use texture::*;
+use blackbody::Blackbody;
+use std::sync::Arc;
+
+// Lambertian emitter. Only the front face emits, unless two_sided is set.
+pub struct DiffuseLight {
+    emit: Arc<dyn Texture>,
+    blackbody: Option<Blackbody>,
+    two_sided: bool
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Arc<dyn Texture>) -> Self {
+        Self {
+            emit,
+            blackbody: None,
+            two_sided: false
+        }
+    }
+
+    // Black body of the given color temperature, with a physical spectrum in
+    // spectral mode and its RGB equivalent otherwise.
+    pub fn blackbody(blackbody: Blackbody) -> Self {
+        Self {
+            emit: solid(blackbody.rgb()),
+            blackbody: Some(blackbody),
+            two_sided: false
+        }
+    }
+
+    pub fn with_two_sided(mut self, two_sided: bool) -> Self {
+        self.two_sided = two_sided;
+        self
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray: &Ray, _rec: &HitRecord, _attenuation: &mut Vec3, _scattered: &mut Ray) -> Option<Lobe> {
+        None
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color3 {
+        if !rec.front_face && !self.two_sided {
+            return Vec3::new();
+        }
+
+        self.emit.value(rec.u, rec.v, &rec.p)
+    }
+
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        if !rec.front_face && !self.two_sided {
+            return Vec3::new();
+        }
+
+        match &self.blackbody {
+            Some(bb) => bb.values(lambdas),
+            None => spectrum::upsample(&self.emit.value(rec.u, rec.v, &rec.p), lambdas)
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// Piecewise-constant distributions for sampling tabulated functions, such as
+// environment maps, proportionally to their value.
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Self {
+        let n = func.len();
+        let func: Vec<f64> = func.iter().map(|f| f.abs()).collect();
+
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f64;
+        }
+
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+            *c = if integral > 0.0 { *c / integral } else { i as f64 / n as f64 };
+        }
+
+        Self {
+            func,
+            cdf,
+            integral
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // Returns a point in [0, 1), its density and the bucket it fell in.
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.count();
+        let offset = self.cdf.partition_point(|&c| c <= u).clamp(1, n) - 1;
+
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if width > 0.0 { (u - self.cdf[offset]) / width } else { 0.0 };
+
+        let pdf = self.pdf_at(offset);
+        (((offset as f64 + du) / n as f64).min(1.0 - f64::EPSILON), pdf, offset)
+    }
+
+    pub fn pdf_at(&self, offset: usize) -> f64 {
+        if self.integral > 0.0 { self.func[offset] / self.integral } else { 1.0 }
+    }
+}
+
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D
+}
+
+impl Distribution2D {
+    // func is stored row by row, nv rows of nu values.
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Self {
+        let conditional: Vec<Distribution1D> = (0..nv).map(|v| Distribution1D::new(&func[v*nu..(v + 1)*nu])).collect();
+        let marginal = Distribution1D::new(&conditional.iter().map(|c| c.integral()).collect::<Vec<f64>>());
+
+        Self {
+            conditional,
+            marginal
+        }
+    }
+
+    // Returns (u, v) in [0, 1)^2 and its density.
+    pub fn sample(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, row) = self.marginal.sample(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let row = ((v * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        let cond = &self.conditional[row];
+        let col = ((u * cond.count() as f64) as usize).min(cond.count() - 1);
+
+        if self.marginal.integral() > 0.0 { cond.func[col] / self.marginal.integral() } else { 1.0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_density_matches_pdf() {
+        let func = [0.0, 1.0, 3.0, 0.5, 2.0, 0.0];
+        let dist = Distribution2D::new(&func, 3, 2);
+
+        for i in 0..100 {
+            let ((u, v), pdf) = dist.sample((i as f64 + 0.5) / 100.0, ((i * 37) % 100) as f64 / 100.0);
+            assert!(pdf > 0.0);
+            assert!((pdf - dist.pdf(u, v)).abs() < 1e-9);
+        }
+
+        // Integrates to one over the unit square.
+        let total: f64 = (0..6).map(|i| dist.pdf((i % 3) as f64 / 3.0 + 0.1, (i / 3) as f64 / 2.0 + 0.1) / 6.0).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+    }
+}
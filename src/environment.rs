@@ -0,0 +1,161 @@
+use crate::*;
+use vec3::*;
+use distribution::Distribution2D;
+use image::codecs::hdr::HdrDecoder;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+
+// Radiance arriving from infinitely far away, seen by rays that leave the
+// scene. Environments are also sampled directly for next event estimation.
+pub trait Environment: Send + Sync {
+    fn radiance(&self, dir: &Vec3) -> Color3;
+
+    // Picks a unit direction towards the environment, with its solid angle density.
+    fn sample(&self) -> (Vec3, f64);
+
+    fn pdf(&self, dir: &Vec3) -> f64;
+}
+
+// The original white-to-blue sky.
+pub struct Gradient {
+    bottom: Color3,
+    top: Color3
+}
+
+impl Gradient {
+    pub fn new(bottom: Color3, top: Color3) -> Self {
+        Self {
+            bottom,
+            top
+        }
+    }
+
+    pub fn sky() -> Self {
+        Gradient::new(Color3::from_f64(1.0, 1.0, 1.0), Color3::from_f64(0.5, 0.7, 1.0))
+    }
+}
+
+impl Environment for Gradient {
+    fn radiance(&self, dir: &Vec3) -> Color3 {
+        let unit_dir = Vec3::unit(dir);
+        let t = 0.5*(unit_dir.y() + 1.0);
+        (1.0-t)*self.bottom + t*self.top
+    }
+
+    fn sample(&self) -> (Vec3, f64) {
+        (util::uniform_sphere_direction(), 1.0/(4.0*PI))
+    }
+
+    fn pdf(&self, _dir: &Vec3) -> f64 {
+        1.0/(4.0*PI)
+    }
+}
+
+// Equirectangular coordinates of a direction turned by rotation radians around
+// +y: u follows the azimuth, v runs from +y at 0 to -y at 1.
+pub fn direction_to_uv(dir: &Vec3, rotation: f64) -> (f64, f64) {
+    let dir = Vec3::unit(dir);
+    let phi = f64::atan2(dir.z(), dir.x()) - rotation;
+    let theta = dir.y().clamp(-1.0, 1.0).acos();
+    ((0.5 + phi / (2.0*PI)).rem_euclid(1.0), theta / PI)
+}
+
+pub fn uv_to_direction(u: f64, v: f64, rotation: f64) -> Vec3 {
+    let theta = v * PI;
+    let phi = (u - 0.5) * 2.0*PI + rotation;
+    let sin_theta = theta.sin();
+    Vec3::from_f64(sin_theta*phi.cos(), theta.cos(), sin_theta*phi.sin())
+}
+
+// An equirectangular HDR image around the scene, +y at the top row. Directions
+// are sampled in proportion to luminance times sin(theta), which accounts for
+// the rows near the poles covering less solid angle.
+pub struct EnvironmentMap {
+    data: Vec<Color3>,
+    width: usize,
+    height: usize,
+    distribution: Distribution2D,
+    rotation: f64,
+    intensity: f64
+}
+
+impl EnvironmentMap {
+    pub fn new(path: &str) -> Self {
+        let file = File::open(path).expect("Environment map load failed.");
+        let decoder = HdrDecoder::new(BufReader::new(file)).expect("Environment map is not a valid HDR image.");
+        let meta = decoder.metadata();
+        let pixels = decoder.read_image_hdr().expect("Environment map load failed.");
+
+        let data = pixels.iter().map(|px| Vec3::from_f64(px[0] as f64, px[1] as f64, px[2] as f64)).collect();
+
+        println!("Loaded environment map {}, {}x{}", path, meta.width, meta.height);
+        EnvironmentMap::from_pixels(data, meta.width as usize, meta.height as usize)
+    }
+
+    pub fn from_pixels(data: Vec<Color3>, width: usize, height: usize) -> Self {
+        let mut weights = Vec::with_capacity(width*height);
+        for j in 0..height {
+            let sin_theta = (PI * (j as f64 + 0.5) / height as f64).sin();
+            for i in 0..width {
+                weights.push(spectrum::luminance(&data[j*width + i]) * sin_theta);
+            }
+        }
+
+        Self {
+            distribution: Distribution2D::new(&weights, width, height),
+            data,
+            width,
+            height,
+            rotation: 0.0,
+            intensity: 1.0
+        }
+    }
+
+    // Turns the map around the vertical axis, in degrees.
+    pub fn with_rotation(mut self, degrees: f64) -> Self {
+        self.rotation = util::deg_to_rad(degrees);
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    fn to_uv(&self, dir: &Vec3) -> (f64, f64) {
+        direction_to_uv(dir, self.rotation)
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, dir: &Vec3) -> Color3 {
+        let (u, v) = self.to_uv(dir);
+        let i = usize::min((u * self.width as f64) as usize, self.width - 1);
+        let j = usize::min((v * self.height as f64) as usize, self.height - 1);
+
+        self.data[j*self.width + i] * self.intensity
+    }
+
+    fn sample(&self) -> (Vec3, f64) {
+        let (u1, u2) = util::random_2d();
+        let ((u, v), pdf) = self.distribution.sample(u1, u2);
+
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return (Vec3::from_f64(0.0, 1.0, 0.0), 0.0);
+        }
+
+        (uv_to_direction(u, v, self.rotation), pdf / (2.0*PI*PI*sin_theta))
+    }
+
+    fn pdf(&self, dir: &Vec3) -> f64 {
+        let (u, v) = self.to_uv(dir);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        self.distribution.pdf(u, v) / (2.0*PI*PI*sin_theta)
+    }
+}
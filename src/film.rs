@@ -0,0 +1,86 @@
+use crate::*;
+use vec3::*;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+// Accumulates contributions that land on arbitrary pixels, such as light paths
+// connected to the camera. Pixels are addressed the way main() lays out its
+// cells, from image coordinates (u, v) in [0, 1].
+pub struct SplatFilm {
+    width: u32,
+    height: u32,
+    data: Vec<[AtomicSum; 3]>
+}
+
+// A sum that threads add to concurrently. It is kept in fixed point, since
+// integer addition gives the same result in any order and floating point
+// doesn't; 2^-32 resolution leaves room for sums up to about 2e9.
+#[derive(Default)]
+pub struct AtomicSum(AtomicI64);
+
+const FIXED_POINT: f64 = 4294967296.0;
+
+impl AtomicSum {
+    pub fn add(&self, v: f64) {
+        self.0.fetch_add((v * FIXED_POINT).round() as i64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        self.0.load(Ordering::Relaxed) as f64 / FIXED_POINT
+    }
+
+    // Returns the sum and resets it to zero.
+    pub fn take(&self) -> f64 {
+        self.0.swap(0, Ordering::Relaxed) as f64 / FIXED_POINT
+    }
+
+    // The exact fixed point value, for saving and restoring the sum.
+    pub fn raw(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set_raw(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+}
+
+impl SplatFilm {
+    pub fn new(width: u32, height: u32) -> Self {
+        let data = (0..width*height).map(|_| Default::default()).collect();
+
+        Self {
+            width,
+            height,
+            data
+        }
+    }
+
+    pub fn add(&self, u: f64, v: f64, c: &Color3) {
+        if !(c.x().is_finite() && c.y().is_finite() && c.z().is_finite()) {
+            return;
+        }
+
+        let x = u32::min((u * (self.width - 1) as f64) as u32, self.width - 1);
+        let y = u32::min((v * (self.height - 1) as f64) as u32, self.height - 1);
+        let px = &self.data[(y*self.width + x) as usize];
+        for i in 0..3 {
+            px[i].add(c[i]);
+        }
+    }
+
+    pub fn get(&self, i: usize) -> Color3 {
+        let px = &self.data[i];
+        Vec3::from_f64(px[0].get(), px[1].get(), px[2].get())
+    }
+
+    pub fn raw(&self) -> Vec<[i64; 3]> {
+        self.data.iter().map(|px| [px[0].raw(), px[1].raw(), px[2].raw()]).collect()
+    }
+
+    pub fn restore(&self, raw: &[[i64; 3]]) {
+        for (px, v) in self.data.iter().zip(raw) {
+            for i in 0..3 {
+                px[i].set_raw(v[i]);
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::vec3::*;
+use std::ops;
+use std::f64::consts::PI;
+
+// Wavelengths in nm standing in for the red, green and blue channels when
+// wavelength-dependent effects are evaluated in RGB.
+pub const RGB_WAVELENGTHS: [f64; 3] = [650.0, 532.0, 450.0];
+
+// Fresnel reflectance of a conductor with complex index of refraction eta + ik,
+// evaluated per channel. cos_i is the cosine between the incident direction and
+// the microfacet normal.
+pub fn conductor(cos_i: f64, eta: &Vec3, k: &Vec3) -> Vec3 {
+    let mut out = Vec3::new();
+    for c in 0..3 {
+        out[c] = conductor_channel(cos_i, eta[c], k[c]);
+    }
+
+    out
+}
+
+// Unpolarized Fresnel reflectance at a smooth dielectric interface. eta is the
+// ratio of the index on the transmitted side over the incident side; a negative
+// cos_i means the ray arrives from the transmitted side.
+pub fn dielectric(cos_i: f64, eta: f64) -> f64 {
+    let (cos_i, eta) = if cos_i < 0.0 {
+        (-cos_i, 1.0/eta)
+    } else {
+        (cos_i, eta)
+    };
+    let cos_i = f64::min(cos_i, 1.0);
+
+    let sin2_t = (1.0 - cos_i*cos_i) / (eta*eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parl = (eta*cos_i - cos_t) / (eta*cos_i + cos_t);
+    let r_perp = (cos_i - eta*cos_t) / (cos_i + eta*cos_t);
+
+    0.5*(r_parl*r_parl + r_perp*r_perp)
+}
+
+fn conductor_channel(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos_i = f64::clamp(cos_i, 0.0, 1.0);
+    let cos2 = cos_i*cos_i;
+    let sin2 = 1.0 - cos2;
+
+    let eta2 = eta*eta;
+    let k2 = k*k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0*t0 + 4.0*eta2*k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5*(a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0*cos_i*a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2*a2_plus_b2 + sin2*sin2;
+    let t4 = t2*sin2;
+    let rp = rs*(t3 - t4) / (t3 + t4);
+
+    0.5*(rp + rs)
+}
+
+#[derive(Copy, Clone)]
+struct Complex {
+    re: f64,
+    im: f64
+}
+
+impl Complex {
+    fn new(re: f64, im: f64) -> Self {
+        Self {
+            re,
+            im
+        }
+    }
+
+    fn norm2(&self) -> f64 {
+        self.re*self.re + self.im*self.im
+    }
+
+    fn sqrt(&self) -> Complex {
+        let r = self.norm2().sqrt();
+        let re = (0.5*(r + self.re)).max(0.0).sqrt();
+        let im = (0.5*(r - self.re)).max(0.0).sqrt();
+        Complex::new(re, if self.im < 0.0 { -im } else { im })
+    }
+
+    fn exp_i(phase: f64) -> Complex {
+        Complex::new(phase.cos(), phase.sin())
+    }
+}
+
+impl ops::Add<Complex> for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl ops::Sub<Complex> for Complex {
+    type Output = Complex;
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl ops::Mul<Complex> for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(self.re*other.re - self.im*other.im, self.re*other.im + self.im*other.re)
+    }
+}
+
+impl ops::Div<Complex> for Complex {
+    type Output = Complex;
+
+    fn div(self, other: Complex) -> Complex {
+        let d = other.norm2();
+        Complex::new((self.re*other.re + self.im*other.im) / d, (self.im*other.re - self.re*other.im) / d)
+    }
+}
+
+// Cosine of the refracted angle for a ray entering n_t from n_i.
+fn cos_transmitted(n_i: Complex, cos_i: Complex, n_t: Complex) -> Complex {
+    let sin2_i = Complex::new(1.0, 0.0) - cos_i*cos_i;
+    let ratio = n_i / n_t;
+    (Complex::new(1.0, 0.0) - ratio*ratio*sin2_i).sqrt()
+}
+
+// Reflectance of a thin film of index film_ior and the given thickness (nm)
+// lying in air on a substrate with complex index eta + ik, for one wavelength.
+// Sums the multiple reflections inside the film (Airy), averaged over s and p.
+fn thin_film_channel(cos_i: f64, film_ior: f64, thickness: f64, eta: f64, k: f64, wavelength: f64) -> f64 {
+    let n1 = Complex::new(1.0, 0.0);
+    let n2 = Complex::new(film_ior, 0.0);
+    let n3 = Complex::new(eta, k);
+
+    let cos1 = Complex::new(f64::clamp(cos_i, 0.0, 1.0), 0.0);
+    let cos2 = cos_transmitted(n1, cos1, n2);
+    let cos3 = cos_transmitted(n1, cos1, n3);
+
+    // exp(2i delta) with delta = 2 pi n2 d cos2 / lambda; cos2 is complex past the critical angle
+    let phase = Complex::new(4.0*PI*film_ior*thickness / wavelength, 0.0) * cos2;
+    let shift = Complex::exp_i(phase.re) * Complex::new((-phase.im).exp(), 0.0);
+
+    let one = Complex::new(1.0, 0.0);
+    let airy = |r12: Complex, r23: Complex| {
+        let r = (r12 + r23*shift) / (one + r12*r23*shift);
+        r.norm2()
+    };
+
+    let rs12 = (n1*cos1 - n2*cos2) / (n1*cos1 + n2*cos2);
+    let rs23 = (n2*cos2 - n3*cos3) / (n2*cos2 + n3*cos3);
+    let rp12 = (n2*cos1 - n1*cos2) / (n2*cos1 + n1*cos2);
+    let rp23 = (n3*cos2 - n2*cos3) / (n3*cos2 + n2*cos3);
+
+    f64::clamp(0.5*(airy(rs12, rs23) + airy(rp12, rp23)), 0.0, 1.0)
+}
+
+// Per channel reflectance of a film over a substrate, each channel evaluated
+// at the matching entry of wavelengths. Dielectric substrates have k = 0.
+pub fn thin_film(cos_i: f64, film_ior: f64, thickness: f64, eta: &Vec3, k: &Vec3, wavelengths: &[f64; 3]) -> Vec3 {
+    let mut out = Vec3::new();
+    for c in 0..3 {
+        out[c] = thin_film_channel(cos_i, film_ior, thickness, eta[c], k[c], wavelengths[c]);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conductor_normal_incidence() {
+        let eta = Vec3::from_f64(0.143119, 0.374957, 1.44248);
+        let k = Vec3::from_f64(3.98316, 2.38572, 1.60322);
+        let f = conductor(1.0, &eta, &k);
+
+        for c in 0..3 {
+            let expected = ((eta[c]-1.0).powi(2) + k[c]*k[c]) / ((eta[c]+1.0).powi(2) + k[c]*k[c]);
+            assert!((f[c] - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn dielectric_normal_incidence() {
+        assert!((dielectric(1.0, 1.5) - 0.04).abs() < 1e-9);
+        assert!((dielectric(-1.0, 1.5) - 0.04).abs() < 1e-9);
+        assert_eq!(dielectric(-0.1, 1.5), 1.0);
+    }
+
+    // Without a film the Airy sum collapses to the plain interface reflectance.
+    #[test]
+    fn thin_film_zero_thickness() {
+        let eta = Vec3::from_f64(0.2, 0.9, 1.1);
+        let k = Vec3::from_f64(3.9, 2.4, 2.1);
+        for &cos in &[1.0, 0.7, 0.2] {
+            let film = thin_film(cos, 1.33, 0.0, &eta, &k, &RGB_WAVELENGTHS);
+            let plain = conductor(cos, &eta, &k);
+            assert!((film - plain).length() < 1e-6);
+
+            let film = thin_film(cos, 1.33, 0.0, &Vec3::from_f64(1.5, 1.5, 1.5), &Vec3::new(), &RGB_WAVELENGTHS);
+            assert!((film.x() - dielectric(cos, 1.5)).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn conductor_grazing() {
+        let f = conductor(0.0, &Vec3::from_f64(0.2, 0.9, 1.1), &Vec3::from_f64(3.9, 2.4, 2.1));
+        for c in 0..3 {
+            assert!((f[c] - 1.0).abs() < 1e-6);
+        }
+    }
+}
@@ -2,14 +2,24 @@ use crate::*;
 use material::Material;
 use std::sync::Arc;
 use aabb::*;
+use onb::ONB;
+use light::Light;
 
 #[derive(Clone)]
 pub struct HitRecord {
     pub p: Vec3,
     pub normal: Vec3,
+    pub geo_normal: Vec3,
+    pub tangent: Vec3,
     pub t: f64,
+    pub u: f64,
+    pub v: f64,
     pub front_face: bool,
-    pub material: Arc<dyn Material>
+    pub material: Arc<dyn Material>,
+    // Wavelengths the hitting ray carries in spectral mode
+    pub wavelengths: Option<[f64; 3]>,
+    // Set when the hit primitive is registered as a light
+    pub area_light: Option<Arc<dyn Light>>
 }
 
 impl HitRecord {
@@ -17,25 +27,66 @@ impl HitRecord {
         Self {
             p: Vec3::new(),
             normal: Vec3::new(),
+            geo_normal: Vec3::new(),
+            tangent: Vec3::new(),
             t: 0.0,
+            u: 0.0,
+            v: 0.0,
             front_face: true,
-            material: Arc::new(dielectric::Dieletric::new(1.0))
+            material: Arc::new(dielectric::Dieletric::new(1.0)),
+            wavelengths: None,
+            area_light: None
         }
     }
 }
 
 impl HitRecord {
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
-        self.front_face = Vec3::dot(&ray.direction(), &outward_normal) < 0.0;
+        self.front_face = Vec3::dot(&ray.direction(), outward_normal) < 0.0;
         self.normal = if self.front_face {
             *outward_normal
         } else {
             -*outward_normal
         };
+        self.geo_normal = self.normal;
     }
+
+    // Frame around the shading normal, with u along the surface tangent when the
+    // primitive provides one.
+    pub fn shading_frame(&self) -> ONB {
+        ONB::from_wt(&self.normal, &self.tangent)
+    }
+
+    // Ray leaving the hit point, nudged off the surface along the geometric normal
+    // so a perturbed shading normal can't make it re-hit the same surface.
+    pub fn spawn_ray(&self, dir: &Vec3) -> Ray {
+        let offset = if Vec3::dot(dir, &self.geo_normal) > 0.0 { 1e-4 } else { -1e-4 };
+        Ray::new(&(self.p + offset*self.geo_normal), dir)
+    }
+}
+
+// Any-hit test run by primitives before they report an intersection. Fully
+// transparent hits are skipped so traversal carries on behind them, and
+// fractional opacity is resolved stochastically.
+pub fn accept_hit(material: &dyn Material, u: f64, v: f64, p: &Point3) -> bool {
+    if !material.has_cutout() {
+        return true;
+    }
+
+    let alpha = material.opacity(u, v, p);
+    if alpha >= 1.0 {
+        return true;
+    }
+
+    alpha > 0.0 && util::random_double() < alpha
 }
 
-pub trait Hittable {
+pub trait Hittable: Send + Sync {
     fn hit(&self, ray: Ray, min: f64, max: f64, hit_record: &mut HitRecord) -> bool;
     fn bounding_box(&self) -> Option<AABB>;
+
+    // Emissive primitives that should be sampled directly as lights.
+    fn lights(&self) -> Vec<Arc<dyn Light>> {
+        Vec::new()
+    }
 }
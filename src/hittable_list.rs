@@ -2,6 +2,7 @@ use std::sync::Arc;
 use crate::*;
 use hittable::*;
 use aabb::*;
+use light::Light;
 
 pub struct HittableList {
     pub list: Vec<Arc<dyn Hittable>>
@@ -37,13 +38,14 @@ impl Hittable for HittableList {
     }
 
     fn bounding_box(&self) -> Option<AABB> {
-        if self.list.len() == 0 {
+        if self.list.is_empty() {
             return None;
         }
 
         self.list.iter().map(|obj| obj.bounding_box()).reduce(AABB::union).unwrap()
     }
-}
 
-unsafe impl Send for HittableList {}
-unsafe impl Sync for HittableList {}
+    fn lights(&self) -> Vec<Arc<dyn Light>> {
+        self.list.iter().flat_map(|obj| obj.lights()).collect()
+    }
+}
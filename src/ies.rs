@@ -0,0 +1,333 @@
+use crate::*;
+use vec3::*;
+use light::{Light, LightSample, LightEmission, PointLight};
+use light_tree::LightBounds;
+use aabb::AABB;
+use onb::ONB;
+use std::sync::Arc;
+
+// Candela distribution of a luminaire from an IESNA LM-63 file, type C
+// photometry: vertical angles run from the nadir (0) to the zenith (180) and
+// horizontal angles go around the vertical axis.
+pub struct IesProfile {
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    // One row of vertical samples per horizontal angle
+    candela: Vec<Vec<f64>>,
+    // Multipliers for the luminaire tilted away from straight down, as pairs
+    // of angle in degrees and factor. Empty for TILT=NONE.
+    tilt: Vec<(f64, f64)>,
+    lumens: f64
+}
+
+impl IesProfile {
+    pub fn load(path: &str) -> Self {
+        let text = std::fs::read_to_string(path).expect("IES file load failed.");
+        let profile = IesProfile::parse(&text).expect("IES file is not valid LM-63.");
+
+        println!("Loaded IES profile {}, {}x{} angles, {} lm", path, profile.vertical.len(), profile.horizontal.len(), profile.lumens());
+        profile
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+
+        // Keyword lines up to the TILT line
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim()[5..].to_string(),
+                Some(_) => continue,
+                None => return Err("missing TILT line".to_string())
+            }
+        };
+
+        let mut numbers = lines.flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|t| !t.is_empty())
+            .map(|t| t.parse::<f64>().map_err(|_| format!("bad number '{}'", t)));
+        let mut next = || numbers.next().unwrap_or_else(|| Err("unexpected end of file".to_string()));
+
+        let tilt = match tilt.as_str() {
+            "NONE" => Vec::new(),
+            "INCLUDE" => {
+                // Lamp to luminaire geometry, then the angles and their multipliers
+                let _geometry = next()?;
+                let pairs = next()? as usize;
+                let angles = (0..pairs).map(|_| next()).collect::<Result<Vec<f64>, String>>()?;
+                let factors = (0..pairs).map(|_| next()).collect::<Result<Vec<f64>, String>>()?;
+                angles.into_iter().zip(factors).collect()
+            },
+            _ => return Err(format!("external TILT file '{}' is not supported", tilt))
+        };
+
+        let lamps = next()?;
+        let lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let n_vertical = next()? as usize;
+        let n_horizontal = next()? as usize;
+        let photometric_type = next()?;
+        let _units = next()?;
+        let _dimensions = [next()?, next()?, next()?];
+        let ballast = next()?;
+        let _ballast_lamp = next()?;
+        let _watts = next()?;
+
+        if photometric_type != 1.0 {
+            return Err("only type C photometry is supported".to_string());
+        }
+        if n_vertical == 0 || n_horizontal == 0 {
+            return Err("profile has no angles".to_string());
+        }
+
+        let vertical = (0..n_vertical).map(|_| next()).collect::<Result<Vec<f64>, String>>()?;
+        let horizontal = (0..n_horizontal).map(|_| next()).collect::<Result<Vec<f64>, String>>()?;
+        let mut candela = Vec::with_capacity(n_horizontal);
+        for _ in 0..n_horizontal {
+            candela.push((0..n_vertical).map(|_| next().map(|c| c*multiplier*ballast)).collect::<Result<Vec<f64>, String>>()?);
+        }
+
+        Ok(Self {
+            vertical,
+            horizontal,
+            candela,
+            tilt,
+            //-1 lumens per lamp marks absolute photometry, whatever the lamp count
+            lumens: if lumens_per_lamp < 0.0 { lumens_per_lamp } else { lamps*lumens_per_lamp }
+        })
+    }
+
+    pub fn max_candela(&self) -> f64 {
+        self.candela.iter().flatten().cloned().fold(0.0, f64::max)
+    }
+
+    // Rated lumens of all the lamps, negative for absolute photometry.
+    pub fn lumens(&self) -> f64 {
+        self.lumens
+    }
+
+    // Intensity towards the given angles in degrees, interpolated bilinearly.
+    pub fn candela(&self, vertical: f64, horizontal: f64) -> f64 {
+        let v0 = self.vertical[0];
+        let v1 = *self.vertical.last().unwrap();
+        if vertical < v0 || vertical > v1 {
+            return 0.0;
+        }
+
+        let h = self.fold_horizontal(horizontal);
+        let (hi, ht) = lerp_index(&self.horizontal, h);
+        let (vi, vt) = lerp_index(&self.vertical, vertical);
+
+        let row = |i: usize| {
+            let r = &self.candela[i];
+            r[vi] + (r[(vi + 1).min(r.len() - 1)] - r[vi])*vt
+        };
+        let a = row(hi);
+        let b = row((hi + 1).min(self.candela.len() - 1));
+        a + (b - a)*ht
+    }
+
+    // Multiplier for the luminaire tilted by the given angle in degrees from
+    // hanging straight down, held at the ends of the table.
+    pub fn tilt_factor(&self, angle: f64) -> f64 {
+        if self.tilt.is_empty() {
+            return 1.0;
+        }
+        let angles: Vec<f64> = self.tilt.iter().map(|t| t.0).collect();
+        let (i, t) = lerp_index(&angles, angle);
+        let (a, b) = (self.tilt[i].1, self.tilt[(i + 1).min(self.tilt.len() - 1)].1);
+        a + (b - a)*t
+    }
+
+    // Maps a horizontal angle into the range the file covers, by the symmetry
+    // its last angle implies.
+    fn fold_horizontal(&self, h: f64) -> f64 {
+        let h = h.rem_euclid(360.0);
+        match *self.horizontal.last().unwrap() as i64 {
+            0 => 0.0,
+            90 => {
+                let h = h % 180.0;
+                if h > 90.0 { 180.0 - h } else { h }
+            },
+            180 => if h > 180.0 { 360.0 - h } else { h },
+            _ => h
+        }
+    }
+}
+
+// Index of the segment containing x and the position within it.
+fn lerp_index(angles: &[f64], x: f64) -> (usize, f64) {
+    if angles.len() == 1 || x <= angles[0] {
+        return (0, 0.0);
+    }
+
+    let i = angles.partition_point(|&a| a <= x).min(angles.len() - 1) - 1;
+    let width = angles[i + 1] - angles[i];
+    let t = if width > 0.0 { ((x - angles[i]) / width).clamp(0.0, 1.0) } else { 0.0 };
+    (i, t)
+}
+
+// Scales a point or spot light by a measured distribution, relative to its
+// brightest direction, so the inner light's intensity is the peak intensity.
+pub struct IesLight {
+    inner: Arc<dyn Light>,
+    profile: Arc<IesProfile>,
+    frame: ONB,
+    peak: f64,
+    // The profile's tilt multiplier for the way the luminaire hangs
+    tilt: f64
+}
+
+impl IesLight {
+    // nadir is the luminaire's 0 degree vertical axis, usually straight down or
+    // along a spot light's direction.
+    pub fn new(inner: Arc<dyn Light>, profile: Arc<IesProfile>, nadir: Vec3) -> Self {
+        let peak = profile.max_candela();
+        let angle = Vec3::dot(&Vec3::unit(&nadir), &Vec3::from_f64(0.0, -1.0, 0.0)).clamp(-1.0, 1.0).acos().to_degrees();
+        let tilt = profile.tilt_factor(angle);
+        Self {
+            inner,
+            profile,
+            frame: ONB::from_w(&nadir),
+            peak,
+            tilt
+        }
+    }
+
+    // A black body point light hanging straight down, with the file's absolute
+    // candela values.
+    pub fn photometric(position: Point3, profile: Arc<IesProfile>, kelvin: f64) -> Self {
+        let inner = Arc::new(PointLight::blackbody(position, kelvin, profile.max_candela()));
+        IesLight::new(inner, profile, Vec3::from_f64(0.0, -1.0, 0.0))
+    }
+
+    fn scale(&self, emitted: &Vec3) -> f64 {
+        if self.peak <= 0.0 {
+            return 0.0;
+        }
+
+        let local = self.frame.world_to_local(emitted);
+        let vertical = local.z().clamp(-1.0, 1.0).acos().to_degrees();
+        let horizontal = f64::atan2(local.y(), local.x()).to_degrees();
+        self.profile.candela(vertical, horizontal) / self.peak * self.tilt
+    }
+}
+
+impl Light for IesLight {
+    fn sample_li(&self, p: &Point3, lambdas: Option<[f64; 3]>) -> Option<LightSample> {
+        let mut ls = self.inner.sample_li(p, lambdas)?;
+        let scale = self.scale(&-ls.wi);
+        if scale <= 0.0 {
+            return None;
+        }
+
+        ls.li = ls.li * scale;
+        Some(ls)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        self.inner.bounds()
+    }
+
+    fn is_delta(&self) -> bool {
+        self.inner.is_delta()
+    }
+
+    fn preprocess(&self, world: &AABB) {
+        self.inner.preprocess(world);
+    }
+
+    fn sample_le(&self, lambdas: Option<[f64; 3]>) -> Option<LightEmission> {
+        let mut le = self.inner.sample_le(lambdas)?;
+        le.le = le.le * self.scale(&le.dir);
+        Some(le)
+    }
+
+    fn pdf_le(&self, p: &Point3, dir: &Vec3) -> (f64, f64) {
+        self.inner.pdf_le(p, dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example luminaire published in the LM-63-2002 standard.
+    fn example() -> IesProfile {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/lm63_example.ies");
+        IesProfile::parse(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parses_bilateral_fixture() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/bilateral.ies");
+        let profile = IesProfile::parse(&std::fs::read_to_string(path).unwrap()).unwrap();
+
+        assert_eq!(profile.vertical, vec![0.0, 45.0, 90.0]);
+        assert_eq!(profile.horizontal, vec![0.0, 90.0, 180.0]);
+        assert_eq!(profile.lumens(), 1500.0);
+        assert_eq!(profile.max_candela(), 1000.0);
+
+        assert_eq!(profile.candela(0.0, 0.0), 1000.0);
+        assert_eq!(profile.candela(45.0, 90.0), 500.0);
+        assert_eq!(profile.candela(45.0, 270.0), 500.0);
+        assert_eq!(profile.candela(22.5, 45.0), 750.0);
+        assert_eq!(profile.candela(45.0, 135.0), 400.0);
+        assert_eq!(profile.candela(100.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn reads_the_published_candela_table() {
+        let profile = example();
+
+        assert_eq!(profile.lumens(), 50000.0);
+        assert_eq!(profile.max_candela(), 50000.0);
+
+        assert_eq!(profile.candela(0.0, 0.0), 10000.0);
+        assert_eq!(profile.candela(22.5, 0.0), 50000.0);
+        assert_eq!(profile.candela(45.0, 45.0), 16000.0);
+        assert_eq!(profile.candela(67.5, 90.0), 5000.0);
+        assert_eq!(profile.candela(90.0, 90.0), 1000.0);
+        assert_eq!(profile.candela(33.75, 0.0), 37500.0);
+
+        //Horizontal angles up to 90 degrees describe one quadrant
+        assert_eq!(profile.candela(22.5, 135.0), 35000.0);
+        assert_eq!(profile.candela(22.5, 270.0), 20000.0);
+        assert_eq!(profile.candela(22.5, 315.0), 35000.0);
+
+        //Nothing is emitted above the horizontal
+        assert_eq!(profile.candela(120.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn light_follows_the_profile() {
+        let peak = 50000.0;
+        let point = Arc::new(PointLight::new(Vec3::new(), Vec3::from_f64(peak, peak, peak)));
+        let light = IesLight::new(point, Arc::new(example()), Vec3::from_f64(0.0, -1.0, 0.0));
+
+        let below = light.sample_li(&Vec3::from_f64(0.0, -2.0, 0.0), None).unwrap();
+        assert!((below.li.x() - 10000.0 / 4.0).abs() < 1e-9);
+        assert!(light.sample_li(&Vec3::from_f64(0.0, 2.0, 0.0), None).is_none());
+    }
+
+    #[test]
+    fn applies_lamp_count_and_tilt() {
+        let profile = example();
+        assert_eq!(profile.tilt.len(), 7);
+        assert_eq!(profile.tilt_factor(0.0), 1.0);
+        assert_eq!(profile.tilt_factor(45.0), 0.90);
+        assert!((profile.tilt_factor(37.5) - 0.92).abs() < 1e-12);
+        assert_eq!(profile.tilt_factor(120.0), 0.98);
+
+        //Two lamps of the bilateral fixture's rating give twice the lumens
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/bilateral.ies");
+        let text = std::fs::read_to_string(path).unwrap().replace("\n1 1500 ", "\n2 1500 ");
+        assert_eq!(IesProfile::parse(&text).unwrap().lumens(), 3000.0);
+        assert_eq!(IesProfile::parse(&text).unwrap().tilt_factor(30.0), 1.0);
+
+        //A luminaire tilted by 45 degrees gives 0.9 of its profile
+        let peak = 50000.0;
+        let point = Arc::new(PointLight::new(Vec3::new(), Vec3::from_f64(peak, peak, peak)));
+        let light = IesLight::new(point, Arc::new(profile), Vec3::from_f64(1.0, -1.0, 0.0));
+        let along = light.sample_li(&Vec3::from_f64(1.0, -1.0, 0.0), None).unwrap();
+        assert!((along.li.x() / (0.9*10000.0 / 2.0) - 1.0).abs() < 1e-6, "{}", along.li.x());
+    }
+}
@@ -0,0 +1,357 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use hittable::*;
+use scene::Scene;
+use medium::Medium;
+
+// Materials, lights and media are described in RGB. In spectral mode those
+// values are upsampled to the wavelengths the ray carries.
+pub fn to_channels(c: Color3, r: &Ray) -> Color3 {
+    match r.wavelengths() {
+        Some(l) => spectrum::upsample(&c, &l),
+        None => c
+    }
+}
+
+// A BSDF value at rec in the ray's channels. Spectral materials already
+// evaluate at its wavelengths.
+pub fn bsdf_channels(f: Color3, rec: &HitRecord, r: &Ray) -> Color3 {
+    if rec.material.is_spectral() { f } else { to_channels(f, r) }
+}
+
+pub fn power_heuristic(pdf: f64, other: f64) -> f64 {
+    let (a, b) = (pdf*pdf, other*other);
+    if a + b == 0.0 { 0.0 } else { a / (a + b) }
+}
+
+pub fn direct_light(r: &Ray, rec: &HitRecord, scene: &Scene) -> Color3 {
+    let wo = -Vec3::unit(&r.direction());
+    let mut direct = Vec3::new();
+
+    //One light per vertex, picked by the light tree
+    let sampled = scene.lights.sample(&rec.p, util::random_double())
+        .and_then(|(light, pmf)| light.sample_li(&rec.p, r.wavelengths()).map(|ls| (light, pmf, ls)));
+
+    if let Some((light, pmf, ls)) = sampled {
+        let f = rec.material.eval(&wo, &ls.wi, rec);
+        if !f.near_zero() && scene.unoccluded(&rec.p, &ls.wi, ls.distance) {
+            let light_pdf = pmf * ls.pdf;
+            let weight = if light.is_area() || light.pdf_dir(&ls.wi) > 0.0 { power_heuristic(light_pdf, rec.material.pdf(&wo, &ls.wi, rec)) } else { 1.0 };
+            direct += bsdf_channels(f, rec, r) * ls.li * weight / light_pdf;
+        }
+    }
+
+    //The environment is also reachable by BSDF sampling, so both strategies are
+    //weighted with the power heuristic
+    let (wi, light_pdf) = scene.environment.sample();
+    let f = rec.material.eval(&wo, &wi, rec);
+    if light_pdf > 0.0 && !f.near_zero() && scene.unoccluded(&rec.p, &wi, f64::INFINITY) {
+        let weight = power_heuristic(light_pdf, rec.material.pdf(&wo, &wi, rec));
+        direct += bsdf_channels(f, rec, r) * to_channels(scene.environment.radiance(&wi), r) * weight / light_pdf;
+    }
+
+    direct
+}
+
+// Bounce limits per kind of scattering event, going by the lobe scatter()
+// reports. Transmission is any bounce through the surface, specular any other
+// delta bounce, and diffuse covers the remaining (diffuse and glossy) reflections.
+#[derive(Copy, Clone)]
+pub struct PathIntegrator {
+    pub max_diffuse: u32,
+    pub max_specular: u32,
+    pub max_transmission: u32,
+    pub max_volume: u32,
+    // Bounces before Russian roulette may end a path
+    pub rr_depth: u32
+}
+
+#[derive(Default)]
+struct Bounces {
+    diffuse: u32,
+    specular: u32,
+    transmission: u32,
+    volume: u32
+}
+
+impl PathIntegrator {
+    pub fn new() -> Self {
+        Self {
+            max_diffuse: 16,
+            max_specular: 32,
+            max_transmission: 32,
+            max_volume: 256,
+            rr_depth: 3
+        }
+    }
+
+    pub fn with_limits(mut self, diffuse: u32, specular: u32, transmission: u32, volume: u32) -> Self {
+        self.max_diffuse = diffuse;
+        self.max_specular = specular;
+        self.max_transmission = transmission;
+        self.max_volume = volume;
+        self
+    }
+
+    pub fn with_rr_depth(mut self, depth: u32) -> Self {
+        self.rr_depth = depth;
+        self
+    }
+
+    // Continues the path with probability tied to its throughput, scaling the
+    // survivors up so the estimate stays unbiased.
+    fn roulette(&self, depth: u32, throughput: &mut Color3) -> bool {
+        if depth < self.rr_depth {
+            return true;
+        }
+
+        let survive = f64::min(throughput.x().max(throughput.y()).max(throughput.z()), 0.95);
+        if survive <= 0.0 || util::random_double() >= survive {
+            return false;
+        }
+
+        *throughput /= survive;
+        true
+    }
+
+    pub fn li(&self, camera_ray: &Ray, scene: &Scene) -> Color3 {
+        let mut radiance = Vec3::new();
+        let mut throughput = Vec3::from_f64(1.0, 1.0, 1.0);
+        let mut r = *camera_ray;
+        let mut medium: Option<Medium> = None;
+        let mut bounces = Bounces::default();
+        let mut depth = 0;
+
+        //Density the previous bounce sampled r with, or 0 when the lights weren't
+        //also sampled directly from there (camera rays, delta lobes, media)
+        let mut bsdf_pdf = 0.0;
+
+        loop {
+            let mut rec = HitRecord::new();
+            let hit = scene.world.hit(r, 0.001, f64::INFINITY, &mut rec);
+
+            //Inside a medium the ray may scatter before it reaches the next surface
+            if let Some(m) = medium {
+                let speed = r.direction().length();
+                let t_max = if hit { rec.t * speed } else { f64::INFINITY };
+                let ms = m.sample(t_max);
+                throughput *= to_channels(ms.weight, &r);
+
+                if ms.scattered {
+                    bounces.volume += 1;
+                    depth += 1;
+                    if bounces.volume > self.max_volume || !self.roulette(depth, &mut throughput) {
+                        break;
+                    }
+
+                    let p = r.at(ms.distance / speed);
+                    let dir = m.sample_phase(&(r.direction() / speed));
+                    r = Ray::new(&p, &dir).with_wavelengths(r.wavelengths());
+                    bsdf_pdf = 0.0;
+                    continue;
+                }
+            }
+
+            if !hit {
+                let dir = Vec3::unit(&r.direction());
+                let weight = if bsdf_pdf > 0.0 { power_heuristic(bsdf_pdf, scene.environment.pdf(&dir)) } else { 1.0 };
+                radiance += throughput * to_channels(scene.environment.radiance(&dir) * weight, &r);
+
+                //So are lights at infinity with a visible disk, like the sun
+                for light in scene.lights.infinite() {
+                    let le = light.le(&dir, r.wavelengths());
+                    if !le.near_zero() {
+                        let weight = if bsdf_pdf > 0.0 { power_heuristic(bsdf_pdf, scene.lights.pmf(&r.origin(), light) * light.pdf_dir(&dir)) } else { 1.0 };
+                        radiance += throughput * le * weight;
+                    }
+                }
+                break;
+            }
+
+            let mat = rec.material.clone();
+            let mut emitted = match r.wavelengths() {
+                Some(l) => mat.emitted_spectral(&rec, &l),
+                None => mat.emitted(&rec)
+            };
+
+            //Emitters that next event estimation also samples get the MIS weight
+            if let Some(light) = &rec.area_light {
+                if bsdf_pdf > 0.0 && !emitted.near_zero() {
+                    let light_pdf = scene.lights.pmf(&r.origin(), light) * light.pdf_li(&r.origin(), &rec);
+                    emitted = emitted * power_heuristic(bsdf_pdf, light_pdf);
+                }
+            }
+            radiance += throughput * emitted;
+
+            //Shadow rays don't account for media yet, so direct lighting is
+            //skipped while the path is inside one.
+            if medium.is_none() {
+                radiance += throughput * direct_light(&r, &rec, scene);
+            }
+
+            let mut attenuation = Vec3::new();
+            let mut scattered = Ray::new(&Vec3::new(), &Vec3::new());
+            let Some(lobe) = mat.scatter(&r, &rec, &mut attenuation, &mut scattered) else {
+                break;
+            };
+            let mut attenuation = bsdf_channels(attenuation, &rec, &r);
+
+            //A dispersive interface that narrowed the ray to its hero wavelength
+            //ends the other two; the hero carries the whole estimate from here on
+            let scattered = match (r.wavelengths(), scattered.wavelengths()) {
+                (Some(l), Some(s)) => {
+                    if s != l {
+                        attenuation = Vec3::from_f64(3.0*attenuation.x(), 0.0, 0.0);
+                    }
+                    scattered
+                },
+                (l, _) => scattered.with_wavelengths(l)
+            };
+
+            let wo = -Vec3::unit(&r.direction());
+            let wi = Vec3::unit(&scattered.direction());
+            let pdf = mat.pdf(&wo, &wi, &rec);
+
+            let (count, limit) = if lobe.is_transmission() {
+                (&mut bounces.transmission, self.max_transmission)
+            } else if lobe.is_specular() {
+                (&mut bounces.specular, self.max_specular)
+            } else {
+                (&mut bounces.diffuse, self.max_diffuse)
+            };
+            *count += 1;
+            depth += 1;
+            if *count > limit {
+                break;
+            }
+
+            throughput *= attenuation;
+            if !self.roulette(depth, &mut throughput) {
+                break;
+            }
+
+            //Only MIS against direct lighting if it was sampled at this vertex
+            bsdf_pdf = if medium.is_none() && !lobe.is_specular() { pdf } else { 0.0 };
+
+            //Crossing the surface enters its interior, or leaves it when hit from inside
+            if lobe.is_transmission() {
+                medium = if rec.front_face { mat.interior() } else { None };
+            }
+            r = scattered;
+        }
+
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::f64::consts::PI;
+    use hittable_list::HittableList;
+    use triangle::Triangle;
+    use lambertian::Lambertian;
+    use light::{Light, DirectionalLight};
+    use environment::Gradient;
+    use material::{Material, Lobe};
+
+    fn plane(world: &mut HittableList, y: f64, mat: Arc<dyn Material>) {
+        let (a, b, c, d) = (Vec3::from_f64(-100.0, y, -100.0), Vec3::from_f64(100.0, y, -100.0), Vec3::from_f64(100.0, y, 100.0), Vec3::from_f64(-100.0, y, 100.0));
+        world.add(Arc::new(Triangle::new(a, b, c, mat.clone())));
+        world.add(Arc::new(Triangle::new(a, c, d, mat)));
+    }
+
+    fn under_black_sky(world: HittableList) -> Scene {
+        let mut scene = Scene::new(bvh::BVH::new(world));
+        scene.set_environment(Arc::new(Gradient::new(Vec3::new(), Vec3::new())));
+        scene
+    }
+
+    // A diffuse floor at y = 0 under a black sky
+    fn floor(albedo: f64) -> Scene {
+        let mut world = HittableList::new();
+        plane(&mut world, 0.0, Arc::new(Lambertian::new(Vec3::from_f64(albedo, albedo, albedo))));
+        under_black_sky(world)
+    }
+
+    // Glows with radiance 1 and sends every path straight back, reporting the
+    // given lobe, so only the lobe decides which bounce limit applies.
+    struct Reflector {
+        lobe: Lobe,
+        albedo: f64
+    }
+
+    impl Material for Reflector {
+        fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+            *attenuation = Vec3::from_f64(self.albedo, self.albedo, self.albedo);
+            *scattered = Ray::new(&rec.p, &-ray.direction());
+            Some(self.lobe)
+        }
+
+        fn emitted(&self, _rec: &HitRecord) -> Color3 {
+            Vec3::from_f64(1.0, 1.0, 1.0)
+        }
+    }
+
+    // Two reflectors at y = 0 and y = 1 and a ray bouncing between them, so the
+    // radiance counts the surfaces the path reached
+    fn corridor(lobe: Lobe, albedo: f64) -> (Scene, Ray) {
+        let mat: Arc<dyn Material> = Arc::new(Reflector { lobe, albedo });
+        let mut world = HittableList::new();
+        plane(&mut world, 0.0, mat.clone());
+        plane(&mut world, 1.0, mat);
+        (under_black_sky(world), Ray::new(&Vec3::from_f64(0.0, 0.5, 0.0), &Vec3::from_f64(0.0, -1.0, 0.0)))
+    }
+
+    #[test]
+    fn bounce_limits_follow_the_lobe() {
+        let integrator = PathIntegrator::new().with_limits(2, 3, 4, 0).with_rr_depth(u32::MAX);
+        let cases = [
+            (Lobe::Reflection, 2.0),
+            (Lobe::SpecularReflection, 3.0),
+            (Lobe::Transmission, 4.0),
+            (Lobe::SpecularTransmission, 4.0)
+        ];
+
+        for (lobe, limit) in cases {
+            let (scene, ray) = corridor(lobe, 1.0);
+            //The hit whose bounce goes over the limit still counts
+            assert_eq!(integrator.li(&ray, &scene).y(), limit + 1.0, "{:?}", lobe);
+        }
+    }
+
+    #[test]
+    fn russian_roulette_ends_unbounded_paths() {
+        //Without limits only roulette ends the path. At albedo 0.5 each bounce
+        //survives with probability 0.5 and the survivors keep a throughput of 1,
+        //so the radiance is the number of hits, 2 on average.
+        let integrator = PathIntegrator::new().with_limits(u32::MAX, u32::MAX, u32::MAX, u32::MAX).with_rr_depth(0);
+        let (scene, ray) = corridor(Lobe::Reflection, 0.5);
+
+        let n = 20000;
+        let mean = (0..n).map(|_| integrator.li(&ray, &scene).y()).sum::<f64>() / n as f64;
+        assert!((mean - 2.0).abs() < 0.05, "{}", mean);
+    }
+
+    #[test]
+    fn sun_disk_is_seen_once() {
+        //A large sun straight overhead, so BSDF samples often find it too
+        let alpha = util::deg_to_rad(20.0);
+        let mut scene = floor(0.5);
+        let sun: Arc<dyn Light> = Arc::new(DirectionalLight::new(Vec3::from_f64(0.0, -1.0, 0.0), Vec3::from_f64(2.0, 2.0, 2.0), 40.0));
+        scene.add_light(sun.clone());
+        let integrator = PathIntegrator::new();
+
+        let up = Ray::new(&Vec3::from_f64(0.0, 1.0, 0.0), &Vec3::from_f64(0.0, 1.0, 0.0));
+        let disk = integrator.li(&up, &scene);
+        assert!((disk.y() - 2.0 / (2.0*PI*(1.0 - alpha.cos()))).abs() < 1e-9);
+
+        let down = Ray::new(&Vec3::from_f64(0.0, 1.0, 0.0), &Vec3::from_f64(0.0, -1.0, 0.0));
+        let n = 20000;
+        let mean = (0..n).map(|_| integrator.li(&down, &scene).y()).sum::<f64>() / n as f64;
+        let expected = 0.5 * 2.0 * (1.0 + alpha.cos()) / (2.0*PI);
+        assert!((mean - expected).abs() < 0.02*expected, "{} vs {}", mean, expected);
+    }
+}
@@ -1,14 +1,15 @@
 use crate::*;
 use vec3::*;
 use ray::*;
-use material::Material;
+use material::{Material, Lobe};
+use std::f64::consts::PI;
 
 pub struct Lambertian {
     albedo: Vec3
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+    fn scatter(&self, _ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
         let mut dir = rec.normal + Vec3::random_unit_vector();
 
         if dir.near_zero() {
@@ -18,7 +19,20 @@ impl Material for Lambertian {
         *scattered = Ray::new(&rec.p, &dir);
         *attenuation = self.albedo;
 
-        true
+        Some(Lobe::Reflection)
+    }
+
+    fn eval(&self, _wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        let cos = Vec3::dot(&Vec3::unit(wi), &rec.normal);
+        if cos <= 0.0 {
+            return Vec3::new();
+        }
+
+        self.albedo * (cos / PI)
+    }
+
+    fn pdf(&self, _wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        f64::max(Vec3::dot(&Vec3::unit(wi), &rec.normal), 0.0) / PI
     }
 }
 
@@ -0,0 +1,468 @@
+use crate::*;
+use vec3::*;
+use blackbody::Blackbody;
+use onb::ONB;
+use aabb::AABB;
+use hittable::HitRecord;
+use light_tree::LightBounds;
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+// Analytic lights live in the scene's light list, not in the hittable world:
+// they are only reached through shadow rays from next event estimation.
+
+// Color of a light, either plain RGB or a black body that keeps its real
+// spectrum in spectral mode.
+#[derive(Copy, Clone)]
+pub enum LightColor {
+    Rgb(Color3),
+    Blackbody(Blackbody)
+}
+
+impl LightColor {
+    pub fn rgb(&self) -> Color3 {
+        match self {
+            LightColor::Rgb(c) => *c,
+            LightColor::Blackbody(bb) => bb.rgb()
+        }
+    }
+
+    // Values in the same channels as a ray carrying these wavelengths.
+    pub fn channels(&self, lambdas: Option<[f64; 3]>) -> Vec3 {
+        match (self, lambdas) {
+            (LightColor::Blackbody(bb), Some(l)) => bb.values(&l),
+            (_, Some(l)) => spectrum::upsample(&self.rgb(), &l),
+            (_, None) => self.rgb()
+        }
+    }
+}
+
+pub struct LightSample {
+    // Unit direction from the shading point towards the light.
+    pub wi: Vec3,
+    // Incident radiance, or irradiance-like intensity for delta lights.
+    pub li: Color3,
+    pub distance: f64,
+    // Solid angle density of wi; 1 for delta lights.
+    pub pdf: f64,
+    // Surface normal at the sampled point, zero for lights without a surface.
+    pub n: Vec3
+}
+
+// A ray leaving a light, for tracing paths from the lights.
+pub struct LightEmission {
+    pub p: Point3,
+    pub n: Vec3,
+    pub dir: Vec3,
+    pub le: Color3,
+    // Area density of p (1 for point sources) and solid angle density of dir
+    pub pdf_pos: f64,
+    pub pdf_dir: f64
+}
+
+pub trait Light: Send + Sync {
+    fn sample_li(&self, p: &Point3, lambdas: Option<[f64; 3]>) -> Option<LightSample>;
+
+    // Spatial and directional extent for the light tree, None for lights at infinity.
+    fn bounds(&self) -> Option<LightBounds>;
+
+    // Area lights are part of the geometry and can also be found by BSDF
+    // sampling, so the two strategies get combined with MIS.
+    fn is_area(&self) -> bool {
+        false
+    }
+
+    // Solid angle density of sample_li() producing the direction from p to the
+    // light point in rec.
+    fn pdf_li(&self, _p: &Point3, _rec: &HitRecord) -> f64 {
+        0.0
+    }
+
+    // Point and direction sources that can't be hit by any ray.
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    // Radiance that an escaping ray travelling along dir receives from a light
+    // at infinity with an extent, like the disk of the sun.
+    fn le(&self, _dir: &Vec3, _lambdas: Option<[f64; 3]>) -> Color3 {
+        Vec3::new()
+    }
+
+    // Solid angle density of sample_li() picking the direction dir, for lights
+    // that le() is non-zero for.
+    fn pdf_dir(&self, _dir: &Vec3) -> f64 {
+        0.0
+    }
+
+    // Called with the bounds of the world once the light is added to a scene.
+    fn preprocess(&self, _world: &AABB) {}
+
+    // Lights at infinity start light paths on a disk covering the world they
+    // were preprocessed with, and return None before that.
+    fn sample_le(&self, _lambdas: Option<[f64; 3]>) -> Option<LightEmission> {
+        None
+    }
+
+    // Densities of sample_le() emitting from p along dir.
+    fn pdf_le(&self, _p: &Point3, _dir: &Vec3) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+}
+
+fn point_bounds(p: Point3, phi: f64, w: Vec3, cos_theta_o: f64, cos_theta_e: f64) -> LightBounds {
+    LightBounds {
+        bounds: AABB::new(p, p),
+        phi,
+        w,
+        cos_theta_o,
+        cos_theta_e,
+        two_sided: false
+    }
+}
+
+pub struct PointLight {
+    position: Point3,
+    intensity: LightColor
+}
+
+impl PointLight {
+    // Radiant intensity per channel, W/(sr nm) in renderer units.
+    pub fn new(position: Point3, intensity: Color3) -> Self {
+        Self {
+            position,
+            intensity: LightColor::Rgb(intensity)
+        }
+    }
+
+    pub fn blackbody(position: Point3, kelvin: f64, candela: f64) -> Self {
+        Self {
+            position,
+            intensity: LightColor::Blackbody(Blackbody::new(kelvin, candela))
+        }
+    }
+
+    pub fn from_lumens(position: Point3, kelvin: f64, lumens: f64) -> Self {
+        PointLight::blackbody(position, kelvin, lumens / (4.0*PI))
+    }
+}
+
+impl Light for PointLight {
+    fn sample_li(&self, p: &Point3, lambdas: Option<[f64; 3]>) -> Option<LightSample> {
+        let d = self.position - *p;
+        let dist2 = d.length_squared();
+        let distance = dist2.sqrt();
+
+        Some(LightSample {
+            wi: d / distance,
+            li: self.intensity.channels(lambdas) / dist2,
+            distance,
+            pdf: 1.0,
+            n: Vec3::new()
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let phi = 4.0*PI*spectrum::luminance(&self.intensity.rgb());
+        Some(point_bounds(self.position, phi, Vec3::from_f64(0.0, 0.0, 1.0), -1.0, 0.0))
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn sample_le(&self, lambdas: Option<[f64; 3]>) -> Option<LightEmission> {
+        Some(LightEmission {
+            p: self.position,
+            n: Vec3::new(),
+            dir: util::uniform_sphere_direction(),
+            le: self.intensity.channels(lambdas),
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (4.0*PI)
+        })
+    }
+
+    fn pdf_le(&self, _p: &Point3, _dir: &Vec3) -> (f64, f64) {
+        (1.0, 1.0 / (4.0*PI))
+    }
+}
+
+pub struct SpotLight {
+    position: Point3,
+    direction: Vec3,
+    intensity: LightColor,
+    cos_inner: f64,
+    cos_outer: f64
+}
+
+impl SpotLight {
+    // Full intensity inside the inner cone, falling off smoothly to zero at the
+    // outer cone. Angles are half-angles in degrees.
+    pub fn new(position: Point3, direction: Vec3, intensity: Color3, inner: f64, outer: f64) -> Self {
+        Self {
+            position,
+            direction: Vec3::unit(&direction),
+            intensity: LightColor::Rgb(intensity),
+            cos_inner: util::deg_to_rad(inner).cos(),
+            cos_outer: util::deg_to_rad(outer).cos()
+        }
+    }
+
+    pub fn blackbody(position: Point3, direction: Vec3, kelvin: f64, candela: f64, inner: f64, outer: f64) -> Self {
+        let mut light = SpotLight::new(position, direction, Vec3::new(), inner, outer);
+        light.intensity = LightColor::Blackbody(Blackbody::new(kelvin, candela));
+        light
+    }
+
+    // Flux spread over the cone, counting the falloff region at half weight.
+    pub fn from_lumens(position: Point3, direction: Vec3, kelvin: f64, lumens: f64, inner: f64, outer: f64) -> Self {
+        let (cos_inner, cos_outer) = (util::deg_to_rad(inner).cos(), util::deg_to_rad(outer).cos());
+        let solid_angle = 2.0*PI*(1.0 - 0.5*(cos_inner + cos_outer));
+        SpotLight::blackbody(position, direction, kelvin, lumens / solid_angle, inner, outer)
+    }
+
+    fn falloff(&self, cos: f64) -> f64 {
+        if cos >= self.cos_inner {
+            return 1.0;
+        }
+        if cos <= self.cos_outer {
+            return 0.0;
+        }
+
+        let t = (cos - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        t*t*(3.0 - 2.0*t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample_li(&self, p: &Point3, lambdas: Option<[f64; 3]>) -> Option<LightSample> {
+        let d = self.position - *p;
+        let dist2 = d.length_squared();
+        let distance = dist2.sqrt();
+        let wi = d / distance;
+
+        let falloff = self.falloff(Vec3::dot(&-wi, &self.direction));
+        if falloff <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            wi,
+            li: self.intensity.channels(lambdas) * (falloff / dist2),
+            distance,
+            pdf: 1.0,
+            n: Vec3::new()
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let solid_angle = 2.0*PI*(1.0 - 0.5*(self.cos_inner + self.cos_outer));
+        let phi = solid_angle*spectrum::luminance(&self.intensity.rgb());
+        let cos_theta_e = (self.cos_outer.acos() - self.cos_inner.acos()).cos();
+        Some(point_bounds(self.position, phi, self.direction, self.cos_inner, cos_theta_e))
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    // Uniform over the outer cone.
+    fn sample_le(&self, lambdas: Option<[f64; 3]>) -> Option<LightEmission> {
+        let (u1, u2) = util::random_2d();
+        let cos = 1.0 - u1*(1.0 - self.cos_outer);
+        let sin = (1.0 - cos*cos).max(0.0).sqrt();
+        let phi = 2.0*PI*u2;
+        let dir = ONB::from_w(&self.direction).local_to_world(&Vec3::from_f64(sin*phi.cos(), sin*phi.sin(), cos));
+
+        Some(LightEmission {
+            p: self.position,
+            n: Vec3::new(),
+            dir,
+            le: self.intensity.channels(lambdas) * self.falloff(cos),
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (2.0*PI*(1.0 - self.cos_outer))
+        })
+    }
+
+    fn pdf_le(&self, _p: &Point3, dir: &Vec3) -> (f64, f64) {
+        if Vec3::dot(&Vec3::unit(dir), &self.direction) < self.cos_outer {
+            return (0.0, 0.0);
+        }
+        (1.0, 1.0 / (2.0*PI*(1.0 - self.cos_outer)))
+    }
+}
+
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: LightColor,
+    cos_max: f64,
+    // Bounding sphere of the world, from the first scene the light was added to
+    world: OnceLock<(Point3, f64)>
+}
+
+impl DirectionalLight {
+    // direction is the way the light travels. With a non-zero angular diameter
+    // (degrees, 0.53 for the sun) the source is a small disk and casts soft shadows.
+    pub fn new(direction: Vec3, irradiance: Color3, angular_diameter: f64) -> Self {
+        Self {
+            direction: Vec3::unit(&direction),
+            irradiance: LightColor::Rgb(irradiance),
+            cos_max: util::deg_to_rad(0.5*angular_diameter).cos(),
+            world: OnceLock::new()
+        }
+    }
+
+    fn solid_angle(&self) -> f64 {
+        2.0*PI*(1.0 - self.cos_max)
+    }
+
+    fn in_disk(&self, dir: &Vec3) -> bool {
+        self.cos_max < 1.0 && Vec3::dot(&Vec3::unit(dir), &-self.direction) >= self.cos_max
+    }
+
+    // Uniform direction towards the disk.
+    fn sample_disk(&self) -> Vec3 {
+        let (u1, u2) = util::random_2d();
+        let cos = 1.0 - u1*(1.0 - self.cos_max);
+        let sin = (1.0 - cos*cos).max(0.0).sqrt();
+        let phi = 2.0*PI*u2;
+        ONB::from_w(&-self.direction).local_to_world(&Vec3::from_f64(sin*phi.cos(), sin*phi.sin(), cos))
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample_li(&self, _p: &Point3, lambdas: Option<[f64; 3]>) -> Option<LightSample> {
+        let e = self.irradiance.channels(lambdas);
+
+        if self.cos_max >= 1.0 {
+            return Some(LightSample {
+                wi: -self.direction,
+                li: e,
+                distance: f64::INFINITY,
+                pdf: 1.0,
+                n: Vec3::new()
+            });
+        }
+
+        // Uniform over the cone; the disk radiance is E / solid angle, so li / pdf = E.
+        let wi = self.sample_disk();
+        let solid_angle = self.solid_angle();
+
+        Some(LightSample {
+            wi,
+            li: e / solid_angle,
+            distance: f64::INFINITY,
+            pdf: 1.0 / solid_angle,
+            n: Vec3::new()
+        })
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    // Escaping rays can see the disk, but bidirectional methods still only
+    // connect to it, as to a delta light.
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn le(&self, dir: &Vec3, lambdas: Option<[f64; 3]>) -> Color3 {
+        if !self.in_disk(dir) {
+            return Vec3::new();
+        }
+
+        self.irradiance.channels(lambdas) / self.solid_angle()
+    }
+
+    fn pdf_dir(&self, dir: &Vec3) -> f64 {
+        if self.in_disk(dir) { 1.0 / self.solid_angle() } else { 0.0 }
+    }
+
+    fn preprocess(&self, world: &AABB) {
+        let center = 0.5*(world.min() + world.max());
+        let _ = self.world.set((center, 0.5*(world.max() - world.min()).length()));
+    }
+
+    // Parallel rays through a disk facing the light, started outside the world.
+    // With an angular diameter each ray leans towards a random point of the sun.
+    fn sample_le(&self, lambdas: Option<[f64; 3]>) -> Option<LightEmission> {
+        let &(center, radius) = self.world.get()?;
+        let e = self.irradiance.channels(lambdas);
+        let (dir, le, pdf_dir) = if self.cos_max >= 1.0 {
+            (self.direction, e, 1.0)
+        } else {
+            (-self.sample_disk(), e / self.solid_angle(), 1.0 / self.solid_angle())
+        };
+
+        let d = radius*util::random_unit_disk();
+        let frame = ONB::from_w(&dir);
+        Some(LightEmission {
+            p: center - dir*radius + frame.local_to_world(&Vec3::from_f64(d.x(), d.y(), 0.0)),
+            n: Vec3::new(),
+            dir,
+            le,
+            pdf_pos: 1.0 / (PI*radius*radius),
+            pdf_dir
+        })
+    }
+
+    // The direction density is a delta without an angular diameter, and left at zero.
+    fn pdf_le(&self, _p: &Point3, dir: &Vec3) -> (f64, f64) {
+        let (_, radius) = match self.world.get() {
+            Some(&w) if w.1 > 0.0 => w,
+            _ => return (0.0, 0.0)
+        };
+        (1.0 / (PI*radius*radius), self.pdf_dir(&-*dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn point_lights_report_the_densities_they_sample_with() {
+        let point = PointLight::new(Vec3::new(), Vec3::from_f64(1.0, 1.0, 1.0));
+        let spot = SpotLight::new(Vec3::new(), Vec3::from_f64(0.0, -1.0, 0.0), Vec3::from_f64(1.0, 1.0, 1.0), 20.0, 30.0);
+        let lights: [&dyn Light; 2] = [&point, &spot];
+
+        for light in lights {
+            let le = light.sample_le(None).unwrap();
+            assert_eq!(light.pdf_le(&le.p, &le.dir), (le.pdf_pos, le.pdf_dir));
+            assert_eq!(le.pdf_pos, 1.0);
+        }
+    }
+
+    #[test]
+    fn directional_light_emits_over_a_disk_covering_the_world() {
+        let e = Vec3::from_f64(2.0, 2.0, 2.0);
+        let world = AABB::new(Vec3::from_f64(-1.0, -2.0, -3.0), Vec3::from_f64(3.0, 2.0, 1.0));
+        let center = Vec3::from_f64(1.0, 0.0, -1.0);
+        let radius = (world.max() - world.min()).length() / 2.0;
+
+        for diameter in [0.0, 10.0] {
+            let light = DirectionalLight::new(Vec3::from_f64(1.0, -1.0, 0.0), e, diameter);
+            assert!(light.sample_le(None).is_none());
+            light.preprocess(&world);
+
+            for _ in 0..100 {
+                let le = light.sample_le(None).unwrap();
+
+                //Starts on the disk outside the world, heading in through it
+                let offset = le.p - center;
+                assert!((Vec3::dot(&offset, &le.dir) + radius).abs() < 1e-9);
+                assert!((offset + le.dir*radius).length() <= radius + 1e-9);
+                assert!(Vec3::dot(&le.dir, &light.direction) >= light.cos_max - 1e-12);
+
+                //The disk receives the whole irradiance
+                let power = le.le / (le.pdf_pos * le.pdf_dir);
+                assert!((power - e*(PI*radius*radius)).length() < 1e-9);
+
+                let (pos, dir) = light.pdf_le(&le.p, &le.dir);
+                assert_eq!(pos, le.pdf_pos);
+                assert_eq!(dir, if diameter > 0.0 { le.pdf_dir } else { 0.0 });
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::*;
+use vec3::*;
+use aabb::AABB;
+use light::Light;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// Where a light is, how much it emits and which way, conservatively. Emission
+// leaves within cos_theta_e of some normal that is itself within cos_theta_o of w.
+#[derive(Clone)]
+pub struct LightBounds {
+    pub bounds: AABB,
+    pub phi: f64,
+    pub w: Vec3,
+    pub cos_theta_o: f64,
+    pub cos_theta_e: f64,
+    pub two_sided: bool
+}
+
+// cos(max(0, a - b)) and sin(max(0, a - b)) from the sines and cosines of a and b.
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b { 1.0 } else { cos_a*cos_b + sin_a*sin_b }
+}
+
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b { 0.0 } else { sin_a*cos_b - cos_a*sin_b }
+}
+
+fn safe_sqrt(x: f64) -> f64 {
+    x.max(0.0).sqrt()
+}
+
+impl LightBounds {
+    fn centroid(&self) -> Point3 {
+        0.5*(self.bounds.min() + self.bounds.max())
+    }
+
+    // Estimated contribution to a point, following Conty Estevez and Kulla 2018
+    // as refined in pbrt-v4. Zero only where the lights can't reach.
+    pub fn importance(&self, p: &Point3) -> f64 {
+        let pc = self.centroid();
+        let diag = self.bounds.max() - self.bounds.min();
+        let d2 = f64::max((*p - pc).length_squared(), 0.5*diag.length());
+
+        let wi = *p - pc;
+        let mut cos_theta_w = if wi.length_squared() > 0.0 { Vec3::dot(&Vec3::unit(&wi), &self.w) } else { 1.0 };
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w*cos_theta_w);
+
+        // Angle subtended by the bounds, seen from p
+        let cos_theta_b = self.subtended_cos(p);
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b*cos_theta_b);
+
+        let sin_theta_o = safe_sqrt(1.0 - self.cos_theta_o*self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        self.phi * cos_theta_p / d2
+    }
+
+    fn subtended_cos(&self, p: &Point3) -> f64 {
+        let (min, max) = (self.bounds.min(), self.bounds.max());
+        let inside = (0..3).all(|i| p[i] >= min[i] && p[i] <= max[i]);
+        if inside {
+            return -1.0;
+        }
+
+        let center = self.centroid();
+        let radius2 = (max - center).length_squared();
+        let dist2 = (*p - center).length_squared();
+        if dist2 < radius2 {
+            return -1.0;
+        }
+
+        safe_sqrt(1.0 - radius2 / dist2)
+    }
+
+    pub fn union(a: &LightBounds, b: &LightBounds) -> LightBounds {
+        if a.phi == 0.0 {
+            return b.clone();
+        }
+        if b.phi == 0.0 {
+            return a.clone();
+        }
+
+        let (w, cos_theta_o) = cone_union(a.w, a.cos_theta_o, b.w, b.cos_theta_o);
+        LightBounds {
+            bounds: AABB::union(Some(a.bounds.clone()), Some(b.bounds.clone())).unwrap(),
+            phi: a.phi + b.phi,
+            w,
+            cos_theta_o,
+            cos_theta_e: f64::min(a.cos_theta_e, b.cos_theta_e),
+            two_sided: a.two_sided || b.two_sided
+        }
+    }
+}
+
+// Smallest cone containing two cones of directions.
+fn cone_union(wa: Vec3, cos_a: f64, wb: Vec3, cos_b: f64) -> (Vec3, f64) {
+    let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+    let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+    let theta_d = Vec3::dot(&wa, &wb).clamp(-1.0, 1.0).acos();
+
+    if f64::min(theta_d + theta_b, PI) <= theta_a {
+        return (wa, cos_a);
+    }
+    if f64::min(theta_d + theta_a, PI) <= theta_b {
+        return (wb, cos_b);
+    }
+
+    let theta_o = 0.5*(theta_a + theta_d + theta_b);
+    if theta_o >= PI {
+        return (wa, -1.0);
+    }
+
+    // Rotate wa towards wb so the new axis sits in the middle of the spread
+    let axis = Vec3::cross(&wa, &wb);
+    if axis.length_squared() < 1e-12 {
+        return (wa, -1.0);
+    }
+    let k = Vec3::unit(&axis);
+    let theta_r = theta_o - theta_a;
+    let w = wa*theta_r.cos() + Vec3::cross(&k, &wa)*theta_r.sin();
+
+    (Vec3::unit(&w), theta_o.cos())
+}
+
+enum Node {
+    Leaf { light: usize, bounds: LightBounds },
+    Interior { children: [usize; 2], bounds: LightBounds }
+}
+
+impl Node {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            Node::Leaf { bounds, .. } => bounds,
+            Node::Interior { bounds, .. } => bounds
+        }
+    }
+}
+
+// Picks one light for next event estimation. Bounded lights sit in a binary
+// tree walked from the root by the importance of each child; lights at infinity
+// can't be bounded and are picked uniformly alongside the tree.
+pub struct LightTree {
+    lights: Vec<Arc<dyn Light>>,
+    infinite: Vec<usize>,
+    nodes: Vec<Node>,
+    // Left/right choices from the root to each bounded light, keyed by address
+    trails: HashMap<usize, (u64, u32)>
+}
+
+fn key(light: &Arc<dyn Light>) -> usize {
+    Arc::as_ptr(light) as *const () as usize
+}
+
+impl LightTree {
+    pub fn new(lights: Vec<Arc<dyn Light>>) -> Self {
+        let mut infinite = Vec::new();
+        let mut bounded = Vec::new();
+        for (i, light) in lights.iter().enumerate() {
+            match light.bounds() {
+                Some(b) if b.phi > 0.0 => bounded.push((i, b)),
+                Some(_) => {},
+                None => infinite.push(i)
+            }
+        }
+
+        let mut tree = Self {
+            lights,
+            infinite,
+            nodes: Vec::new(),
+            trails: HashMap::new()
+        };
+
+        if !bounded.is_empty() {
+            tree.build(&mut bounded, 0, 0);
+        }
+
+        tree
+    }
+
+    // Lights at infinity, which escaping rays may see.
+    pub fn infinite(&self) -> impl Iterator<Item = &Arc<dyn Light>> {
+        self.infinite.iter().map(|&i| &self.lights[i])
+    }
+
+    pub fn lights(&self) -> &[Arc<dyn Light>] {
+        &self.lights
+    }
+
+    // Splits at the median centroid along the widest axis of the centroids.
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        if lights.len() == 1 {
+            let (light, bounds) = lights[0].clone();
+            self.trails.insert(key(&self.lights[light]), (trail, depth));
+            self.nodes.push(Node::Leaf { light, bounds });
+            return self.nodes.len() - 1;
+        }
+
+        let centroids: Vec<Point3> = lights.iter().map(|l| l.1.centroid()).collect();
+        let lo = centroids.iter().fold(centroids[0], |a, c| Vec3::from_f64(a.x().min(c.x()), a.y().min(c.y()), a.z().min(c.z())));
+        let hi = centroids.iter().fold(centroids[0], |a, c| Vec3::from_f64(a.x().max(c.x()), a.y().max(c.y()), a.z().max(c.z())));
+        let extent = hi - lo;
+        let axis = if extent.x() > extent.y() && extent.x() > extent.z() { 0 } else if extent.y() > extent.z() { 1 } else { 2 };
+
+        lights.sort_by(|a, b| a.1.centroid()[axis].partial_cmp(&b.1.centroid()[axis]).unwrap());
+        let mid = lights.len() / 2;
+
+        let index = self.nodes.len();
+        self.nodes.push(Node::Leaf { light: 0, bounds: lights[0].1.clone() });
+
+        let (left, right) = lights.split_at_mut(mid);
+        let l = self.build(left, trail, depth + 1);
+        let r = self.build(right, trail | (1 << depth), depth + 1);
+
+        let bounds = LightBounds::union(self.nodes[l].bounds(), self.nodes[r].bounds());
+        self.nodes[index] = Node::Interior { children: [l, r], bounds };
+        index
+    }
+
+    fn infinite_probability(&self) -> f64 {
+        let n = self.infinite.len() as f64;
+        let tree = if self.nodes.is_empty() { 0.0 } else { 1.0 };
+        if n + tree == 0.0 { 0.0 } else { n / (n + tree) }
+    }
+
+    // A light with the probability of having picked it.
+    pub fn sample(&self, p: &Point3, u: f64) -> Option<(Arc<dyn Light>, f64)> {
+        let p_inf = self.infinite_probability();
+        if u < p_inf {
+            let i = ((u / p_inf * self.infinite.len() as f64) as usize).min(self.infinite.len() - 1);
+            return Some((self.lights[self.infinite[i]].clone(), p_inf / self.infinite.len() as f64));
+        }
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut u = ((u - p_inf) / (1.0 - p_inf)).min(1.0 - f64::EPSILON);
+        let mut pmf = 1.0 - p_inf;
+        let mut node = 0;
+        loop {
+            match &self.nodes[node] {
+                Node::Leaf { light, bounds } => {
+                    if bounds.importance(p) <= 0.0 {
+                        return None;
+                    }
+                    return Some((self.lights[*light].clone(), pmf));
+                },
+                Node::Interior { children, .. } => {
+                    let il = self.nodes[children[0]].bounds().importance(p);
+                    let ir = self.nodes[children[1]].bounds().importance(p);
+                    if il + ir <= 0.0 {
+                        return None;
+                    }
+
+                    let pl = il / (il + ir);
+                    if u < pl {
+                        u /= pl;
+                        pmf *= pl;
+                        node = children[0];
+                    } else {
+                        u = ((u - pl) / (1.0 - pl)).min(1.0 - f64::EPSILON);
+                        pmf *= 1.0 - pl;
+                        node = children[1];
+                    }
+                }
+            }
+        }
+    }
+
+    // Probability that sample() picks the given light from p.
+    pub fn pmf(&self, p: &Point3, light: &Arc<dyn Light>) -> f64 {
+        let (mut trail, depth) = match self.trails.get(&key(light)) {
+            Some(t) => *t,
+            None => {
+                let n = self.infinite.len();
+                let is_infinite = self.infinite.iter().any(|&i| key(&self.lights[i]) == key(light));
+                return if is_infinite { self.infinite_probability() / n as f64 } else { 0.0 };
+            }
+        };
+
+        let mut pmf = 1.0 - self.infinite_probability();
+        let mut node = 0;
+        for _ in 0..depth {
+            match &self.nodes[node] {
+                Node::Leaf { .. } => break,
+                Node::Interior { children, .. } => {
+                    let il = self.nodes[children[0]].bounds().importance(p);
+                    let ir = self.nodes[children[1]].bounds().importance(p);
+                    if il + ir <= 0.0 {
+                        return 0.0;
+                    }
+
+                    let side = (trail & 1) as usize;
+                    pmf *= [il, ir][side] / (il + ir);
+                    node = children[side];
+                    trail >>= 1;
+                }
+            }
+        }
+
+        pmf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use light::{PointLight, DirectionalLight};
+
+    #[test]
+    fn pmf_matches_sampling() {
+        let mut lights: Vec<Arc<dyn Light>> = (0..7).map(|i| {
+            let pos = Vec3::from_f64(i as f64, 0.5*(i % 3) as f64, -(i as f64));
+            Arc::new(PointLight::new(pos, Vec3::from_f64(1.0 + i as f64, 1.0, 1.0))) as Arc<dyn Light>
+        }).collect();
+        lights.push(Arc::new(DirectionalLight::new(Vec3::from_f64(0.0, -1.0, 0.0), Vec3::from_f64(1.0, 1.0, 1.0), 0.0)));
+        let tree = LightTree::new(lights.clone());
+
+        let p = Vec3::from_f64(2.5, 1.0, 0.3);
+        let total: f64 = lights.iter().map(|l| tree.pmf(&p, l)).sum();
+        assert!((total - 1.0).abs() < 1e-9);
+
+        for i in 0..64 {
+            let (light, pmf) = tree.sample(&p, (i as f64 + 0.5) / 64.0).unwrap();
+            assert!((pmf - tree.pmf(&p, &light)).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::OnceLock;
+
+// Building blocks for the samplers: hashing, shuffles and scrambles that turn
+// one point set into decorrelated ones per pixel and dimension, following
+// pbrt-v4.
+
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, &v| mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15)))
+}
+
+// Uniform value in [0, 1) from the top 53 bits.
+pub fn to_unit(v: u64) -> f64 {
+    (v >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn u32_to_unit(v: u32) -> f64 {
+    v as f64 / 4294967296.0
+}
+
+// Element i of a pseudo-random permutation of 0..l picked by p (Kensler 2013).
+pub fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+
+    (i.wrapping_add(p)) % l
+}
+
+// Hash-based Owen scrambling of a 32-bit fixed point value (Laine and Karras 2011).
+pub fn owen_scramble(mut v: u32, seed: u32) -> u32 {
+    v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x05526c56);
+    v ^= v.wrapping_mul(0x53a22864);
+    v.reverse_bits()
+}
+
+// The first two dimensions of the Sobol sequence form a (0, 2)-sequence, which
+// is all the padded samplers need: van der Corput, and the dimension generated
+// by the polynomial x + 1.
+pub fn sobol_bits(index: u32, dimension: usize) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+
+    let mut v = 1u32 << 31;
+    let mut bits = 0;
+    let mut i = index;
+    while i != 0 {
+        if i & 1 != 0 {
+            bits ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    bits
+}
+
+pub fn sobol_owen(index: u32, dimension: usize, seed: u32) -> f64 {
+    u32_to_unit(owen_scramble(sobol_bits(index, dimension), seed))
+}
+
+pub const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131
+];
+
+// Radical inverse with every digit permuted according to the digits below it,
+// the base b analogue of Owen scrambling. Leading zeros get permuted too, so
+// even the first few points of a large base spread over [0, 1).
+pub fn owen_radical_inverse(base: u64, mut index: u64, seed: u64) -> f64 {
+    let inv = 1.0 / base as f64;
+    let mut inv_n = 1.0;
+    let mut reversed = 0u64;
+    while 1.0 - (base - 1) as f64 * inv_n < 1.0 {
+        let digit = index % base;
+        index /= base;
+        let p = mix_bits(seed ^ reversed) as u32;
+        reversed = reversed*base + permutation_element(digit as u32, base as u32, p) as u64;
+        inv_n *= inv;
+    }
+    f64::min(reversed as f64 * inv_n, 1.0 - f64::EPSILON)
+}
+
+pub const BLUE_NOISE_SIZE: usize = 64;
+
+// Rank of each texel of a tileable blue noise mask, scaled to [0, 1).
+// Built once with void-and-cluster (Ulichney 1993).
+pub fn blue_noise(x: usize, y: usize) -> f64 {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    let mask = MASK.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE, 1.9));
+    mask[(y % BLUE_NOISE_SIZE)*BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE]
+}
+
+fn void_and_cluster(n: usize, sigma: f64) -> Vec<f64> {
+    let size = n*n;
+
+    //Toroidal Gaussian energy each set texel adds to the others
+    let kernel: Vec<f64> = (0..size).map(|i| {
+        let (dx, dy) = ((i % n).min(n - i % n) as f64, (i / n).min(n - i / n) as f64);
+        (-(dx*dx + dy*dy) / (2.0*sigma*sigma)).exp()
+    }).collect();
+
+    let update = |energy: &mut Vec<f64>, p: usize, sign: f64| {
+        let (px, py) = (p % n, p / n);
+        for (i, e) in energy.iter_mut().enumerate() {
+            let dx = (i % n + n - px) % n;
+            let dy = (i / n + n - py) % n;
+            *e += sign*kernel[dy*n + dx];
+        }
+    };
+    let tightest_cluster = |set: &[bool], energy: &[f64]| (0..size).filter(|&i| set[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap();
+    let largest_void = |set: &[bool], energy: &[f64]| (0..size).filter(|&i| !set[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap();
+
+    //Initial pattern: a tenth of the texels, relaxed until no point moves
+    let mut set = vec![false; size];
+    let mut energy = vec![0.0; size];
+    let mut ones = 0;
+    let mut k = 0;
+    while ones < size / 10 {
+        let p = (hash(&[k]) % size as u64) as usize;
+        k += 1;
+        if !set[p] {
+            set[p] = true;
+            update(&mut energy, p, 1.0);
+            ones += 1;
+        }
+    }
+    loop {
+        let cluster = tightest_cluster(&set, &energy);
+        set[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&set, &energy);
+        set[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut rank = vec![0; size];
+
+    //Ranks below the initial pattern come from removing its tightest clusters
+    let (mut set_down, mut energy_down) = (set.clone(), energy.clone());
+    for r in (0..ones).rev() {
+        let cluster = tightest_cluster(&set_down, &energy_down);
+        set_down[cluster] = false;
+        update(&mut energy_down, cluster, -1.0);
+        rank[cluster] = r;
+    }
+
+    //and the rest from filling the largest voids
+    for r in ones..size {
+        let void = largest_void(&set, &energy);
+        set[void] = true;
+        update(&mut energy, void, 1.0);
+        rank[void] = r;
+    }
+
+    rank.iter().map(|&r| (r as f64 + 0.5) / size as f64).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permutation_is_bijective() {
+        for &l in &[1, 7, 64, 100] {
+            let mut seen = vec![false; l as usize];
+            for i in 0..l {
+                let j = permutation_element(i, l, 0x1234567) as usize;
+                assert!(!seen[j]);
+                seen[j] = true;
+            }
+        }
+    }
+
+    #[test]
+    fn scrambled_sobol_is_stratified() {
+        //Any power of two prefix has one point in each elementary interval
+        let n = 64;
+        let mut cells = vec![0; n];
+        for i in 0..n as u32 {
+            let (x, y) = (sobol_owen(i, 0, 77), sobol_owen(i, 1, 1234));
+            cells[(x*8.0) as usize*8 + (y*8.0) as usize] += 1;
+        }
+        assert!(cells.iter().all(|&c| c == 1));
+    }
+}
@@ -13,9 +13,49 @@ mod triangle;
 mod model;
 mod aabb;
 mod bvh;
+mod onb;
+mod fresnel;
+mod microfacet;
+mod conductor;
+mod rough_dielectric;
+mod texture;
+mod principled;
+mod coated;
+mod mix;
+mod normal_map;
+mod cutout;
+mod medium;
+mod subsurface;
+mod thin_film;
+mod spectrum;
+mod blackbody;
+mod diffuse_light;
+mod light;
+mod scene;
+mod environment;
+mod sky;
+mod distribution;
+mod light_tree;
+mod area_light;
+mod ies;
+mod integrator;
+mod film;
+mod bdpt;
+mod sppm;
+mod render_settings;
+mod sampler;
+mod mlt;
+mod low_discrepancy;
+mod adaptive;
+mod checkpoint;
 
 use vec3::*;
 use ray::*;
+use scene::Scene;
+use render_settings::{RenderSettings, IntegratorKind, SceneKind};
+use material::Material;
+use light::Light;
+use texture::{solid, constant};
 
 use hittable_list::*;
 use hittable::*;
@@ -25,37 +65,10 @@ use sphere::*;
 use std::sync::Arc;
 use image::*;
 use std::time::{Instant};
-use rayon::prelude::*;
 use std::thread::{sleep, spawn};
 use std::time::Duration;
 use std::sync::atomic::{Ordering, AtomicU64};
 
-fn ray_color(r: &Ray, world: &dyn Hittable, depth: u32) -> Color3 {
-    if depth <= 0 {
-        return Vec3::from_f64(0.0, 0.0, 0.0);
-    }
-
-    let mut rec = HitRecord::new();
-    if world.hit(*r, 0.001, f64::INFINITY, &mut rec) {
-        //let target = rec.p + rec.normal + Vec3::random_unit_vector();
-        //let target = rec.p + Vec3::random_in_hemisphere(&rec.normal);
-
-        let mut attenuation = Vec3::new();
-        let mut scattered = Ray::new(&Vec3::new(), &Vec3::new());
-
-        let mat = rec.material.clone();
-        if mat.scatter(r, &rec, &mut attenuation, &mut scattered) {
-            return attenuation * ray_color(&scattered, world, depth-1);
-        }
-
-        return Vec3::from_f64(0.0, 0.0, 0.0);
-    }
-
-    let unit_dir = Vec3::unit(&r.direction());
-    let t = 0.5*(unit_dir.y() + 1.0);
-    return (1.0-t)*Color3::from_f64(1.0, 1.0, 1.0)+t*Color3::from_f64(0.5, 0.7, 1.0);
-}
-
 fn write_color(col: &Color3, samples_per_pixel: u32) -> Rgb<u8> {
     let scale = 1.0/(samples_per_pixel as f64);
 
@@ -69,7 +82,30 @@ fn write_color(col: &Color3, samples_per_pixel: u32) -> Rgb<u8> {
     )
 }
 
-fn random_world() -> HittableList {
+// Saves through a temporary file, so a render killed while writing still
+// leaves the previous image intact.
+fn save_image(cells: &[Color3], film: &film::SplatFilm, splat_scale: f64, width: u32, height: u32, path: &str) {
+    let mut img = RgbImage::new(width, height);
+    for i in 0..(height*width) {
+        let x = i % width;
+        let y = i / width;
+
+        let col = cells[i as usize] + film.get(i as usize) * splat_scale;
+        img.put_pixel(x, height-1-y, write_color(&col, 1));
+    }
+
+    let tmp = format!("{}.tmp", path);
+    if img.save_with_format(&tmp, ImageFormat::Png).is_ok() {
+        let _ = std::fs::rename(&tmp, path);
+    }
+}
+
+// Camera samples behind each splat, per pixel on average.
+fn splat_scale(pixels: &[adaptive::PixelEstimate]) -> f64 {
+    pixels.len() as f64 / pixels.iter().map(|px| px.samples as u64).sum::<u64>() as f64
+}
+
+fn random_world(dispersion: bool) -> HittableList {
     let mut world = HittableList::new();
 
     let mat_ground = Arc::new(lambertian::Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
@@ -104,7 +140,11 @@ fn random_world() -> HittableList {
         }
     }
 
-    let mat1 = Arc::new(dielectric::Dieletric::new(1.5));
+    let mat1 = if dispersion {
+        Arc::new(dielectric::Dieletric::new(1.5).with_dispersion(dielectric::Dispersion::bk7()))
+    } else {
+        Arc::new(dielectric::Dieletric::new(1.5))
+    };
     let mat2 = Arc::new(lambertian::Lambertian::new(Vec3::from_f64(0.4, 0.2, 0.1)));
     let mat3 = Arc::new(metal::Metal::new(Vec3::from_f64(0.7, 0.6, 0.5), 0.0));
 
@@ -115,19 +155,125 @@ fn random_world() -> HittableList {
     world
 }
 
+// A quad of two triangles from corner a along the edges ab and ad, facing
+// along ab x ad.
+fn quad(world: &mut HittableList, a: Point3, ab: Vec3, ad: Vec3, mat: Arc<dyn Material>) {
+    world.add(Arc::new(triangle::Triangle::new(a, a + ab, a + ab + ad, mat.clone())));
+    world.add(Arc::new(triangle::Triangle::new(a, a + ab + ad, a + ad, mat)));
+}
+
+// Two rows of spheres, metals and coatings in front and dielectrics and the
+// principled material behind, under an area light, a bulb, a spot and point
+// lights. With ies set, the spot gives way to the measured luminaire.
+fn showcase_world(settings: &RenderSettings) -> (HittableList, Vec<Arc<dyn Light>>) {
+    let mut world = HittableList::new();
+
+    let mat_ground = Arc::new(lambertian::Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
+    world.add(Arc::new(Sphere::new(Vec3::from_f64(0.0, -1000.0, 0.0), 1000.0, mat_ground)));
+
+    let checker = |scale: f64| -> Arc<dyn texture::Texture> { Arc::new(texture::Checker::new(constant(1.0), constant(0.0), scale)) };
+    let rust = Arc::new(lambertian::Lambertian::new(Vec3::from_f64(0.35, 0.12, 0.05)));
+
+    let front: Vec<Arc<dyn Material>> = vec![
+        Arc::new(conductor::Conductor::gold(0.15)),
+        //Oxide layer on copper
+        Arc::new(conductor::Conductor::copper(0.05).with_thin_film(thin_film::ThinFilm::new(2.0, 250.0))),
+        Arc::new(conductor::Conductor::aluminium(0.0).with_anisotropic_roughness(0.05, 0.4)),
+        Arc::new(cutout::Cutout::new(Arc::new(conductor::Conductor::silver(0.1)), checker(8.0))),
+        Arc::new(mix::MixMaterial::new(Arc::new(conductor::Conductor::copper(0.2)), rust, checker(6.0))),
+        Arc::new(coated::Coated::new(lambertian::Lambertian::new(Vec3::from_f64(0.6, 0.3, 0.1)), 1.5)
+            .with_roughness(0.05)
+            .with_absorption(Vec3::from_f64(0.1, 0.4, 0.8), 0.2))
+    ];
+
+    let principled = principled::Principled::new(solid(Vec3::from_f64(0.1, 0.2, 0.6)))
+        .with_metallic(constant(0.0))
+        .with_roughness(constant(0.5))
+        .with_specular(constant(0.5))
+        .with_clearcoat(constant(1.0), constant(0.03))
+        .with_sheen(solid(Vec3::from_f64(0.3, 0.3, 0.3)));
+    let back: Vec<Arc<dyn Material>> = vec![
+        Arc::new(normal_map::BumpMap::new(Arc::new(principled), checker(10.0), 0.002)),
+        Arc::new(principled::Principled::new(solid(Vec3::from_f64(1.0, 1.0, 1.0)))
+            .with_metallic(constant(0.0))
+            .with_roughness(constant(0.05))
+            .with_transmission(constant(1.0))
+            .with_ior(1.45)),
+        Arc::new(principled::Principled::new(solid(Vec3::from_f64(0.8, 0.6, 0.5)))
+            .with_metallic(constant(0.0))
+            .with_subsurface(constant(1.0), solid(Vec3::from_f64(0.9, 0.4, 0.3)))),
+        //Forward scattering wax
+        Arc::new(subsurface::Subsurface::new(1.4, Vec3::from_f64(0.9, 0.8, 0.6), Vec3::from_f64(0.5, 0.3, 0.1))
+            .with_roughness(0.2)
+            .with_medium(medium::Medium::from_color(Vec3::from_f64(0.9, 0.8, 0.6), Vec3::from_f64(0.5, 0.3, 0.1), 0.6))),
+        //Frosted glass with a soap film thinning in bands
+        Arc::new(rough_dielectric::RoughDielectric::anisotropic(1.5, 0.02, 0.2)
+            .with_thin_film(thin_film::ThinFilm::textured(1.33, checker(4.0), 600.0))),
+        Arc::new(dielectric::Dieletric::new(2.42).with_dispersion(dielectric::Dispersion::diamond())),
+        //Dense flint glass
+        Arc::new(dielectric::Dieletric::new(1.75).with_dispersion(dielectric::Dispersion::Cauchy { a: 1.728, b: 0.01342 })),
+        Arc::new(principled::Principled::new(solid(Vec3::new()))
+            .with_blackbody_emission(blackbody::Blackbody::new(1900.0, 20000.0)))
+    ];
+
+    for (row, z) in [(&front, 0.0), (&back, -1.5)] {
+        let first = -1.25 * (row.len() - 1) as f64 / 2.0;
+        for (i, mat) in row.iter().enumerate() {
+            world.add(Arc::new(Sphere::new(Vec3::from_f64(first + 1.25*i as f64, 0.5, z), 0.5, mat.clone())));
+        }
+    }
+
+    //A 5000K panel overhead and a 60W incandescent bulb's worth of light
+    //coming from a small card to the side
+    let panel = blackbody::Blackbody::from_lumens(5000.0, 2.0e6, 4.0);
+    quad(&mut world, Vec3::from_f64(-1.0, 5.0, -1.75), Vec3::from_f64(2.0, 0.0, 0.0), Vec3::from_f64(0.0, 0.0, 2.0), Arc::new(diffuse_light::DiffuseLight::blackbody(panel)));
+    let bulb = blackbody::Blackbody::from_watts(2700.0, 60.0, 15.0, 0.01);
+    quad(&mut world, Vec3::from_f64(5.0, 1.5, 1.0), Vec3::from_f64(0.0, 0.1, 0.0), Vec3::from_f64(0.0, 0.0, 0.1), Arc::new(diffuse_light::DiffuseLight::blackbody(bulb).with_two_sided(true)));
+    //A red strip along the back
+    let strip = Arc::new(diffuse_light::DiffuseLight::new(solid(Vec3::from_f64(8.0, 0.5, 0.3))));
+    quad(&mut world, Vec3::from_f64(-4.0, 0.05, -3.0), Vec3::from_f64(8.0, 0.0, 0.0), Vec3::from_f64(0.0, 0.1, 0.0), strip);
+
+    let key: Arc<dyn Light> = match &settings.ies {
+        Some(path) => Arc::new(ies::IesLight::photometric(Vec3::from_f64(-3.0, 5.0, 1.0), Arc::new(ies::IesProfile::load(path)), 3000.0)),
+        None => Arc::new(light::SpotLight::from_lumens(Vec3::from_f64(-5.0, 6.0, 5.0), Vec3::from_f64(3.0, -5.5, -5.0), 3200.0, 1.5e6, 10.0, 20.0))
+    };
+    let lights: Vec<Arc<dyn Light>> = vec![
+        key,
+        Arc::new(light::PointLight::from_lumens(Vec3::from_f64(4.0, 2.5, 3.0), 2700.0, 3.0e6)),
+        //Cool fill from behind the camera
+        Arc::new(light::PointLight::new(Vec3::from_f64(-6.0, 3.0, 8.0), Vec3::from_f64(15.0, 18.0, 30.0)))
+    ];
+
+    (world, lights)
+}
+
 static PROGRESS: AtomicU64 = AtomicU64::new(0);
 
 fn main() {
     const ASPECT_RATIO : f64 = 16.0/9.0;
     const IMAGE_WIDTH : u32 = 1920;
     const IMAGE_HEIGHT : u32 = (IMAGE_WIDTH as f64 / ASPECT_RATIO) as u32;
-    let samples_per_pixel = 50;
-    let max_ray_depth = 50;
+    let args: Vec<String> = std::env::args().collect();
+    let spectral = args.iter().any(|a| a == "--spectral");
+    let mut settings = match args.iter().position(|a| a == "--settings").and_then(|i| args.get(i + 1)) {
+        Some(path) => RenderSettings::load(path),
+        None => RenderSettings::new()
+    };
+    if args.iter().any(|a| a == "--bdpt") {
+        settings.integrator = IntegratorKind::Bdpt;
+    }
+    sampler::set_seed(settings.seed);
+    let samples_per_pixel = settings.samples;
+    let integrator = integrator::PathIntegrator::new()
+        .with_limits(settings.path_max_diffuse, settings.path_max_specular, settings.path_max_transmission, settings.path_max_volume)
+        .with_rr_depth(settings.path_rr_depth);
 
     let metal_mat = Arc::new(metal::Metal::new(Vec3::from_f64(59.0/255.0,102.0/255.0,57.0/255.0), 0.0));
 
-    let mut world = random_world();
-    //let mut world = HittableList::new();
+    let (world, lights) = match settings.scene {
+        SceneKind::Random => {
+            let mut world = random_world(settings.dispersion);
+            //let mut world = HittableList::new();
 
     /*let v0 = Vec3::from_f64(-0.5, 0.0, 1.0);
     let v1 = Vec3::from_f64(-0.5, 1.0, 1.0);
@@ -135,75 +281,182 @@ fn main() {
     let mat_tri = Arc::new(lambertian::Lambertian::new(Vec3::from_f64(1.0, 0.0, 0.0)));
     world.add(Arc::new(triangle::Triangle::new(v0, v1, v2, mat_tri.clone())));*/
 
-    let m = model::Model::new("cube2.obj", metal_mat.clone());
-    world.add(Arc::new(m));
-
-    let bv = bvh::BVH::new(world);
-
-    let mut img = RgbImage::new(IMAGE_WIDTH, IMAGE_HEIGHT);
+            let m = model::Model::new("cube2.obj", metal_mat.clone());
+            world.add(Arc::new(m));
+            (world, Vec::new())
+        },
+        SceneKind::Showcase => showcase_world(&settings)
+    };
+
+    let mut scene = Scene::new(bvh::BVH::new(world));
+    scene.add_lights(lights);
+    let env = args.iter().position(|a| a == "--env").and_then(|i| args.get(i + 1));
+    match env {
+        Some(path) => scene.set_environment(Arc::new(environment::EnvironmentMap::new(path)
+            .with_rotation(settings.environment_rotation)
+            .with_intensity(settings.environment_intensity))),
+        None => scene.set_sky(sky::PhysicalSky::new(35.0, 60.0, 3.0, Color3::from_f64(0.3, 0.3, 0.3)).with_intensity(settings.environment_intensity))
+    }
 
-    let lookfrom = Vec3::from_f64(-13.0, 3.0, 3.0);
-    let lookat = Vec3::from_f64(0.0, 0.0, 0.0);
-    let dist_to_focus = 10.0;
+    let (lookfrom, lookat, vfov, dist_to_focus) = match settings.scene {
+        SceneKind::Random => (Vec3::from_f64(-13.0, 3.0, 3.0), Vec3::from_f64(0.0, 0.0, 0.0), 20.0, 10.0),
+        SceneKind::Showcase => (Vec3::from_f64(0.0, 3.0, 11.0), Vec3::from_f64(0.0, 0.5, -0.75), 30.0, 12.0)
+    };
     let cam = Camera::new(lookfrom,
                             lookat,
                             Vec3::from_f64(0.0, 1.0, 0.0),
                             ASPECT_RATIO,
-                            20.0,
+                            vfov,
                             0.1,
                             dist_to_focus);
 
+    //Light paths connected to the camera land on arbitrary pixels
+    let film = film::SplatFilm::new(IMAGE_WIDTH, IMAGE_HEIGHT);
+    let bdpt = if settings.integrator == IntegratorKind::Bdpt { Some(bdpt::BdptIntegrator::new(&scene, settings.max_depth)) } else { None };
+
     let start = Instant::now();
     let mut cells = vec![Vec3::new(); (IMAGE_HEIGHT * IMAGE_WIDTH) as usize];
 
+    //Progress is counted in samples
+    let budget = (IMAGE_HEIGHT * IMAGE_WIDTH) as u64 * samples_per_pixel as u64;
     spawn(move || {
         let start = Instant::now();
         loop {
             let pix = PROGRESS.load(Ordering::Relaxed);
-            let pix = pix as f64 / (IMAGE_HEIGHT * IMAGE_WIDTH) as f64;
+            let pix = pix as f64 / budget as f64;
             let pix = pix * 100.0;
             let dur = Instant::now() - start;
             println!("Current progress: {:.2}%, {:.2} seconds elapsed", pix, dur.as_secs());
-            let _ = sleep(Duration::from_secs(1));
+            sleep(Duration::from_secs(1));
         }
     });
 
-    cells.par_iter_mut().enumerate().for_each(|(i, col_out)| {
-        let mut pixel_col = Vec3::new();
-        let x = (i as u32) % IMAGE_WIDTH;
-        let y = (i as u32) / IMAGE_WIDTH;
-
-        let x = x as f64;
-        let y = y as f64;
-
-        for _ in 0..samples_per_pixel {
-
-            let dx = util::random_double();//*2.0 - 1.0;
-            let dy = util::random_double();//*2.0 - 1.0;
-
-            let u = (x + dx) / (IMAGE_WIDTH-1) as f64;
-            let v = (y + dy) / (IMAGE_HEIGHT-1) as f64;
-
-            let r = cam.get_ray(u, v);
-            let col = ray_color(&r, &bv, max_ray_depth);
-            pixel_col += col;
+    //Splats are summed over every camera sample of the image
+    let mut splat = 1.0;
+    if settings.integrator == IntegratorKind::Sppm {
+        let sppm = sppm::SppmIntegrator::new(&scene, settings.sppm_photons, settings.sppm_radius)
+            .with_max_depth(settings.max_depth)
+            .with_iterations(settings.sppm_iterations)
+            .with_time_limit(settings.sppm_time);
+
+        cells = sppm.render(&scene, &cam, IMAGE_WIDTH, IMAGE_HEIGHT, |i| {
+            PROGRESS.store(i as u64 * budget / settings.sppm_iterations as u64, Ordering::Relaxed);
+        });
+    } else if settings.integrator == IntegratorKind::Mlt {
+        let mlt = mlt::MltIntegrator::new(integrator, settings.mlt_mutations)
+            .with_chains(settings.mlt_chains, settings.mlt_bootstrap)
+            .with_mutation(settings.mlt_sigma, settings.mlt_large_step)
+            .with_spectral(spectral);
+
+        cells = mlt.render(&scene, &cam, IMAGE_WIDTH, IMAGE_HEIGHT);
+    } else {
+        let mut adaptive = adaptive::AdaptiveSampling::new(settings.sampler, samples_per_pixel)
+            .with_pass(settings.pass)
+            .with_time_limit(settings.time);
+        if let Some(threshold) = settings.adaptive_threshold {
+            adaptive = adaptive.with_threshold(threshold, settings.adaptive_max_samples);
+        }
+        let mut last_write = Instant::now();
+
+        let key = checkpoint::CheckpointSettings {
+            scene: settings.scene,
+            width: IMAGE_WIDTH,
+            height: IMAGE_HEIGHT,
+            seed: settings.seed,
+            integrator: settings.integrator,
+            sampler: adaptive.sampler,
+            samples_per_pixel: adaptive.samples_per_pixel,
+            pass: adaptive.pass,
+            threshold: adaptive.threshold,
+            max_samples: adaptive.max_samples,
+            max_depth: settings.max_depth,
+            path_limits: [integrator.max_diffuse, integrator.max_specular, integrator.max_transmission, integrator.max_volume],
+            rr_depth: integrator.rr_depth,
+            spectral,
+            dispersion: settings.dispersion,
+            environment: env.cloned(),
+            environment_rotation: settings.environment_rotation,
+            environment_intensity: settings.environment_intensity,
+            ies: settings.ies.clone()
+        };
+
+        let mut restored = None;
+        let mut elapsed = Duration::ZERO;
+        if args.iter().any(|a| a == "--resume") {
+            let path = settings.checkpoint.as_deref().expect("--resume needs a checkpoint path in the render settings.");
+            let ckpt = checkpoint::Checkpoint::load(path, &key).unwrap_or_else(|e| panic!("Checkpoint {} can't be resumed: {}", path, e));
+
+            film.restore(&ckpt.splats);
+            PROGRESS.store(ckpt.pixels.iter().map(|px| px.samples as u64).sum(), Ordering::Relaxed);
+            restored = Some(ckpt.pixels);
+            elapsed = ckpt.elapsed;
         }
+        let resumed = Instant::now();
+        let mut last_checkpoint = Instant::now();
+
+        //Everything a sample draws comes from the pixel's sampler
+        let sample = |x: u32, y: u32| {
+            let (dx, dy) = util::random_2d();
+
+            let u = (x as f64 + dx) / (IMAGE_WIDTH-1) as f64;
+            let v = (y as f64 + dy) / (IMAGE_HEIGHT-1) as f64;
+
+            let lambdas = if spectral { Some(spectrum::sample_wavelengths(util::random_double())) } else { None };
+            let r = cam.get_ray(u, v).with_wavelengths(lambdas);
+
+            let col = match &bdpt {
+                Some(bdpt) => bdpt.li(&r, &scene, &cam, &film),
+                None => integrator.li(&r, &scene)
+            };
+            match lambdas {
+                Some(l) => spectrum::to_rgb(&col, &l),
+                None => col
+            }
+        };
+        let on_samples = |n: u32| { PROGRESS.fetch_add(n as u64, Ordering::Relaxed); };
+        let on_pass = |pixels: &[adaptive::PixelEstimate]| {
+            //The image so far, in case the render doesn't get to finish
+            if settings.write_interval.is_some_and(|interval| last_write.elapsed() >= interval) {
+                let cells: Vec<Color3> = pixels.iter().map(|px| px.color()).collect();
+                save_image(&cells, &film, splat_scale(pixels), IMAGE_WIDTH, IMAGE_HEIGHT, "test.png");
+                last_write = Instant::now();
+            }
 
-        *col_out = pixel_col;
-        PROGRESS.fetch_add(1, Ordering::Relaxed);
-    });
+            if let Some(path) = settings.checkpoint.as_deref().filter(|_| last_checkpoint.elapsed() >= settings.checkpoint_interval) {
+                let ckpt = checkpoint::Checkpoint {
+                    settings: key.clone(),
+                    elapsed: elapsed + resumed.elapsed(),
+                    pixels: pixels.to_vec(),
+                    splats: film.raw()
+                };
+                if let Err(e) = ckpt.save(path) {
+                    println!("Checkpoint save failed: {}", e);
+                }
+                last_checkpoint = Instant::now();
+            }
+        };
+        let pixels = match restored {
+            Some(pixels) => adaptive.resume(pixels, elapsed, IMAGE_WIDTH, sample, on_samples, on_pass),
+            None => adaptive.render(IMAGE_WIDTH, IMAGE_HEIGHT, sample, on_samples, on_pass)
+        };
+
+        splat = splat_scale(&pixels);
+        cells = pixels.iter().map(|px| px.color()).collect();
+
+        if let Some(path) = &settings.adaptive_spp_image {
+            let max = pixels.iter().map(|px| px.samples).max().unwrap_or(1);
+            let spp_img = RgbImage::from_fn(IMAGE_WIDTH, IMAGE_HEIGHT, |x, y| {
+                let v = (pixels[((IMAGE_HEIGHT-1-y)*IMAGE_WIDTH + x) as usize].samples as f64 / max as f64 * 255.0) as u8;
+                Rgb([v, v, v])
+            });
+            let _ = spp_img.save(path);
+        }
+    }
 
-    println!("");
+    println!();
     println!("Writing image...");
 
-    for i in 0..(IMAGE_HEIGHT*IMAGE_WIDTH) {
-        let x = (i as u32) % IMAGE_WIDTH;
-        let y = (i as u32) / IMAGE_WIDTH;
-
-        img.put_pixel(x, IMAGE_HEIGHT-1-y, write_color(&cells[i as usize], samples_per_pixel));
-    }
-
-    let _ = img.save("test.png");
+    save_image(&cells, &film, splat, IMAGE_WIDTH, IMAGE_HEIGHT, "test.png");
     println!("Done.");
 
     let end = Instant::now();
@@ -1,7 +1,141 @@
 use crate::ray::*;
 use crate::hittable::*;
 use crate::vec3::*;
+use crate::medium::Medium;
+use crate::spectrum;
 
-pub trait Material {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool;
+// Kind of lobe a scatter() sample was drawn from. Specular lobes are the delta
+// ones that eval() and pdf() leave out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Lobe {
+    // Diffuse or glossy
+    Reflection,
+    Transmission,
+    SpecularReflection,
+    SpecularTransmission
+}
+
+impl Lobe {
+    pub fn is_specular(self) -> bool {
+        matches!(self, Lobe::SpecularReflection | Lobe::SpecularTransmission)
+    }
+
+    pub fn is_transmission(self) -> bool {
+        matches!(self, Lobe::Transmission | Lobe::SpecularTransmission)
+    }
+}
+
+pub trait Material: Send + Sync {
+    // Samples the direction the path continues in and the lobe it came from,
+    // or None when the path ends here.
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe>;
+
+    fn emitted(&self, _rec: &HitRecord) -> Color3 {
+        Vec3::new()
+    }
+
+    // Emission at the given wavelengths, for spectral mode. Emitters with a
+    // physical spectrum override this instead of going through RGB upsampling.
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        spectrum::upsample(&self.emitted(rec), lambdas)
+    }
+
+    // Whether emitted() can be non-zero, so geometry using this material is
+    // registered as a light for direct sampling.
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    // Medium filling the inside of closed geometry with this material.
+    fn interior(&self) -> Option<Medium> {
+        None
+    }
+
+    // Whether scatter() and eval() already return values at rec.wavelengths in
+    // spectral mode. Everything else returns RGB for the integrator to upsample.
+    fn is_spectral(&self) -> bool {
+        false
+    }
+
+    // Coverage in [0, 1] at a candidate hit, checked during traversal before
+    // the hit record is filled in; see hittable::accept_hit.
+    fn opacity(&self, _u: f64, _v: f64, _p: &Point3) -> f64 {
+        1.0
+    }
+
+    // Whether opacity() can be below one anywhere. Primitives skip the any-hit
+    // test and the texture coordinates it needs for materials without a cutout.
+    fn has_cutout(&self) -> bool {
+        false
+    }
+
+    // BSDF times |cos| of wi against the shading normal, for light arriving along
+    // wi and leaving along wo. Both directions point away from the surface.
+    // Materials that only have perfectly specular lobes leave this at zero.
+    fn eval(&self, _wo: &Vec3, _wi: &Vec3, _rec: &HitRecord) -> Color3 {
+        Vec3::new()
+    }
+
+    // Solid angle density with which scatter() picks wi when leaving along wo.
+    // The attenuation returned by scatter() equals eval() / pdf() for the same pair.
+    fn pdf(&self, _wo: &Vec3, _wi: &Vec3, _rec: &HitRecord) -> f64 {
+        0.0
+    }
+}
+
+// An RGB value in the channels of the ray that made rec, for materials that
+// combine spectral results with RGB factors of their own.
+pub fn rec_channels(c: Color3, rec: &HitRecord) -> Color3 {
+    match rec.wavelengths {
+        Some(l) => spectrum::upsample(&c, &l),
+        None => c
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Hit on the xy plane with the normal along +z, seen from either side.
+    pub fn flat_hit(front_face: bool) -> HitRecord {
+        let mut rec = HitRecord::new();
+        rec.normal = Vec3::from_f64(0.0, 0.0, 1.0);
+        rec.geo_normal = rec.normal;
+        rec.tangent = Vec3::from_f64(1.0, 0.0, 0.0);
+        rec.front_face = front_face;
+        rec
+    }
+
+    // Every direction scatter() samples has to be reproducible through eval()
+    // and pdf(). Each setup is a hit and the direction of the incoming ray.
+    // Returns how many of the samples went through the surface.
+    pub fn check_sample_matches_eval_over_pdf(mat: &dyn Material, setups: &[(HitRecord, Vec3)], samples: usize) -> usize {
+        let mut transmitted = 0;
+
+        for (rec, dir) in setups {
+            let ray = Ray::new(&-*dir, dir);
+            let wo = -Vec3::unit(dir);
+
+            for _ in 0..samples {
+                let mut attenuation = Vec3::new();
+                let mut scattered = Ray::new(&Vec3::new(), &Vec3::new());
+                if mat.scatter(&ray, rec, &mut attenuation, &mut scattered).is_none() {
+                    continue;
+                }
+
+                let wi = scattered.direction();
+                let pdf = mat.pdf(&wo, &wi, rec);
+                assert!(pdf > 0.0);
+
+                let expected = mat.eval(&wo, &wi, rec) / pdf;
+                assert!((expected - attenuation).length() < 1e-6 * (1.0 + attenuation.length()));
+
+                if Vec3::dot(&wi, &rec.normal) < 0.0 {
+                    transmitted += 1;
+                }
+            }
+        }
+
+        transmitted
+    }
 }
@@ -0,0 +1,144 @@
+use crate::*;
+use vec3::*;
+use onb::ONB;
+use std::f64::consts::PI;
+
+// Homogeneous participating medium filling the inside of a closed surface.
+// Coefficients are per scene unit and per channel.
+#[derive(Copy, Clone)]
+pub struct Medium {
+    sigma_s: Color3,
+    sigma_t: Color3,
+    g: f64
+}
+
+// Result of tracking a ray through the medium up to the next surface.
+pub struct MediumSample {
+    pub scattered: bool,
+    pub distance: f64,
+    pub weight: Color3
+}
+
+fn exp3(v: Color3) -> Color3 {
+    Vec3::from_f64(v.x().exp(), v.y().exp(), v.z().exp())
+}
+
+impl Medium {
+    pub fn new(sigma_s: Color3, sigma_a: Color3, g: f64) -> Self {
+        Self {
+            sigma_s,
+            sigma_t: sigma_s + sigma_a,
+            g
+        }
+    }
+
+    // Maps a multiple-scattering surface color and mean free path to medium
+    // coefficients, following Chiang et al. 2016.
+    pub fn from_color(color: Color3, mean_free_path: Color3, g: f64) -> Self {
+        let mut sigma_s = Vec3::new();
+        let mut sigma_a = Vec3::new();
+
+        for c in 0..3 {
+            let a = color[c].clamp(0.0, 0.999);
+            let alpha = 1.0 - (4.09712 + 4.20863*a - (9.59217 + 41.6808*a + 17.7126*a*a).sqrt()).powi(2);
+            let s = 1.9 - a + 3.5*(a - 0.8)*(a - 0.8);
+            let sigma_t = 1.0 / (mean_free_path[c].max(1e-6) * s);
+
+            sigma_s[c] = alpha * sigma_t;
+            sigma_a[c] = (1.0 - alpha) * sigma_t;
+        }
+
+        Medium::new(sigma_s, sigma_a, g)
+    }
+
+    pub fn transmittance(&self, distance: f64) -> Color3 {
+        exp3(-distance * self.sigma_t)
+    }
+
+    // Samples a free-flight distance along a unit-speed ray that would reach the
+    // next surface after t_max. A channel is picked uniformly and the weight uses
+    // the average density over all three, so chromatic media stay unbiased.
+    pub fn sample(&self, t_max: f64) -> MediumSample {
+        let c = usize::min((util::random_double() * 3.0) as usize, 2);
+        let distance = -(1.0 - util::random_double()).ln() / self.sigma_t[c];
+
+        if distance < t_max {
+            let tr = self.transmittance(distance);
+            let pdf = (self.sigma_t.x()*tr.x() + self.sigma_t.y()*tr.y() + self.sigma_t.z()*tr.z()) / 3.0;
+
+            return MediumSample {
+                scattered: true,
+                distance,
+                weight: self.sigma_s * tr / pdf
+            };
+        }
+
+        let tr = self.transmittance(t_max);
+        let pdf = (tr.x() + tr.y() + tr.z()) / 3.0;
+
+        MediumSample {
+            scattered: false,
+            distance: t_max,
+            weight: tr / pdf
+        }
+    }
+
+    pub fn sample_phase(&self, dir: &Vec3) -> Vec3 {
+        let (u1, u2) = util::random_2d();
+
+        let cos = if self.g.abs() < 1e-3 {
+            1.0 - 2.0*u1
+        } else {
+            let sq = (1.0 - self.g*self.g) / (1.0 - self.g + 2.0*self.g*u1);
+            (1.0 + self.g*self.g - sq*sq) / (2.0*self.g)
+        };
+
+        let sin = (1.0 - cos*cos).max(0.0).sqrt();
+        let phi = 2.0*PI*u2;
+
+        ONB::from_w(dir).local_to_world(&Vec3::from_f64(sin*phi.cos(), sin*phi.sin(), cos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fraction of diffuse light entering a semi-infinite slab that leaves it
+    // again, estimated with random walks through the medium.
+    fn slab_albedo(medium: &Medium, walks: usize) -> Color3 {
+        let mut total = Vec3::new();
+
+        for _ in 0..walks {
+            let mut z = 0.0;
+            let mut dir = -util::random_cosine_direction();
+            let mut throughput = Vec3::from_f64(1.0, 1.0, 1.0);
+
+            for _ in 0..10000 {
+                let t_max = if dir.z() > 0.0 { -z / dir.z() } else { f64::INFINITY };
+                let sample = medium.sample(t_max);
+                throughput *= sample.weight;
+
+                if !sample.scattered {
+                    total += throughput;
+                    break;
+                }
+
+                z += sample.distance * dir.z();
+                dir = medium.sample_phase(&dir);
+            }
+        }
+
+        total / walks as f64
+    }
+
+    #[test]
+    fn from_color_round_trips_to_the_multiple_scattering_albedo() {
+        let color = Vec3::from_f64(0.2, 0.5, 0.8);
+        let albedo = slab_albedo(&Medium::from_color(color, Vec3::from_f64(0.5, 1.0, 2.0), 0.0), 20000);
+
+        for c in 0..3 {
+            assert!((albedo[c] - color[c]).abs() < 0.02, "{:?} vs {:?}", albedo, color);
+        }
+    }
+}
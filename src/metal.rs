@@ -1,7 +1,7 @@
 use crate::*;
 use vec3::*;
 use ray::*;
-use material::Material;
+use material::{Material, Lobe};
 
 pub struct Metal {
     albedo: Vec3,
@@ -9,13 +9,14 @@ pub struct Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
         let dir = Vec3::reflect(&Vec3::unit(&ray.direction()), &rec.normal);
 
         *scattered = Ray::new(&rec.p, &(dir + self.fuzz*Vec3::random_unit_sphere()));
         *attenuation = self.albedo;
 
-        Vec3::dot(&scattered.direction(), &rec.normal) > 0.0
+        //eval() and pdf() don't cover the fuzz, so it counts as a delta lobe
+        if Vec3::dot(&scattered.direction(), &rec.normal) > 0.0 { Some(Lobe::SpecularReflection) } else { None }
     }
 }
 
@@ -24,7 +25,7 @@ impl Metal {
     pub fn new(vec: Vec3, fuzz: f64) -> Self {
         Self {
             albedo: vec,
-            fuzz: fuzz
+            fuzz
         }
     }
 }
@@ -0,0 +1,173 @@
+use crate::vec3::*;
+use std::f64::consts::PI;
+
+// Trowbridge-Reitz (GGX) microfacet distribution. All directions are in the
+// local shading frame, where the macro-surface normal is +z and the x/y axes
+// carry the two anisotropic roughness values.
+#[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct GGX {
+    alpha_x: f64,
+    alpha_y: f64
+}
+
+const MIN_ALPHA: f64 = 1e-4;
+
+impl GGX {
+    pub fn new(alpha_x: f64, alpha_y: f64) -> Self {
+        Self {
+            alpha_x: f64::max(alpha_x, MIN_ALPHA),
+            alpha_y: f64::max(alpha_y, MIN_ALPHA)
+        }
+    }
+
+    // Perceptual roughness in [0, 1] is squared to get alpha, as in the Disney and glTF models.
+    pub fn from_roughness(roughness_x: f64, roughness_y: f64) -> Self {
+        GGX::new(roughness_x*roughness_x, roughness_y*roughness_y)
+    }
+
+    pub fn isotropic(roughness: f64) -> Self {
+        GGX::from_roughness(roughness, roughness)
+    }
+
+    pub fn d(&self, wh: &Vec3) -> f64 {
+        if wh.z() <= 0.0 {
+            return 0.0;
+        }
+
+        let x = wh.x() / self.alpha_x;
+        let y = wh.y() / self.alpha_y;
+        let e = x*x + y*y + wh.z()*wh.z();
+
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    pub fn lambda(&self, w: &Vec3) -> f64 {
+        let cos2 = w.z()*w.z();
+        if cos2 == 0.0 {
+            return f64::INFINITY;
+        }
+
+        let ax = self.alpha_x * w.x();
+        let ay = self.alpha_y * w.y();
+        let a2_tan2 = (ax*ax + ay*ay) / cos2;
+
+        0.5*(-1.0 + (1.0 + a2_tan2).sqrt())
+    }
+
+    pub fn g1(&self, w: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height-correlated Smith masking-shadowing.
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Samples a microfacet normal from the distribution of normals visible from wo
+    // (Heitz 2018). The returned normal is always in the upper hemisphere.
+    pub fn sample_wh(&self, wo: &Vec3, u1: f64, u2: f64) -> Vec3 {
+        let wo = if wo.z() < 0.0 { -*wo } else { *wo };
+
+        let vh = Vec3::unit(&Vec3::from_f64(self.alpha_x*wo.x(), self.alpha_y*wo.y(), wo.z()));
+
+        let len2 = vh.x()*vh.x() + vh.y()*vh.y();
+        let t1 = if len2 > 0.0 {
+            Vec3::from_f64(-vh.y(), vh.x(), 0.0) / len2.sqrt()
+        } else {
+            Vec3::from_f64(1.0, 0.0, 0.0)
+        };
+        let t2 = Vec3::cross(&vh, &t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0*PI*u2;
+        let p1 = r*phi.cos();
+        let p2 = r*phi.sin();
+        let s = 0.5*(1.0 + vh.z());
+        let p2 = (1.0 - s)*(1.0 - p1*p1).sqrt() + s*p2;
+
+        let nh = p1*t1 + p2*t2 + (1.0 - p1*p1 - p2*p2).max(0.0).sqrt()*vh;
+
+        Vec3::unit(&Vec3::from_f64(self.alpha_x*nh.x(), self.alpha_y*nh.y(), nh.z().max(1e-6)))
+    }
+
+    // Density of sample_wh returning wh, with respect to solid angle of wh.
+    pub fn pdf(&self, wo: &Vec3, wh: &Vec3) -> f64 {
+        if wo.z() == 0.0 {
+            return 0.0;
+        }
+
+        self.g1(wo) * Vec3::dot(wo, wh).abs() * self.d(wh) / wo.z().abs()
+    }
+}
+
+// Refracts wo through a microfacet with normal wh. eta is the index on the
+// transmitted side over the index on the side of wo.
+pub fn refract(wo: &Vec3, wh: &Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = Vec3::dot(wo, wh);
+    let sin2_t = (1.0 - cos_i*cos_i).max(0.0) / (eta*eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-*wo / eta + (cos_i / eta - cos_t) * *wh)
+}
+
+// Generalized half vector for a reflected (wi.z > 0) or transmitted pair, oriented
+// into the upper hemisphere, or None if no microfacet can produce the pair.
+pub fn half_vector(wo: &Vec3, wi: &Vec3, eta: f64) -> Option<Vec3> {
+    let reflect = wi.z() > 0.0;
+    let wh = if reflect { *wo + *wi } else { *wo + eta * *wi };
+    if wh.near_zero() {
+        return None;
+    }
+
+    let wh = Vec3::unit(&wh);
+    let wh = if wh.z() < 0.0 { -wh } else { wh };
+
+    // Discard back-facing microfacets.
+    if Vec3::dot(&wh, wo) <= 0.0 || Vec3::dot(&wh, wi) * wi.z() <= 0.0 {
+        return None;
+    }
+
+    Some(wh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    // The projected area of the microfacets must equal that of the macro surface.
+    #[test]
+    fn d_is_normalized() {
+        let ggx = GGX::new(0.3, 0.6);
+        let n = 200000;
+        let mut sum = 0.0;
+
+        for _ in 0..n {
+            let z = util::random_double();
+            let phi = 2.0*PI*util::random_double();
+            let r = (1.0 - z*z).sqrt();
+            let wh = Vec3::from_f64(r*phi.cos(), r*phi.sin(), z);
+
+            sum += ggx.d(&wh) * wh.z() * 2.0*PI;
+        }
+
+        assert!((sum / n as f64 - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn visible_normals_face_wo() {
+        let ggx = GGX::from_roughness(0.8, 0.2);
+        let wo = Vec3::unit(&Vec3::from_f64(0.7, -0.2, 0.3));
+
+        for _ in 0..1000 {
+            let wh = ggx.sample_wh(&wo, util::random_double(), util::random_double());
+            assert!(wh.z() > 0.0);
+            assert!(Vec3::dot(&wo, &wh) > -1e-9);
+            assert!(ggx.pdf(&wo, &wh) >= 0.0);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use medium::Medium;
+use texture::Texture;
+use std::sync::Arc;
+
+// Picks one of two materials per hit, choosing `b` with probability equal to the
+// weight texture at the hit's UV/position. Masks such as rust or decals are
+// painted into the weight instead of being modelled as separate geometry.
+pub struct MixMaterial {
+    a: Arc<dyn Material>,
+    b: Arc<dyn Material>,
+    weight: Arc<dyn Texture>
+}
+
+impl MixMaterial {
+    pub fn new(a: Arc<dyn Material>, b: Arc<dyn Material>, weight: Arc<dyn Texture>) -> Self {
+        Self {
+            a,
+            b,
+            weight
+        }
+    }
+
+    fn weight(&self, rec: &HitRecord) -> f64 {
+        self.weight_at(rec.u, rec.v, &rec.p)
+    }
+
+    fn weight_at(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        self.weight.value(u, v, p).x().clamp(0.0, 1.0)
+    }
+
+    // A component's scatter or eval result in the channels the mix reports in.
+    fn channels(&self, m: &Arc<dyn Material>, c: Color3, rec: &HitRecord) -> Color3 {
+        if self.is_spectral() && !m.is_spectral() { material::rec_channels(c, rec) } else { c }
+    }
+}
+
+impl Material for MixMaterial {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        let chosen = if util::random_double() < self.weight(rec) { &self.b } else { &self.a };
+        let lobe = chosen.scatter(ray, rec, attenuation, scattered)?;
+
+        //A delta lobe keeps its own weight, since picking it with the mix weight
+        //cancels that weight. Anything else could have come from either material.
+        let wo = -Vec3::unit(&ray.direction());
+        let wi = scattered.direction();
+        if lobe.is_specular() {
+            *attenuation = self.channels(chosen, *attenuation, rec);
+            return Some(lobe);
+        }
+
+        let pdf = self.pdf(&wo, &wi, rec);
+        if pdf <= 0.0 {
+            return None;
+        }
+        *attenuation = self.eval(&wo, &wi, rec) / pdf;
+        Some(lobe)
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        let w = self.weight(rec);
+        (1.0 - w) * self.channels(&self.a, self.a.eval(wo, wi, rec), rec) + w * self.channels(&self.b, self.b.eval(wo, wi, rec), rec)
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        let w = self.weight(rec);
+        (1.0 - w) * self.a.pdf(wo, wi, rec) + w * self.b.pdf(wo, wi, rec)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color3 {
+        let w = self.weight(rec);
+        (1.0 - w) * self.a.emitted(rec) + w * self.b.emitted(rec)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.a.is_emissive() || self.b.is_emissive()
+    }
+
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        let w = self.weight(rec);
+        (1.0 - w) * self.a.emitted_spectral(rec, lambdas) + w * self.b.emitted_spectral(rec, lambdas)
+    }
+
+    fn opacity(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        let w = self.weight_at(u, v, p);
+        (1.0 - w) * self.a.opacity(u, v, p) + w * self.b.opacity(u, v, p)
+    }
+
+    fn has_cutout(&self) -> bool {
+        self.a.has_cutout() || self.b.has_cutout()
+    }
+
+    // The medium isn't blended, so the first component that has one wins.
+    fn interior(&self) -> Option<Medium> {
+        self.a.interior().or_else(|| self.b.interior())
+    }
+
+    fn is_spectral(&self) -> bool {
+        self.a.is_spectral() || self.b.is_spectral()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::tests::*;
+    use lambertian::Lambertian;
+    use conductor::Conductor;
+    use texture::constant;
+
+    #[test]
+    fn sample_matches_eval_over_pdf() {
+        let mix = MixMaterial::new(Arc::new(Lambertian::new(Vec3::from_f64(0.8, 0.3, 0.2))), Arc::new(Conductor::gold(0.3)), constant(0.4));
+
+        check_sample_matches_eval_over_pdf(&mix, &[(flat_hit(true), Vec3::from_f64(1.0, -0.3, -1.0))], 2000);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::*;
+use vec3::*;
+use scene::Scene;
+use camera::Camera;
+use film::SplatFilm;
+use sampler::Sampler;
+use integrator::PathIntegrator;
+use distribution::Distribution1D;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::f64::consts::PI;
+
+// Primary sample space Metropolis light transport (Kelemen et al. 2002,
+// following pbrt-v3). The path tracer is run with every random number it draws
+// taken from a vector of primary samples, so a path is a point in the unit
+// hypercube. Markov chains wander that space with large steps (fresh uniform
+// samples) and small steps (perturbations), visiting paths in proportion to
+// their luminance, and splat them onto the film.
+
+#[derive(Copy, Clone)]
+struct PrimarySample {
+    value: f64,
+    last_modified: u64,
+    backup: f64,
+    backup_modified: u64
+}
+
+struct MltSampler {
+    rng: StdRng,
+    sigma: f64,
+    large_step_probability: f64,
+    x: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    index: usize
+}
+
+impl MltSampler {
+    // Samples are created lazily, so the first path behaves as a large step.
+    fn new(seed: u64, sigma: f64, large_step_probability: f64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            x: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for xi in self.x.iter_mut() {
+            if xi.last_modified == self.iteration {
+                xi.value = xi.backup;
+                xi.last_modified = xi.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    // Standard normal sample by Box-Muller.
+    fn normal(&mut self) -> f64 {
+        let u1: f64 = 1.0 - self.rng.gen::<f64>();
+        let u2: f64 = self.rng.gen();
+        (-2.0*u1.ln()).sqrt() * (2.0*PI*u2).cos()
+    }
+
+    // Brings sample i up to date with the mutations it missed while unused.
+    fn ensure_ready(&mut self, i: usize) {
+        //Coordinates the chain hasn't used yet are independent and uniform;
+        //starting them at zero would stall rejection sampling loops
+        while i >= self.x.len() {
+            let value = self.rng.gen();
+            let last_modified = self.iteration.saturating_sub(1);
+            self.x.push(PrimarySample { value, last_modified, backup: value, backup_modified: last_modified });
+        }
+
+        if self.x[i].last_modified < self.last_large_step {
+            self.x[i].value = self.rng.gen();
+            self.x[i].last_modified = self.last_large_step;
+        }
+
+        let xi = self.x[i];
+        self.x[i].backup = xi.value;
+        self.x[i].backup_modified = xi.last_modified;
+
+        let value = if self.large_step {
+            self.rng.gen()
+        } else {
+            //Pending small steps add up to one wider Gaussian
+            let steps = (self.iteration - xi.last_modified) as f64;
+            let v = xi.value + self.normal() * self.sigma * steps.sqrt();
+            v - v.floor()
+        };
+
+        self.x[i].value = value;
+        self.x[i].last_modified = self.iteration;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next_1d(&mut self) -> f64 {
+        let i = self.index;
+        self.index += 1;
+        self.ensure_ready(i);
+        self.x[i].value
+    }
+}
+
+pub struct MltIntegrator {
+    pub path: PathIntegrator,
+    pub mutations_per_pixel: usize,
+    pub chains: usize,
+    pub bootstrap: usize,
+    pub sigma: f64,
+    pub large_step_probability: f64,
+    pub spectral: bool
+}
+
+// A path's contribution in RGB and the image coordinates it lands on.
+struct PathSample {
+    rgb: Color3,
+    u: f64,
+    v: f64
+}
+
+impl MltIntegrator {
+    pub fn new(path: PathIntegrator, mutations_per_pixel: usize) -> Self {
+        Self {
+            path,
+            mutations_per_pixel,
+            chains: 1000,
+            bootstrap: 100_000,
+            sigma: 0.01,
+            large_step_probability: 0.3,
+            spectral: false
+        }
+    }
+
+    pub fn with_chains(mut self, chains: usize, bootstrap: usize) -> Self {
+        self.chains = chains;
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    pub fn with_mutation(mut self, sigma: f64, large_step_probability: f64) -> Self {
+        self.sigma = sigma;
+        self.large_step_probability = large_step_probability;
+        self
+    }
+
+    pub fn with_spectral(mut self, spectral: bool) -> Self {
+        self.spectral = spectral;
+        self
+    }
+
+    // Runs the path tracer on the sampler's current primary samples.
+    fn evaluate(&self, sampler: &Rc<RefCell<MltSampler>>, scene: &Scene, camera: &Camera, width: u32, height: u32) -> PathSample {
+        sampler::scoped(Box::new(sampler.clone()), || {
+            //Same pixel to camera mapping as main()
+            let u = util::random_double() * width as f64 / (width - 1) as f64;
+            let v = util::random_double() * height as f64 / (height - 1) as f64;
+
+            let lambdas = if self.spectral { Some(spectrum::sample_wavelengths(util::random_double())) } else { None };
+            let r = camera.get_ray(u, v).with_wavelengths(lambdas);
+            let col = self.path.li(&r, scene);
+            let rgb = match lambdas {
+                Some(l) => spectrum::to_rgb(&col, &l),
+                None => col
+            };
+
+            PathSample { rgb, u, v }
+        })
+    }
+
+    fn sampler(&self, index: usize) -> Rc<RefCell<MltSampler>> {
+        let seed = low_discrepancy::hash(&[sampler::seed(), index as u64]);
+        Rc::new(RefCell::new(MltSampler::new(seed, self.sigma, self.large_step_probability)))
+    }
+
+    // Renders the whole image and returns the radiance of each pixel, laid out
+    // like main()'s cells.
+    pub fn render(&self, scene: &Scene, camera: &Camera, width: u32, height: u32) -> Vec<Color3> {
+        //Bootstrap paths estimate the image's total luminance and seed the chains
+        let weights: Vec<f64> = (0..self.bootstrap).into_par_iter().map(|i| {
+            let y = spectrum::luminance(&self.evaluate(&self.sampler(i), scene, camera, width, height).rgb);
+            if y.is_finite() { y.max(0.0) } else { 0.0 }
+        }).collect();
+
+        let b = weights.iter().sum::<f64>() / self.bootstrap as f64;
+        let film = SplatFilm::new(width, height);
+        if b <= 0.0 {
+            return vec![Vec3::new(); (width*height) as usize];
+        }
+
+        let seeds = Distribution1D::new(&weights);
+        let mutations = self.mutations_per_pixel as u64 * (width*height) as u64;
+
+        (0..self.chains).into_par_iter().for_each(|chain| {
+            let count = mutations / self.chains as u64 + if (chain as u64) < mutations % self.chains as u64 { 1 } else { 0 };
+
+            //Replaying a bootstrap seed regenerates its path as the chain's start
+            let (_, _, seed) = seeds.sample((chain as f64 + 0.5) / self.chains as f64);
+            let sampler = self.sampler(seed);
+            let mut current = self.evaluate(&sampler, scene, camera, width, height);
+            let mut y_current = spectrum::luminance(&current.rgb);
+
+            for _ in 0..count {
+                sampler.borrow_mut().start_iteration();
+                let proposed = self.evaluate(&sampler, scene, camera, width, height);
+                let y_proposed = spectrum::luminance(&proposed.rgb);
+                let y_proposed = if y_proposed.is_finite() { y_proposed.max(0.0) } else { 0.0 };
+
+                let accept = if y_current > 0.0 { f64::min(1.0, y_proposed / y_current) } else { 1.0 };
+
+                //Both states are splatted with their expected weights
+                if accept > 0.0 {
+                    film.add(proposed.u, proposed.v, &(proposed.rgb * (accept / y_proposed)));
+                }
+                if accept < 1.0 {
+                    film.add(current.u, current.v, &(current.rgb * ((1.0 - accept) / y_current)));
+                }
+
+                if sampler.borrow_mut().rng.gen::<f64>() < accept {
+                    current = proposed;
+                    y_current = y_proposed;
+                    sampler.borrow_mut().accept();
+                } else {
+                    sampler.borrow_mut().reject();
+                }
+            }
+        });
+
+        let scale = b / self.mutations_per_pixel as f64;
+        (0..(width*height) as usize).map(|i| film.get(i) * scale).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use hittable_list::HittableList;
+    use triangle::Triangle;
+    use lambertian::Lambertian;
+    use environment::Gradient;
+
+    fn draw(sampler: &mut MltSampler, n: usize) -> Vec<f64> {
+        (0..n).map(|_| sampler.next_1d()).collect()
+    }
+
+    fn state(sampler: &MltSampler) -> Vec<(u64, u64)> {
+        sampler.x.iter().map(|x| (x.value.to_bits(), x.last_modified)).collect()
+    }
+
+    #[test]
+    fn rejected_steps_restore_the_primary_samples() {
+        for large_step_probability in [0.0, 1.0] {
+            let mut sampler = MltSampler::new(3, 0.01, large_step_probability);
+            let first = draw(&mut sampler, 8);
+            sampler.accept();
+            let before = state(&sampler);
+
+            //The proposal also reaches samples the current path never used
+            sampler.start_iteration();
+            let proposed = draw(&mut sampler, 12);
+            assert_ne!(&proposed[..8], &first[..]);
+            sampler.reject();
+
+            assert_eq!(&state(&sampler)[..8], &before[..]);
+            assert_eq!(sampler.iteration, 0);
+        }
+    }
+
+    #[test]
+    fn bootstrap_seeds_replay_their_paths() {
+        let mat = Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
+        let (a, b, c, d) = (Vec3::from_f64(-100.0, 0.0, -100.0), Vec3::from_f64(100.0, 0.0, -100.0), Vec3::from_f64(100.0, 0.0, 100.0), Vec3::from_f64(-100.0, 0.0, 100.0));
+        let mut world = HittableList::new();
+        world.add(Arc::new(Triangle::new(a, b, c, mat.clone())));
+        world.add(Arc::new(Triangle::new(a, c, d, mat)));
+        let mut scene = Scene::new(bvh::BVH::new(world));
+        scene.set_environment(Arc::new(Gradient::sky()));
+
+        let camera = Camera::new(Vec3::from_f64(0.0, 1.0, 3.0), Vec3::new(), Vec3::from_f64(0.0, 1.0, 0.0), 1.0, 60.0, 0.0, 3.0);
+        let mlt = MltIntegrator::new(PathIntegrator::new(), 1).with_spectral(true);
+
+        for seed in [0, 7, 99] {
+            let first = mlt.evaluate(&mlt.sampler(seed), &scene, &camera, 8, 8);
+            let again = mlt.evaluate(&mlt.sampler(seed), &scene, &camera, 8, 8);
+            assert_eq!((first.u, first.v), (again.u, again.v));
+            assert_eq!(first.rgb.x().to_bits(), again.rgb.x().to_bits());
+            assert_eq!(first.rgb.y().to_bits(), again.rgb.y().to_bits());
+            assert_eq!(first.rgb.z().to_bits(), again.rgb.z().to_bits());
+        }
+
+        let paths: Vec<f64> = (0..4).map(|seed| mlt.evaluate(&mlt.sampler(seed), &scene, &camera, 8, 8).u).collect();
+        assert!(paths.windows(2).all(|w| w[0] != w[1]));
+    }
+}
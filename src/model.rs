@@ -1,4 +1,3 @@
-use tobj;
 use crate::triangle::Triangle;
 use crate::vec3::Vec3;
 use crate::ray::Ray;
@@ -6,13 +5,69 @@ use crate::material::Material;
 use std::sync::Arc;
 use crate::hittable::*;
 use crate::aabb::*;
+use crate::light::Light;
+use crate::texture::{self, Texture, ImageTexture};
+use crate::principled::Principled;
+use crate::normal_map::{NormalMap, BumpMap};
+use crate::cutout::Cutout;
+use std::path::Path;
 
 pub struct Model {
     tris: Vec<Triangle>,
     bb: Option<AABB>
 }
 
+// The -bm multiplier given before a bump or normal map's file name.
+fn bump_multiplier(map: &str) -> f64 {
+    let options: Vec<&str> = map.split_whitespace().collect();
+    options.iter().position(|&o| o == "-bm").and_then(|i| options.get(i + 1)).and_then(|v| v.parse().ok()).unwrap_or(1.0)
+}
+
+// A Principled material for an MTL entry. Besides Kd and map_Kd it reads the
+// usual PBR extensions: Pr/map_Pr roughness, Pm/map_Pm metallic, Ke emission
+// and norm for a tangent-space normal map. map_Bump is a height map, and
+// both are scaled by their -bm option. map_d is an alpha mask.
+fn mtl_material(m: &tobj::Material, dir: &Path) -> Arc<dyn Material> {
+    let file = |name: &str| dir.join(name.split_whitespace().last().unwrap_or("")).to_str().unwrap().to_string();
+    let param = |key: &str| m.unknown_param.get(key).map(|v| v.trim());
+    let scalar = |key: &str, map: &str| -> Option<Arc<dyn Texture>> {
+        match (param(map), param(key).and_then(|v| v.parse::<f64>().ok())) {
+            (Some(name), _) => Some(Arc::new(ImageTexture::linear(&file(name)))),
+            (None, Some(v)) => Some(texture::constant(v)),
+            (None, None) => None
+        }
+    };
+
+    let base: Arc<dyn Texture> = if m.diffuse_texture.is_empty() {
+        texture::solid(Vec3::from_f64(m.diffuse[0] as f64, m.diffuse[1] as f64, m.diffuse[2] as f64))
+    } else {
+        Arc::new(ImageTexture::new(&file(&m.diffuse_texture)))
+    };
+
+    //MTL materials are dielectric unless Pm says otherwise
+    let mut principled = Principled::new(base).with_metallic(scalar("Pm", "map_Pm").unwrap_or_else(|| texture::constant(0.0)));
+    if let Some(roughness) = scalar("Pr", "map_Pr") {
+        principled = principled.with_roughness(roughness);
+    }
+    let ke: Vec<f64> = param("Ke").map(|v| v.split_whitespace().filter_map(|x| x.parse().ok()).collect()).unwrap_or_default();
+    if ke.len() == 3 && ke.iter().any(|&x| x > 0.0) {
+        principled = principled.with_emission(texture::solid(Vec3::from_f64(ke[0], ke[1], ke[2])), 1.0);
+    }
+
+    let mut mat: Arc<dyn Material> = Arc::new(principled);
+    if let Some(name) = param("norm") {
+        mat = Arc::new(NormalMap::new(mat, Arc::new(ImageTexture::linear(&file(name)))).with_strength(bump_multiplier(name)));
+    } else if !m.normal_texture.is_empty() {
+        mat = Arc::new(BumpMap::new(mat, Arc::new(ImageTexture::linear(&file(&m.normal_texture))), bump_multiplier(&m.normal_texture)));
+    }
+    if !m.dissolve_texture.is_empty() {
+        mat = Arc::new(Cutout::new(mat, Arc::new(ImageTexture::alpha(&file(&m.dissolve_texture)))));
+    }
+    mat
+}
+
 impl Model {
+    // Meshes with an MTL material use it, the rest get mat.
     pub fn new(path: &str, mat: Arc<dyn Material>) -> Self {
         let model = tobj::load_obj(
             path,
@@ -21,13 +76,16 @@ impl Model {
             },
         );
 
-        let (models, _) = model.expect("Model load failed.");
+        let (models, materials) = model.expect("Model load failed.");
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        let materials: Vec<Arc<dyn Material>> = materials.unwrap_or_default().iter().map(|m| mtl_material(m, dir)).collect();
 
 
         let mut tris = Vec::new();
 
-        for (_, model) in models.iter().enumerate() {
+        for model in models.iter() {
             let mesh = &model.mesh;
+            let mat = mesh.material_id.and_then(|i| materials.get(i)).unwrap_or(&mat);
 
             let mut vertices = Vec::new();
             for chunk in mesh.positions.chunks(3) {
@@ -42,13 +100,21 @@ impl Model {
                 panic!("Mesh is not triangulated!");
             }
 
+            let uvs: Vec<(f64, f64)> = mesh.texcoords.chunks(2).map(|c| (c[0] as f64, c[1] as f64)).collect();
+            let uv_indices = if mesh.texcoord_indices.is_empty() { &mesh.indices } else { &mesh.texcoord_indices };
+
             //println!("{:?}", vertices);
-            for v_chunk in mesh.indices.chunks(3) {
+            for (i, v_chunk) in mesh.indices.chunks(3).enumerate() {
                 let v0 = vertices[v_chunk[0] as usize];
                 let v1 = vertices[v_chunk[1] as usize];
                 let v2 = vertices[v_chunk[2] as usize];
                 //println!("{} {} {}", v0, v1, v2);
-                tris.push(Triangle::new(v0, v1, v2, mat.clone()));
+                if uvs.is_empty() {
+                    tris.push(Triangle::new(v0, v1, v2, mat.clone()));
+                } else {
+                    let uv = [uvs[uv_indices[3*i] as usize], uvs[uv_indices[3*i+1] as usize], uvs[uv_indices[3*i+2] as usize]];
+                    tris.push(Triangle::with_uvs(v0, v1, v2, uv, mat.clone()));
+                }
             }
         }
 
@@ -82,4 +148,8 @@ impl Hittable for Model {
     fn bounding_box(&self) -> Option<AABB> {
         self.bb.clone()
     }
+
+    fn lights(&self) -> Vec<Arc<dyn Light>> {
+        self.tris.iter().flat_map(|tri| tri.lights()).collect()
+    }
 }
@@ -0,0 +1,236 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use medium::Medium;
+use texture::Texture;
+use std::sync::Arc;
+
+// Normal and bump maps wrap another material and hand it a copy of the hit
+// record with a perturbed shading normal. The geometric normal is left alone
+// and used to reject directions that would pass through the real surface.
+
+fn perturbed(rec: &HitRecord, outward: Vec3) -> HitRecord {
+    let mut rec = rec.clone();
+    rec.normal = if rec.front_face { outward } else { -outward };
+
+    // Keep the shading normal on the same side as the geometric one.
+    if Vec3::dot(&rec.normal, &rec.geo_normal) <= 0.0 {
+        rec.normal = rec.geo_normal;
+    }
+
+    rec
+}
+
+// Scatters with the perturbed record and drops samples that leave on the
+// other side of the geometry than the shading normal intended.
+fn scatter_with(inner: &dyn Material, ray: &Ray, rec: &HitRecord, shading: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+    let lobe = inner.scatter(ray, shading, attenuation, scattered)?;
+
+    let dir = scattered.direction();
+    let shading_side = Vec3::dot(&dir, &shading.normal) > 0.0;
+    let geo_side = Vec3::dot(&dir, &rec.geo_normal) > 0.0;
+    if shading_side != geo_side {
+        return None;
+    }
+
+    *scattered = rec.spawn_ray(&dir);
+    Some(lobe)
+}
+
+pub struct NormalMap {
+    inner: Arc<dyn Material>,
+    map: Arc<dyn Texture>,
+    strength: f64
+}
+
+impl NormalMap {
+    // map is a tangent-space normal map, read linearly (see ImageTexture::linear).
+    pub fn new(inner: Arc<dyn Material>, map: Arc<dyn Texture>) -> Self {
+        Self {
+            inner,
+            map,
+            strength: 1.0
+        }
+    }
+
+    pub fn with_strength(mut self, strength: f64) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    fn shading(&self, rec: &HitRecord) -> HitRecord {
+        let c = self.map.value(rec.u, rec.v, &rec.p);
+        let ts = Vec3::from_f64(self.strength*(2.0*c.x() - 1.0), self.strength*(2.0*c.y() - 1.0), 2.0*c.z() - 1.0);
+        if ts.near_zero() {
+            return rec.clone();
+        }
+
+        let outward = if rec.front_face { rec.normal } else { -rec.normal };
+        let frame = onb::ONB::from_wt(&outward, &rec.tangent);
+
+        perturbed(rec, Vec3::unit(&frame.local_to_world(&ts)))
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        scatter_with(self.inner.as_ref(), ray, rec, &self.shading(rec), attenuation, scattered)
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        self.inner.eval(wo, wi, &self.shading(rec))
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        self.inner.pdf(wo, wi, &self.shading(rec))
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color3 {
+        self.inner.emitted(rec)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.inner.is_emissive()
+    }
+
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        self.inner.emitted_spectral(rec, lambdas)
+    }
+
+    fn opacity(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        self.inner.opacity(u, v, p)
+    }
+
+    fn has_cutout(&self) -> bool {
+        self.inner.has_cutout()
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        self.inner.interior()
+    }
+
+    fn is_spectral(&self) -> bool {
+        self.inner.is_spectral()
+    }
+}
+
+pub struct BumpMap {
+    inner: Arc<dyn Material>,
+    height: Arc<dyn Texture>,
+    scale: f64
+}
+
+const BUMP_DELTA: f64 = 1.0/2048.0;
+
+impl BumpMap {
+    // height is read from the first channel; scale converts it to scene units
+    // relative to one unit of texture space.
+    pub fn new(inner: Arc<dyn Material>, height: Arc<dyn Texture>, scale: f64) -> Self {
+        Self {
+            inner,
+            height,
+            scale
+        }
+    }
+
+    fn shading(&self, rec: &HitRecord) -> HitRecord {
+        let h = |u: f64, v: f64| self.height.value(u, v, &rec.p).x();
+
+        let h0 = h(rec.u, rec.v);
+        let dhdu = (h(rec.u + BUMP_DELTA, rec.v) - h0) / BUMP_DELTA;
+        let dhdv = (h(rec.u, rec.v + BUMP_DELTA) - h0) / BUMP_DELTA;
+
+        let outward = if rec.front_face { rec.normal } else { -rec.normal };
+        let frame = onb::ONB::from_wt(&outward, &rec.tangent);
+        let n = outward - self.scale*(dhdu*frame.u() + dhdv*frame.v());
+
+        perturbed(rec, Vec3::unit(&n))
+    }
+}
+
+impl Material for BumpMap {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        scatter_with(self.inner.as_ref(), ray, rec, &self.shading(rec), attenuation, scattered)
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        self.inner.eval(wo, wi, &self.shading(rec))
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        self.inner.pdf(wo, wi, &self.shading(rec))
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color3 {
+        self.inner.emitted(rec)
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.inner.is_emissive()
+    }
+
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        self.inner.emitted_spectral(rec, lambdas)
+    }
+
+    fn opacity(&self, u: f64, v: f64, p: &Point3) -> f64 {
+        self.inner.opacity(u, v, p)
+    }
+
+    fn has_cutout(&self) -> bool {
+        self.inner.has_cutout()
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        self.inner.interior()
+    }
+
+    fn is_spectral(&self) -> bool {
+        self.inner.is_spectral()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambertian::Lambertian;
+    use texture::{solid, constant};
+
+    fn hit(front_face: bool) -> HitRecord {
+        let mut rec = HitRecord::new();
+        rec.normal = Vec3::from_f64(0.0, if front_face { 1.0 } else { -1.0 }, 0.0);
+        rec.geo_normal = rec.normal;
+        rec.tangent = Vec3::from_f64(1.0, 0.0, 0.0);
+        rec.front_face = front_face;
+        rec
+    }
+
+    #[test]
+    fn flat_maps_keep_the_normal() {
+        let inner: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
+        let normal_map = NormalMap::new(inner.clone(), solid(Color3::from_f64(0.5, 0.5, 1.0)));
+        let bump_map = BumpMap::new(inner, constant(0.3), 1.0);
+
+        for front_face in [true, false] {
+            let rec = hit(front_face);
+            assert!((normal_map.shading(&rec).normal - rec.normal).length() < 1e-9);
+            assert!((bump_map.shading(&rec).normal - rec.normal).length() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn normal_map_tilts_along_the_tangent() {
+        let inner: Arc<dyn Material> = Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
+        let map = NormalMap::new(inner, solid(Color3::from_f64(1.0, 0.5, 1.0)));
+
+        //+x in tangent space leans the outward normal 45 degrees towards the tangent
+        let expected = Vec3::unit(&Vec3::from_f64(1.0, 1.0, 0.0));
+        let n = map.shading(&hit(true)).normal;
+        assert!((n - expected).length() < 1e-9);
+
+        //Seen from the back the shading normal flips with the geometric one
+        let n = map.shading(&hit(false)).normal;
+        assert!((n + expected).length() < 1e-9);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::vec3::*;
+
+// Orthonormal basis with w along the surface normal, used to move directions
+// in and out of the local shading frame where the normal is +z.
+#[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct ONB {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3
+}
+
+impl ONB {
+    pub fn from_w(n: &Vec3) -> Self {
+        let w = Vec3::unit(n);
+        let a = if w.x().abs() > 0.9 {
+            Vec3::from_f64(0.0, 1.0, 0.0)
+        } else {
+            Vec3::from_f64(1.0, 0.0, 0.0)
+        };
+
+        let v = Vec3::unit(&Vec3::cross(&w, &a));
+        let u = Vec3::cross(&w, &v);
+
+        Self {
+            u,
+            v,
+            w
+        }
+    }
+
+    // Like from_w, but with u following the projection of t onto the plane,
+    // so anisotropic materials line up with the surface parameterization.
+    pub fn from_wt(n: &Vec3, t: &Vec3) -> Self {
+        let w = Vec3::unit(n);
+        let t = *t - Vec3::dot(t, &w)*w;
+        if t.near_zero() {
+            return ONB::from_w(n);
+        }
+
+        let u = Vec3::unit(&t);
+        let v = Vec3::cross(&w, &u);
+
+        Self {
+            u,
+            v,
+            w
+        }
+    }
+
+    pub fn u(&self) -> Vec3 {
+        self.u
+    }
+
+    pub fn v(&self) -> Vec3 {
+        self.v
+    }
+
+    pub fn local_to_world(&self, a: &Vec3) -> Vec3 {
+        a.x()*self.u + a.y()*self.v + a.z()*self.w
+    }
+
+    pub fn world_to_local(&self, a: &Vec3) -> Vec3 {
+        Vec3::from_f64(Vec3::dot(a, &self.u), Vec3::dot(a, &self.v), Vec3::dot(a, &self.w))
+    }
+}
@@ -0,0 +1,353 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use microfacet::GGX;
+use texture::*;
+use blackbody::Blackbody;
+use std::sync::Arc;
+use std::f64::consts::PI;
+
+// Uber material following the glTF 2.0 metallic-roughness model and its
+// KHR_materials_{specular,transmission,clearcoat,sheen,ior,emissive_strength}
+// extensions. Defaults match the glTF defaults, so a material with only
+// base_color set is a rough metal, just like in glTF.
+pub struct Principled {
+    base_color: Arc<dyn Texture>,
+    metallic: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>,
+    specular: Arc<dyn Texture>,
+    transmission: Arc<dyn Texture>,
+    clearcoat: Arc<dyn Texture>,
+    clearcoat_roughness: Arc<dyn Texture>,
+    sheen: Arc<dyn Texture>,
+    subsurface: Arc<dyn Texture>,
+    subsurface_color: Arc<dyn Texture>,
+    emission: Arc<dyn Texture>,
+    emission_strength: f64,
+    // Spectrum behind the emission, used as is in spectral mode
+    blackbody: Option<Blackbody>,
+    emissive: bool,
+    ior: f64
+}
+
+// Texture lookups for one shading point.
+struct Params {
+    base_color: Color3,
+    metallic: f64,
+    roughness: f64,
+    specular: f64,
+    transmission: f64,
+    clearcoat: f64,
+    clearcoat_roughness: f64,
+    sheen: Color3,
+    subsurface: f64,
+    subsurface_color: Color3,
+    eta: f64
+}
+
+const CLEARCOAT_IOR: f64 = 1.5;
+
+fn schlick(f0: &Color3, cos: f64) -> Color3 {
+    let w = (1.0 - cos).clamp(0.0, 1.0).powi(5);
+    *f0 + (Vec3::from_f64(1.0, 1.0, 1.0) - *f0) * w
+}
+
+impl Principled {
+    pub fn new(base_color: Arc<dyn Texture>) -> Self {
+        Self {
+            base_color,
+            metallic: constant(1.0),
+            roughness: constant(1.0),
+            specular: constant(1.0),
+            transmission: constant(0.0),
+            clearcoat: constant(0.0),
+            clearcoat_roughness: constant(0.0),
+            sheen: constant(0.0),
+            subsurface: constant(0.0),
+            subsurface_color: constant(1.0),
+            emission: constant(0.0),
+            emission_strength: 1.0,
+            blackbody: None,
+            emissive: false,
+            ior: 1.5
+        }
+    }
+
+    pub fn with_metallic(mut self, t: Arc<dyn Texture>) -> Self {
+        self.metallic = t;
+        self
+    }
+
+    pub fn with_roughness(mut self, t: Arc<dyn Texture>) -> Self {
+        self.roughness = t;
+        self
+    }
+
+    pub fn with_specular(mut self, t: Arc<dyn Texture>) -> Self {
+        self.specular = t;
+        self
+    }
+
+    pub fn with_transmission(mut self, t: Arc<dyn Texture>) -> Self {
+        self.transmission = t;
+        self
+    }
+
+    pub fn with_clearcoat(mut self, amount: Arc<dyn Texture>, roughness: Arc<dyn Texture>) -> Self {
+        self.clearcoat = amount;
+        self.clearcoat_roughness = roughness;
+        self
+    }
+
+    pub fn with_sheen(mut self, color: Arc<dyn Texture>) -> Self {
+        self.sheen = color;
+        self
+    }
+
+    pub fn with_subsurface(mut self, amount: Arc<dyn Texture>, tint: Arc<dyn Texture>) -> Self {
+        self.subsurface = amount;
+        self.subsurface_color = tint;
+        self
+    }
+
+    pub fn with_emission(mut self, color: Arc<dyn Texture>, strength: f64) -> Self {
+        self.emission = color;
+        self.emission_strength = strength;
+        self.emissive = strength > 0.0;
+        self.blackbody = None;
+        self
+    }
+
+    pub fn with_blackbody_emission(self, blackbody: Blackbody) -> Self {
+        let mut m = self.with_emission(solid(blackbody.rgb()), 1.0);
+        m.blackbody = Some(blackbody);
+        m
+    }
+
+    pub fn with_ior(mut self, ior: f64) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    fn params(&self, rec: &HitRecord) -> Params {
+        let (u, v, p) = (rec.u, rec.v, &rec.p);
+        let scalar = |t: &Arc<dyn Texture>| t.value(u, v, p).x().clamp(0.0, 1.0);
+
+        Params {
+            base_color: self.base_color.value(u, v, p),
+            metallic: scalar(&self.metallic),
+            roughness: scalar(&self.roughness),
+            specular: scalar(&self.specular),
+            transmission: scalar(&self.transmission),
+            clearcoat: scalar(&self.clearcoat),
+            clearcoat_roughness: scalar(&self.clearcoat_roughness),
+            sheen: self.sheen.value(u, v, p),
+            subsurface: scalar(&self.subsurface),
+            subsurface_color: self.subsurface_color.value(u, v, p),
+            eta: if rec.front_face { self.ior } else { 1.0/self.ior }
+        }
+    }
+
+    // Probabilities of sampling the diffuse, specular, transmission and clearcoat
+    // lobes, estimated from their weights as seen from wo.
+    fn lobe_probabilities(p: &Params, wo: &Vec3) -> [f64; 4] {
+        let fv = f64::min(1.0, p.specular * fresnel::dielectric(wo.z(), p.eta));
+        let fc = fresnel::dielectric(wo.z(), CLEARCOAT_IOR);
+
+        let w = [
+            (1.0 - p.metallic) * (1.0 - p.transmission) * (1.0 - fv),
+            p.metallic + (1.0 - p.metallic) * f64::max(fv, 0.25),
+            (1.0 - p.metallic) * p.transmission * (1.0 - fv),
+            p.clearcoat * f64::max(fc, 0.25)
+        ];
+
+        let total: f64 = w.iter().sum();
+        [w[0]/total, w[1]/total, w[2]/total, w[3]/total]
+    }
+
+    fn eval_local(p: &Params, wo: &Vec3, wi: &Vec3) -> Color3 {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return Vec3::new();
+        }
+
+        let ggx = GGX::isotropic(p.roughness);
+        let coat_atten = 1.0 - p.clearcoat * fresnel::dielectric(wo.z(), CLEARCOAT_IOR);
+
+        if wi.z() < 0.0 {
+            let wh = match microfacet::half_vector(wo, wi, p.eta) {
+                Some(wh) => wh,
+                None => return Vec3::new()
+            };
+
+            let fd = f64::min(1.0, p.specular * fresnel::dielectric(Vec3::dot(wo, &wh), p.eta));
+            let denom = Vec3::dot(wi, &wh) + Vec3::dot(wo, &wh) / p.eta;
+            let btdf = ggx.d(&wh) * ggx.g(wo, wi) * (Vec3::dot(wi, &wh) * Vec3::dot(wo, &wh)).abs() / (wo.z() * denom * denom);
+
+            return p.base_color * (coat_atten * (1.0 - p.metallic) * p.transmission * (1.0 - fd) * btdf);
+        }
+
+        let wh = Vec3::unit(&(*wo + *wi));
+        let cos_d = Vec3::dot(wi, &wh);
+        let fd = f64::min(1.0, p.specular * fresnel::dielectric(Vec3::dot(wo, &wh), p.eta));
+        let fm = schlick(&p.base_color, cos_d);
+
+        let spec = ((1.0 - p.metallic) * fd * Vec3::from_f64(1.0, 1.0, 1.0) + p.metallic * fm)
+                    * (ggx.d(&wh) * ggx.g(wo, wi) / (4.0*wo.z()));
+
+        // Disney's flattened subsurface approximation blended into Lambert.
+        let fss90 = p.roughness * cos_d * cos_d;
+        let fss = (1.0 + (fss90 - 1.0)*(1.0 - wi.z()).powi(5)) * (1.0 + (fss90 - 1.0)*(1.0 - wo.z()).powi(5));
+        let ss = 1.25*(fss*(1.0/(wi.z() + wo.z()) - 0.5) + 0.5);
+
+        let diffuse = ((1.0 - p.subsurface) * p.base_color + (p.subsurface * ss) * p.base_color * p.subsurface_color) / PI;
+        let sheen = p.sheen * (1.0 - cos_d).powi(5);
+        let base = (diffuse + sheen) * ((1.0 - p.metallic) * (1.0 - p.transmission) * (1.0 - fd) * wi.z());
+
+        let cc = GGX::isotropic(p.clearcoat_roughness);
+        let fc = fresnel::dielectric(Vec3::dot(wo, &wh), CLEARCOAT_IOR);
+        let coat = p.clearcoat * fc * cc.d(&wh) * cc.g(wo, wi) / (4.0*wo.z());
+
+        coat_atten * (base + spec) + Vec3::from_f64(coat, coat, coat)
+    }
+
+    fn pdf_local(p: &Params, wo: &Vec3, wi: &Vec3) -> f64 {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+
+        let probs = Principled::lobe_probabilities(p, wo);
+        let ggx = GGX::isotropic(p.roughness);
+
+        if wi.z() < 0.0 {
+            return match microfacet::half_vector(wo, wi, p.eta) {
+                Some(wh) => {
+                    let denom = Vec3::dot(wi, &wh) + Vec3::dot(wo, &wh) / p.eta;
+                    probs[2] * ggx.pdf(wo, &wh) * Vec3::dot(wi, &wh).abs() / (denom * denom)
+                },
+                None => 0.0
+            };
+        }
+
+        let wh = Vec3::unit(&(*wo + *wi));
+        let voh = Vec3::dot(wo, &wh);
+        let cc = GGX::isotropic(p.clearcoat_roughness);
+
+        probs[0] * wi.z() / PI
+            + probs[1] * ggx.pdf(wo, &wh) / (4.0*voh)
+            + probs[3] * cc.pdf(wo, &wh) / (4.0*voh)
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        let p = self.params(rec);
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&-Vec3::unit(&ray.direction()));
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        let probs = Principled::lobe_probabilities(&p, &wo);
+        let xi = util::random_double();
+
+        let wi = if xi < probs[0] {
+            util::random_cosine_direction()
+        } else if xi < probs[0] + probs[1] {
+            let (u1, u2) = util::random_2d();
+            let wh = GGX::isotropic(p.roughness).sample_wh(&wo, u1, u2);
+            Vec3::reflect(&-wo, &wh)
+        } else if xi < probs[0] + probs[1] + probs[2] {
+            let (u1, u2) = util::random_2d();
+            let wh = GGX::isotropic(p.roughness).sample_wh(&wo, u1, u2);
+            microfacet::refract(&wo, &wh, p.eta)?
+        } else {
+            let (u1, u2) = util::random_2d();
+            let wh = GGX::isotropic(p.clearcoat_roughness).sample_wh(&wo, u1, u2);
+            Vec3::reflect(&-wo, &wh)
+        };
+
+        //pdf_local only counts transmission below the surface and reflection
+        //above it, so samples landing on the other side are rejected
+        let transmitted = xi >= probs[0] + probs[1] && xi < probs[0] + probs[1] + probs[2];
+        if (wi.z() < 0.0) != transmitted {
+            return None;
+        }
+
+        let pdf = Principled::pdf_local(&p, &wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        *attenuation = Principled::eval_local(&p, &wo, &wi) / pdf;
+        *scattered = Ray::new(&rec.p, &frame.local_to_world(&wi));
+        Some(if transmitted { Lobe::Transmission } else { Lobe::Reflection })
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&Vec3::unit(wo));
+        let wi = frame.world_to_local(&Vec3::unit(wi));
+
+        Principled::eval_local(&self.params(rec), &wo, &wi)
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&Vec3::unit(wo));
+        let wi = frame.world_to_local(&Vec3::unit(wi));
+
+        Principled::pdf_local(&self.params(rec), &wo, &wi)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Color3 {
+        self.emission.value(rec.u, rec.v, &rec.p) * self.emission_strength
+    }
+
+    fn emitted_spectral(&self, rec: &HitRecord, lambdas: &[f64; 3]) -> Vec3 {
+        match &self.blackbody {
+            Some(bb) => bb.values(lambdas) * self.emission_strength,
+            None => spectrum::upsample(&self.emitted(rec), lambdas)
+        }
+    }
+
+    fn is_emissive(&self) -> bool {
+        self.emissive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::tests::*;
+
+    // Every sampled direction has to be reproducible through eval() and pdf().
+    // Rough transmission at a grazing angle is where refracted samples can
+    // end up on the incident side.
+    #[test]
+    fn sample_matches_eval_over_pdf() {
+        let glass = Principled::new(solid(Color3::from_f64(0.9, 0.8, 0.7)))
+            .with_metallic(constant(0.0))
+            .with_roughness(constant(1.0))
+            .with_transmission(constant(1.0))
+            .with_clearcoat(constant(0.5), constant(0.2));
+        let dir = Vec3::from_f64(1.0, -0.3, -0.15);
+
+        assert!(check_sample_matches_eval_over_pdf(&glass, &[(flat_hit(true), dir)], 5000) > 0);
+    }
+
+    #[test]
+    fn blackbody_emission_keeps_its_spectrum() {
+        let bb = Blackbody::new(2700.0, 100.0);
+        let m = Principled::new(constant(0.5)).with_blackbody_emission(bb);
+        let lambdas = [420.0, 560.0, 700.0];
+
+        let e = m.emitted_spectral(&flat_hit(true), &lambdas);
+        assert_eq!((e.x(), e.y(), e.z()), (bb.value(420.0), bb.value(560.0), bb.value(700.0)));
+        assert!(e.z() > 3.0*e.x());
+
+        //Plain RGB emission set afterwards replaces the spectrum
+        let m = m.with_emission(constant(2.0), 1.0);
+        assert_eq!(m.emitted_spectral(&flat_hit(true), &[560.0; 3]).y(), 2.0);
+    }
+}
@@ -3,17 +3,29 @@ use crate::vec3::*;
 #[derive(Copy, Clone)]
 pub struct Ray {
     dir: Vec3,
-    origin: Point3
+    origin: Point3,
+    wavelengths: Option<[f64; 3]>
 }
 
 impl Ray {
     pub fn new(origin: &Point3, dir: &Vec3) -> Self {
         Self {
             dir: *dir,
-            origin: *origin
+            origin: *origin,
+            wavelengths: None
         }
     }
 
+    // Wavelengths in nm carried by the three channels in spectral mode.
+    pub fn with_wavelengths(mut self, wavelengths: Option<[f64; 3]>) -> Self {
+        self.wavelengths = wavelengths;
+        self
+    }
+
+    pub fn wavelengths(&self) -> Option<[f64; 3]> {
+        self.wavelengths
+    }
+
     pub fn origin(&self) -> Point3 {
         self.origin
     }
@@ -0,0 +1,260 @@
+use crate::*;
+use sampler::SamplerKind;
+use std::time::Duration;
+
+// Render settings passed with --settings. They pick how the scene is rendered;
+// the scene itself is still built in main.rs. The file holds one `key = value`
+// per line and # starts a comment:
+//
+//   scene = showcase        # random or showcase
+//   ies = lamp.ies          # measured profile for the showcase's spot light
+//   environment.rotation = 90   # degrees, turns the --env map
+//   environment.intensity = 2   # scales the --env map or the sky
+//   integrator = sppm       # path, bdpt, sppm or mlt
+//   sampler = sobol         # independent, stratified, halton, sobol or bluenoise
+//   seed = 7                # same seed, same image
+//   samples = 50            # per pixel, on average when adaptive
+//   pass = 16               # samples per pixel per progressive pass
+//   time = 600              # seconds; stops after the pass that reaches it
+//   write_interval = 30     # seconds between intermediate images
+//   checkpoint = render.ckpt    # saved to for --resume; path and bdpt only
+//   checkpoint_interval = 300   # seconds between checkpoints
+//   adaptive.threshold = 0.02   # relative error at which a pixel stops
+//   adaptive.max_samples = 1024
+//   adaptive.spp_image = spp.png    # samples spent per pixel, for debugging
+//   max_depth = 10          # longest bdpt/sppm path
+//   path.diffuse = 16       # path integrator bounce limits per kind of bounce
+//   path.specular = 32
+//   path.transmission = 32
+//   path.volume = 256
+//   path.rr_depth = 3       # bounces before Russian roulette starts
+//   dispersion = true       # BK7 glass for the large glass sphere, seen with --spectral
+//   sppm.photons = 200000   # photons per iteration
+//   sppm.radius = 0.1       # initial gather radius in scene units
+//   sppm.iterations = 64
+//   sppm.time = 600         # seconds; stops early when reached
+//   mlt.mutations = 64      # per pixel
+//   mlt.chains = 1000
+//   mlt.bootstrap = 100000
+//   mlt.sigma = 0.01        # small step size
+//   mlt.large_step = 0.3    # large step probability
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SceneKind {
+    // The spheres from the end of Ray Tracing in One Weekend
+    Random,
+    // A row of spheres, one per material model, under point, spot and area lights
+    Showcase
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IntegratorKind {
+    Path,
+    Bdpt,
+    Sppm,
+    Mlt
+}
+
+pub struct RenderSettings {
+    pub scene: SceneKind,
+    pub ies: Option<String>,
+    pub environment_rotation: f64,
+    pub environment_intensity: f64,
+    pub integrator: IntegratorKind,
+    pub sampler: SamplerKind,
+    pub seed: u64,
+    pub samples: u32,
+    pub adaptive_threshold: Option<f64>,
+    pub pass: u32,
+    pub time: Option<Duration>,
+    pub write_interval: Option<Duration>,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: Duration,
+    pub adaptive_max_samples: u32,
+    pub adaptive_spp_image: Option<String>,
+    pub max_depth: usize,
+    pub path_max_diffuse: u32,
+    pub path_max_specular: u32,
+    pub path_max_transmission: u32,
+    pub path_max_volume: u32,
+    pub path_rr_depth: u32,
+    pub dispersion: bool,
+    pub sppm_photons: usize,
+    pub sppm_radius: f64,
+    pub sppm_iterations: usize,
+    pub sppm_time: Option<Duration>,
+    pub mlt_mutations: usize,
+    pub mlt_chains: usize,
+    pub mlt_bootstrap: usize,
+    pub mlt_sigma: f64,
+    pub mlt_large_step: f64
+}
+
+fn value<T: std::str::FromStr>(key: &str, v: &str) -> Result<T, String> {
+    v.parse().map_err(|_| format!("bad value '{}' for {}", v, key))
+}
+
+// Seconds; from_secs_f64 would panic on negative, NaN or overflowing values.
+fn duration(key: &str, v: &str) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(value(key, v)?).map_err(|e| format!("bad value '{}' for {}: {}", v, key, e))
+}
+
+// A number of samples; none at all would never finish a pass.
+fn count(key: &str, v: &str) -> Result<u32, String> {
+    match value(key, v)? {
+        0 => Err(format!("{} must be at least 1", key)),
+        n => Ok(n)
+    }
+}
+
+impl RenderSettings {
+    pub fn new() -> Self {
+        Self {
+            scene: SceneKind::Random,
+            ies: None,
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
+            integrator: IntegratorKind::Path,
+            sampler: SamplerKind::Independent,
+            seed: 0,
+            samples: 50,
+            adaptive_threshold: None,
+            pass: 16,
+            time: None,
+            write_interval: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(300),
+            adaptive_max_samples: 1024,
+            adaptive_spp_image: None,
+            max_depth: 10,
+            path_max_diffuse: 16,
+            path_max_specular: 32,
+            path_max_transmission: 32,
+            path_max_volume: 256,
+            path_rr_depth: 3,
+            dispersion: false,
+            sppm_photons: 200_000,
+            sppm_radius: 0.1,
+            sppm_iterations: 64,
+            sppm_time: None,
+            mlt_mutations: 64,
+            mlt_chains: 1000,
+            mlt_bootstrap: 100_000,
+            mlt_sigma: 0.01,
+            mlt_large_step: 0.3
+        }
+    }
+
+    pub fn load(path: &str) -> Self {
+        let text = std::fs::read_to_string(path).expect("Render settings load failed.");
+        RenderSettings::parse(&text).unwrap_or_else(|e| panic!("Render settings {} are not valid: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut settings = RenderSettings::new();
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, v) = line.split_once('=').ok_or_else(|| format!("expected key = value, got '{}'", line))?;
+            let (key, v) = (key.trim(), v.trim());
+
+            match key {
+                "scene" => settings.scene = match v {
+                    "random" => SceneKind::Random,
+                    "showcase" => SceneKind::Showcase,
+                    _ => return Err(format!("unknown scene '{}'", v))
+                },
+                "ies" => settings.ies = Some(v.to_string()),
+                "environment.rotation" => settings.environment_rotation = value(key, v)?,
+                "environment.intensity" => settings.environment_intensity = value(key, v)?,
+                "integrator" => settings.integrator = match v {
+                    "path" => IntegratorKind::Path,
+                    "bdpt" => IntegratorKind::Bdpt,
+                    "sppm" => IntegratorKind::Sppm,
+                    "mlt" => IntegratorKind::Mlt,
+                    _ => return Err(format!("unknown integrator '{}'", v))
+                },
+                "sampler" => settings.sampler = match v {
+                    "independent" => SamplerKind::Independent,
+                    "stratified" => SamplerKind::Stratified,
+                    "halton" => SamplerKind::Halton,
+                    "sobol" => SamplerKind::Sobol,
+                    "bluenoise" => SamplerKind::BlueNoise,
+                    _ => return Err(format!("unknown sampler '{}'", v))
+                },
+                "seed" => settings.seed = value(key, v)?,
+                "samples" => settings.samples = count(key, v)?,
+                "adaptive.threshold" => settings.adaptive_threshold = Some(value(key, v)?),
+                "pass" => settings.pass = count(key, v)?,
+                "time" => settings.time = Some(duration(key, v)?),
+                "write_interval" => settings.write_interval = Some(duration(key, v)?),
+                "checkpoint" => settings.checkpoint = Some(v.to_string()),
+                "checkpoint_interval" => settings.checkpoint_interval = duration(key, v)?,
+                "adaptive.max_samples" => settings.adaptive_max_samples = count(key, v)?,
+                "adaptive.spp_image" => settings.adaptive_spp_image = Some(v.to_string()),
+                "max_depth" => settings.max_depth = value(key, v)?,
+                "path.diffuse" => settings.path_max_diffuse = value(key, v)?,
+                "path.specular" => settings.path_max_specular = value(key, v)?,
+                "path.transmission" => settings.path_max_transmission = value(key, v)?,
+                "path.volume" => settings.path_max_volume = value(key, v)?,
+                "path.rr_depth" => settings.path_rr_depth = value(key, v)?,
+                "dispersion" => settings.dispersion = value(key, v)?,
+                "sppm.photons" => settings.sppm_photons = value(key, v)?,
+                "sppm.radius" => settings.sppm_radius = value(key, v)?,
+                "sppm.iterations" => settings.sppm_iterations = value(key, v)?,
+                "sppm.time" => settings.sppm_time = Some(duration(key, v)?),
+                "mlt.mutations" => settings.mlt_mutations = value(key, v)?,
+                "mlt.chains" => settings.mlt_chains = value(key, v)?,
+                "mlt.bootstrap" => settings.mlt_bootstrap = value(key, v)?,
+                "mlt.sigma" => settings.mlt_sigma = value(key, v)?,
+                "mlt.large_step" => settings.mlt_large_step = value(key, v)?,
+                _ => return Err(format!("unknown key '{}'", key))
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sppm_settings() {
+        let text = "# caustics\nintegrator = sppm\nsppm.photons = 5000  # per pass\n\nsppm.time = 1.5\n";
+        let settings = RenderSettings::parse(text).unwrap();
+
+        assert_eq!(settings.integrator, IntegratorKind::Sppm);
+        assert_eq!(settings.sppm_photons, 5000);
+        assert_eq!(settings.sppm_iterations, 64);
+        assert_eq!(settings.sppm_time, Some(Duration::from_millis(1500)));
+
+        assert!(!settings.dispersion);
+        assert!(RenderSettings::parse("dispersion = true").unwrap().dispersion);
+
+        assert!(RenderSettings::parse("integrator = ao").is_err());
+        assert_eq!(RenderSettings::parse("scene = showcase").unwrap().scene, SceneKind::Showcase);
+        assert!(RenderSettings::parse("scene = cornell").is_err());
+        assert!(RenderSettings::parse("sppm.radius").is_err());
+    }
+
+    #[test]
+    fn rejects_zero_samples() {
+        assert!(RenderSettings::parse("pass = 0").is_err());
+        assert!(RenderSettings::parse("samples = 0").is_err());
+        assert!(RenderSettings::parse("adaptive.max_samples = 0").is_err());
+        assert_eq!(RenderSettings::parse("pass = 1").unwrap().pass, 1);
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(RenderSettings::parse("time = -1").is_err());
+        assert!(RenderSettings::parse("write_interval = NaN").is_err());
+        assert!(RenderSettings::parse("checkpoint_interval = 1e300").is_err());
+        assert!(RenderSettings::parse("sppm.time = inf").is_err());
+    }
+}
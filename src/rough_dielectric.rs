@@ -0,0 +1,165 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use microfacet::GGX;
+use thin_film::ThinFilm;
+
+// Dielectric with GGX microfacets on both the reflected and the transmitted side
+// (Walter et al. 2007). Like Dieletric, the eta^2 radiance scaling across the
+// interface is left out so paths that enter and leave the object balance.
+pub struct RoughDielectric {
+    ir: f64,
+    distribution: GGX,
+    film: Option<ThinFilm>
+}
+
+impl RoughDielectric {
+    pub fn new(ir: f64, roughness: f64) -> Self {
+        Self {
+            ir,
+            distribution: GGX::isotropic(roughness),
+            film: None
+        }
+    }
+
+    pub fn anisotropic(ir: f64, roughness_u: f64, roughness_v: f64) -> Self {
+        Self {
+            ir,
+            distribution: GGX::from_roughness(roughness_u, roughness_v),
+            film: None
+        }
+    }
+
+    pub fn with_thin_film(mut self, film: ThinFilm) -> Self {
+        self.film = Some(film);
+        self
+    }
+
+    // Reflectance per channel. The film sits on the outside of the surface, so
+    // rays arriving from inside see the bare interface.
+    fn fresnel(&self, rec: &HitRecord, cos_i: f64, eta: f64) -> Color3 {
+        match &self.film {
+            Some(film) if rec.front_face => {
+                film.reflectance(rec, cos_i, &Vec3::from_f64(self.ir, self.ir, self.ir), &Vec3::new())
+            },
+            _ => {
+                let f = fresnel::dielectric(cos_i, eta);
+                Vec3::from_f64(f, f, f)
+            }
+        }
+    }
+
+    // rec.normal always faces the incoming ray, so the ratio only depends on which side we hit.
+    fn eta(&self, rec: &HitRecord) -> f64 {
+        if rec.front_face { self.ir } else { 1.0/self.ir }
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&-Vec3::unit(&ray.direction()));
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        let eta = self.eta(rec);
+        let (u1, u2) = util::random_2d();
+        let wh = self.distribution.sample_wh(&wo, u1, u2);
+        let f = self.fresnel(rec, Vec3::dot(&wo, &wh), eta);
+        let pr = (f.x() + f.y() + f.z()) / 3.0;
+
+        // Reflection is picked with the average reflectance, which cancels the
+        // Fresnel term for colorless interfaces.
+        let (wi, weight) = if util::random_double() < pr {
+            let wi = Vec3::reflect(&-wo, &wh);
+            if wi.z() <= 0.0 {
+                return None;
+            }
+            (wi, f / pr)
+        } else {
+            match microfacet::refract(&wo, &wh, eta) {
+                Some(wi) if wi.z() < 0.0 => (wi, (Vec3::from_f64(1.0, 1.0, 1.0) - f) / (1.0 - pr)),
+                _ => return None
+            }
+        };
+
+        *attenuation = weight * (self.distribution.g(&wo, &wi) / self.distribution.g1(&wo));
+        *scattered = Ray::new(&rec.p, &frame.local_to_world(&wi));
+        Some(if wi.z() > 0.0 { Lobe::Reflection } else { Lobe::Transmission })
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&Vec3::unit(wo));
+        let wi = frame.world_to_local(&Vec3::unit(wi));
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return Vec3::new();
+        }
+
+        let eta = self.eta(rec);
+        let wh = match microfacet::half_vector(&wo, &wi, eta) {
+            Some(wh) => wh,
+            None => return Vec3::new()
+        };
+
+        let f = self.fresnel(rec, Vec3::dot(&wo, &wh), eta);
+        let dg = self.distribution.d(&wh) * self.distribution.g(&wo, &wi);
+
+        if wi.z() > 0.0 {
+            f * (dg / (4.0*wo.z()))
+        } else {
+            let denom = Vec3::dot(&wi, &wh) + Vec3::dot(&wo, &wh) / eta;
+            (Vec3::from_f64(1.0, 1.0, 1.0) - f) * (dg * (Vec3::dot(&wi, &wh) * Vec3::dot(&wo, &wh)).abs() / (wo.z() * denom * denom))
+        }
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        let frame = rec.shading_frame();
+        let wo = frame.world_to_local(&Vec3::unit(wo));
+        let wi = frame.world_to_local(&Vec3::unit(wi));
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+
+        let eta = self.eta(rec);
+        let wh = match microfacet::half_vector(&wo, &wi, eta) {
+            Some(wh) => wh,
+            None => return 0.0
+        };
+
+        let f = self.fresnel(rec, Vec3::dot(&wo, &wh), eta);
+        let f = (f.x() + f.y() + f.z()) / 3.0;
+        let pdf_wh = self.distribution.pdf(&wo, &wh);
+
+        if wi.z() > 0.0 {
+            f * pdf_wh / (4.0*Vec3::dot(&wo, &wh))
+        } else {
+            let denom = Vec3::dot(&wi, &wh) + Vec3::dot(&wo, &wh) / eta;
+            (1.0 - f) * pdf_wh * Vec3::dot(&wi, &wh).abs() / (denom * denom)
+        }
+    }
+
+    // Only a film makes the reflectance depend on the ray's wavelengths
+    fn is_spectral(&self) -> bool {
+        self.film.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use material::tests::*;
+
+    // Every sampled direction has to be reproducible through eval() and pdf().
+    #[test]
+    fn sample_matches_eval_over_pdf() {
+        let plain = RoughDielectric::new(1.5, 0.4);
+        let coated = RoughDielectric::new(1.5, 0.4).with_thin_film(ThinFilm::new(1.33, 400.0));
+        let dir = Vec3::from_f64(1.0, -0.3, -1.0);
+
+        check_sample_matches_eval_over_pdf(&plain, &[(flat_hit(true), dir), (flat_hit(false), dir)], 2000);
+        check_sample_matches_eval_over_pdf(&coated, &[(flat_hit(true), dir)], 2000);
+    }
+}
@@ -0,0 +1,364 @@
+use crate::*;
+use low_discrepancy::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+// Source of the numbers util::random_double() hands out. Code that runs in
+// parallel installs a sampler around each unit of work, a pixel sample, a
+// photon or a Markov chain, so the numbers it gets depend only on the global
+// seed and that unit, never on which thread runs it or when.
+//
+// Every request takes the next dimension of the current pixel sample; 2D
+// requests take two at once, so samplers can stratify them jointly.
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, _x: u32, _y: u32, _index: u32) {}
+
+    fn next_1d(&mut self) -> f64;
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+// Lets the installer keep a handle and inspect the sampler's state afterwards.
+impl<S: Sampler> Sampler for Rc<RefCell<S>> {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.borrow_mut().start_pixel_sample(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        self.borrow_mut().next_1d()
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        self.borrow_mut().next_2d()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise
+}
+
+pub fn create(kind: SamplerKind, samples_per_pixel: u32, seed: u64) -> Box<dyn Sampler> {
+    let px = PixelSample { seed, ..Default::default() };
+    match kind {
+        SamplerKind::Independent => Box::new(IndependentSampler { px }),
+        SamplerKind::Stratified => Box::new(StratifiedSampler { samples_per_pixel, px }),
+        SamplerKind::Halton => Box::new(HaltonSampler { px }),
+        SamplerKind::Sobol => Box::new(SobolSampler { samples_per_pixel, px }),
+        SamplerKind::BlueNoise => Box::new(BlueNoiseSampler { samples_per_pixel, px })
+    }
+}
+
+// Position in the sample stream, common to the samplers below.
+#[derive(Default)]
+struct PixelSample {
+    seed: u64,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u64
+}
+
+impl PixelSample {
+    fn start(&mut self, x: u32, y: u32, index: u32) {
+        self.x = x;
+        self.y = y;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    // Hash of the pixel and the dimension, the same for all its samples.
+    fn pixel_hash(&self) -> u64 {
+        hash(&[self.x as u64, self.y as u64, self.dimension, self.seed])
+    }
+
+    // Uniform value for this sample, dimension and k.
+    fn uniform(&self, k: u64) -> f64 {
+        to_unit(hash(&[self.x as u64, self.y as u64, self.index as u64, self.dimension, self.seed, k]))
+    }
+
+    // Index into a sample set of n points, shuffled independently per pixel and dimension.
+    fn shuffled(&self, n: u32, h: u64) -> u32 {
+        if self.index < n { permutation_element(self.index, n, h as u32) } else { self.index }
+    }
+
+    fn advance(&mut self, dimensions: u64) {
+        self.dimension += dimensions;
+    }
+}
+
+pub struct IndependentSampler {
+    px: PixelSample
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.px.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let u = self.px.uniform(0);
+        self.px.advance(1);
+        u
+    }
+}
+
+// Jittered strata, shuffled per dimension so dimensions don't correlate.
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    px: PixelSample
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.px.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let n = self.samples_per_pixel;
+        let stratum = permutation_element(self.px.index % n, n, self.px.pixel_hash() as u32);
+        let u = (stratum as f64 + self.px.uniform(0)) / n as f64;
+        self.px.advance(1);
+        u
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let nx = (self.samples_per_pixel as f64).sqrt().ceil() as u32;
+        let ny = self.samples_per_pixel.div_ceil(nx);
+        let stratum = permutation_element(self.px.index % (nx*ny), nx*ny, self.px.pixel_hash() as u32);
+
+        let u = ((stratum % nx) as f64 + self.px.uniform(0)) / nx as f64;
+        let v = ((stratum / nx) as f64 + self.px.uniform(1)) / ny as f64;
+        self.px.advance(2);
+        (u, v)
+    }
+}
+
+// Halton points, Owen-scrambled per pixel and dimension.
+pub struct HaltonSampler {
+    px: PixelSample
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.px.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        //Past the prime table the larger bases would stratify poorly anyway
+        let d = self.px.dimension as usize;
+        let u = if d < PRIMES.len() {
+            owen_radical_inverse(PRIMES[d], self.px.index as u64, self.px.pixel_hash())
+        } else {
+            self.px.uniform(0)
+        };
+        self.px.advance(1);
+        u
+    }
+}
+
+// Owen-scrambled Sobol points. Each dimension (or pair of dimensions for 2D
+// requests) is its own shuffled and scrambled copy of the first two Sobol
+// dimensions, which keeps them well stratified at any dimension count.
+pub struct SobolSampler {
+    samples_per_pixel: u32,
+    px: PixelSample
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.px.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let h = self.px.pixel_hash();
+        let u = sobol_owen(self.px.shuffled(self.samples_per_pixel, h), 0, (h >> 32) as u32);
+        self.px.advance(1);
+        u
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let h = self.px.pixel_hash();
+        let i = self.px.shuffled(self.samples_per_pixel, h);
+        let u = (sobol_owen(i, 0, h as u32), sobol_owen(i, 1, (h >> 32) as u32));
+        self.px.advance(2);
+        u
+    }
+}
+
+// The same scrambled Sobol points for every pixel, shifted per pixel by a
+// blue noise mask (Georgiev and Fajardo 2016). Neighbouring pixels get
+// dissimilar shifts, so the remaining error looks like high frequency noise.
+pub struct BlueNoiseSampler {
+    samples_per_pixel: u32,
+    px: PixelSample
+}
+
+impl BlueNoiseSampler {
+    fn shift(&self, k: u64) -> f64 {
+        //Each dimension reads the mask at its own offset
+        let h = hash(&[self.px.dimension, self.px.seed, k]);
+        let n = BLUE_NOISE_SIZE as u64;
+        blue_noise(((self.px.x as u64 + h % n) % n) as usize, ((self.px.y as u64 + (h >> 32) % n) % n) as usize)
+    }
+
+    fn sequence(&self) -> (u32, u64) {
+        let h = hash(&[self.px.dimension, self.px.seed]);
+        (self.px.shuffled(self.samples_per_pixel, h), h)
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u32) {
+        self.px.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let (i, h) = self.sequence();
+        let v = sobol_owen(i, 0, (h >> 32) as u32) + self.shift(0);
+        self.px.advance(1);
+        v - v.floor()
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let (i, h) = self.sequence();
+        let u = sobol_owen(i, 0, h as u32) + self.shift(0);
+        let v = sobol_owen(i, 1, (h >> 32) as u32) + self.shift(1);
+        self.px.advance(2);
+        (u - u.floor(), v - v.floor())
+    }
+}
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+static NEXT_STREAM: AtomicU32 = AtomicU32::new(0);
+
+// Call before drawing any random numbers.
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+}
+
+pub fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+
+// Independent numbers for the work keyed by (tag, a, b), e.g. a photon.
+pub fn stream(tag: u64, a: u32, b: u32) -> Box<dyn Sampler> {
+    let mut sampler = IndependentSampler { px: PixelSample { seed: hash(&[seed(), tag]), ..Default::default() } };
+    sampler.start_pixel_sample(a, b, 0);
+    Box::new(sampler)
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Box<dyn Sampler>>> = RefCell::new(None);
+
+    //Used outside any installed sampler. Streams are numbered in order of first
+    //use, so the main thread always gets the same one.
+    static FALLBACK: RefCell<Box<dyn Sampler>> = RefCell::new(stream(u64::MAX, NEXT_STREAM.fetch_add(1, Ordering::Relaxed), 0));
+}
+
+// Runs f with sampler providing this thread's random numbers.
+pub fn scoped<R>(sampler: Box<dyn Sampler>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Box<dyn Sampler>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let prev = self.0.take();
+            CURRENT.with(|c| *c.borrow_mut() = prev);
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|c| c.borrow_mut().replace(sampler)));
+    f()
+}
+
+pub fn start_pixel_sample(x: u32, y: u32, index: u32) {
+    CURRENT.with(|c| {
+        if let Some(sampler) = c.borrow_mut().as_mut() {
+            sampler.start_pixel_sample(x, y, index);
+        }
+    });
+}
+
+// Which fallback stream a thread gets depends on scheduling, so parallel work
+// that draws numbers without installing a sampler is a bug, not just noise.
+fn fallback<R>(f: impl FnOnce(&mut dyn Sampler) -> R) -> R {
+    assert!(rayon::current_thread_index().is_none(), "Random number drawn on a worker thread without an installed sampler.");
+    FALLBACK.with(|s| f(s.borrow_mut().as_mut()))
+}
+
+pub fn next_1d() -> f64 {
+    CURRENT.with(|c| match c.borrow_mut().as_mut() {
+        Some(sampler) => sampler.next_1d(),
+        None => fallback(|s| s.next_1d())
+    })
+}
+
+pub fn next_2d() -> (f64, f64) {
+    CURRENT.with(|c| match c.borrow_mut().as_mut() {
+        Some(sampler) => sampler.next_2d(),
+        None => fallback(|s| s.next_2d())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(f64);
+
+    impl Sampler for Counter {
+        fn next_1d(&mut self) -> f64 {
+            self.0 += 0.25;
+            self.0
+        }
+    }
+
+    #[test]
+    fn scoped_sampler_feeds_util() {
+        let counter = Rc::new(RefCell::new(Counter(0.0)));
+        let drawn = scoped(Box::new(counter.clone()), || [crate::util::random_double(), crate::util::random_double()]);
+
+        assert_eq!(drawn, [0.25, 0.5]);
+        assert_eq!(counter.borrow().0, 0.5);
+        assert!(crate::util::random_double() < 1.0);
+        assert_eq!(counter.borrow().0, 0.5);
+    }
+
+    #[test]
+    fn pixel_samples_are_stratified() {
+        for kind in [SamplerKind::Stratified, SamplerKind::Sobol] {
+            let mut sampler = create(kind, 16, 3);
+            let mut strata = [[0; 4]; 4];
+            for i in 0..16 {
+                sampler.start_pixel_sample(5, 9, i);
+                sampler.next_1d();
+                let (u, v) = sampler.next_2d();
+                strata[(u*4.0) as usize][(v*4.0) as usize] += 1;
+            }
+            assert!(strata.iter().flatten().all(|&c| c == 1));
+        }
+    }
+
+    #[test]
+    fn streams_do_not_depend_on_thread() {
+        let draw = || scoped(stream(9, 4, 2), || [crate::util::random_double(), crate::util::random_double()]);
+        let here = draw();
+        let there = std::thread::spawn(draw).join().unwrap();
+
+        assert_eq!(here, there);
+        assert_ne!(here, scoped(stream(9, 5, 2), || [crate::util::random_double(), crate::util::random_double()]));
+    }
+
+    #[test]
+    fn workers_need_an_installed_sampler() {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        assert!(pool.install(|| std::panic::catch_unwind(crate::util::random_double)).is_err());
+        assert!(pool.install(|| scoped(stream(9, 4, 2), crate::util::random_double)) < 1.0);
+    }
+}
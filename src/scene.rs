@@ -0,0 +1,61 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use bvh::BVH;
+use light::Light;
+use light_tree::LightTree;
+use hittable::Hittable;
+use environment::{Environment, Gradient};
+use sky::PhysicalSky;
+use std::sync::Arc;
+
+pub struct Scene {
+    pub world: BVH,
+    pub lights: LightTree,
+    pub environment: Arc<dyn Environment>
+}
+
+impl Scene {
+    // Emissive primitives in the world are picked up as lights.
+    pub fn new(world: BVH) -> Self {
+        let lights = LightTree::new(world.lights());
+        Self {
+            world,
+            lights,
+            environment: Arc::new(Gradient::sky())
+        }
+    }
+
+    // Rebuilds the light tree, so many lights should go in one add_lights().
+    pub fn add_light(&mut self, light: Arc<dyn Light>) {
+        self.add_lights([light]);
+    }
+
+    pub fn add_lights(&mut self, lights: impl IntoIterator<Item = Arc<dyn Light>>) {
+        let mut all = self.lights.lights().to_vec();
+        let first = all.len();
+        all.extend(lights);
+        if let Some(world) = self.world.bounding_box() {
+            for light in &all[first..] {
+                light.preprocess(&world);
+            }
+        }
+        self.lights = LightTree::new(all);
+    }
+
+    pub fn set_environment(&mut self, environment: Arc<dyn Environment>) {
+        self.environment = environment;
+    }
+
+    // Uses the sky as the environment and adds its sun as a light.
+    pub fn set_sky(&mut self, sky: PhysicalSky) {
+        self.add_light(Arc::new(sky.sun()));
+        self.set_environment(Arc::new(sky));
+    }
+
+    // Shadow ray test against the BVH, up to just short of the light.
+    pub fn unoccluded(&self, p: &Point3, wi: &Vec3, distance: f64) -> bool {
+        let mut rec = HitRecord::new();
+        !self.world.hit(Ray::new(p, wi), 0.001, distance*(1.0 - 1e-4), &mut rec)
+    }
+}
@@ -0,0 +1,223 @@
+use crate::*;
+use vec3::*;
+use environment::*;
+use light::DirectionalLight;
+use blackbody::{Blackbody, LUMINOUS_EFFICACY};
+use distribution::Distribution2D;
+use std::f64::consts::PI;
+
+// Preetham et al. 1999 analytic daylight, with y up. The sun itself is not part
+// of the sky radiance; it comes from the matching DirectionalLight returned by
+// sun(), whose disk escaping rays see and weigh against sampling the light.
+pub struct PhysicalSky {
+    sun_dir: Vec3,
+    theta_s: f64,
+    zenith: Vec3,
+    perez: [[f64; 5]; 3],
+    ground: Color3,
+    sun_rgb: Color3,
+    distribution: Distribution2D,
+    intensity: f64
+}
+
+const SUN_ANGULAR_DIAMETER: f64 = 0.53;
+// Illuminance of the sun above the atmosphere, lux.
+const SOLAR_ILLUMINANCE: f64 = 128000.0;
+// Resolution of the equirectangular table the sky is sampled from.
+const TABLE_WIDTH: usize = 512;
+const TABLE_HEIGHT: usize = 256;
+
+fn perez(coeffs: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coeffs;
+    (1.0 + a*(b / cos_theta.max(0.01)).exp()) * (1.0 + c*(d*gamma).exp() + e*gamma.cos()*gamma.cos())
+}
+
+impl PhysicalSky {
+    // Elevation and azimuth of the sun in degrees (azimuth 0 along +x, 90 along +z),
+    // turbidity from 2 (very clear) to 10 (hazy), and the albedo of the ground
+    // seen below the horizon.
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, ground_albedo: Color3) -> Self {
+        let el = util::deg_to_rad(elevation);
+        let az = util::deg_to_rad(azimuth);
+        let sun_dir = Vec3::from_f64(el.cos()*az.cos(), el.sin(), el.cos()*az.sin());
+
+        let t = turbidity;
+        let theta_s = PI/2.0 - el.max(0.0);
+
+        let chi = (4.0/9.0 - t/120.0) * (PI - 2.0*theta_s);
+        let yz = ((4.0453*t - 4.9710)*chi.tan() - 0.2155*t + 2.4192).max(0.0);
+
+        let th = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+        let tt = [t*t, t, 1.0];
+        let poly = |m: [[f64; 4]; 3]| {
+            (0..3).map(|i| tt[i] * (0..4).map(|j| m[i][j]*th[j]).sum::<f64>()).sum::<f64>()
+        };
+        let xz = poly([[0.00166, -0.00375, 0.00209, 0.0],
+                        [-0.02903, 0.06377, -0.03202, 0.00394],
+                        [0.11693, -0.21196, 0.06052, 0.25886]]);
+        let yz_chroma = poly([[0.00275, -0.00610, 0.00317, 0.0],
+                            [-0.04214, 0.08970, -0.04153, 0.00516],
+                            [0.15346, -0.26756, 0.06670, 0.26688]]);
+
+        let perez = [
+            [0.1787*t - 1.4630, -0.3554*t + 0.4275, -0.0227*t + 5.3251, 0.1206*t - 2.5771, -0.0670*t + 0.3703],
+            [-0.0193*t - 0.2592, -0.0665*t + 0.0008, -0.0004*t + 0.2125, -0.0641*t - 0.8989, -0.0033*t + 0.0452],
+            [-0.0167*t - 0.2608, -0.0950*t + 0.0092, -0.0079*t + 0.2102, -0.0441*t - 1.6537, -0.0109*t + 0.0529]
+        ];
+
+        let mut sky = Self {
+            sun_dir,
+            theta_s,
+            zenith: Vec3::from_f64(yz, xz, yz_chroma),
+            perez,
+            ground: Vec3::new(),
+            sun_rgb: PhysicalSky::sun_color(theta_s, t),
+            distribution: Distribution2D::new(&[], 0, 0),
+            intensity: 1.0
+        };
+
+        // Light reaching the ground: the sun on a horizontal plane plus a rough
+        // estimate of the sky dome from its zenith radiance.
+        let irradiance = sky.sun_rgb * f64::max(el.sin(), 0.0) + PI*sky.sky_radiance(&Vec3::from_f64(0.0, 1.0, 0.0));
+        sky.ground = ground_albedo * irradiance / PI;
+
+        // Tabulated like an environment map, so directions follow the luminance
+        // of the sky and ground, circumsolar peak included.
+        let mut weights = Vec::with_capacity(TABLE_WIDTH*TABLE_HEIGHT);
+        for j in 0..TABLE_HEIGHT {
+            let v = (j as f64 + 0.5) / TABLE_HEIGHT as f64;
+            let sin_theta = (v * PI).sin();
+            for i in 0..TABLE_WIDTH {
+                let dir = uv_to_direction((i as f64 + 0.5) / TABLE_WIDTH as f64, v, 0.0);
+                weights.push(spectrum::luminance(&sky.radiance(&dir)) * sin_theta);
+            }
+        }
+        sky.distribution = Distribution2D::new(&weights, TABLE_WIDTH, TABLE_HEIGHT);
+        sky
+    }
+
+    // Scales sky and sun together.
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    // Sun irradiance at the ground: extraterrestrial sunlight attenuated by
+    // Rayleigh and aerosol extinction along the optical air mass.
+    fn sun_color(theta_s: f64, turbidity: f64) -> Color3 {
+        let deg = theta_s.to_degrees();
+        if deg >= 93.0 {
+            return Vec3::new();
+        }
+
+        let air_mass = 1.0 / (theta_s.cos() + 0.15*(93.885 - deg).powf(-1.253));
+        let beta = 0.04608*turbidity - 0.04586;
+        let sun = Blackbody::new(5778.0, SOLAR_ILLUMINANCE);
+
+        let xyz = spectrum::integrate_xyz(|l| {
+            let um = l*1e-3;
+            let tau = 0.008735*um.powf(-4.08) + beta*um.powf(-1.3);
+            sun.value(l) * (-tau*air_mass).exp()
+        });
+
+        spectrum::xyz_to_film_rgb(&xyz)
+    }
+
+    fn sky_radiance(&self, dir: &Vec3) -> Color3 {
+        let cos_theta = dir.y().max(0.0);
+        let cos_gamma = Vec3::dot(dir, &self.sun_dir).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+
+        let rel = |i: usize| perez(&self.perez[i], cos_theta, gamma) / perez(&self.perez[i], 1.0, self.theta_s);
+
+        // Yxy in kcd/m^2, converted to XYZ in the film's radiometric units.
+        let y = self.zenith.x() * rel(0);
+        let cx = self.zenith.y() * rel(1);
+        let cy = self.zenith.z() * rel(2);
+        if cy <= 0.0 || y <= 0.0 {
+            return Vec3::new();
+        }
+
+        let scale = 1000.0 / LUMINOUS_EFFICACY;
+        let xyz = Vec3::from_f64(cx / cy * y, y, (1.0 - cx - cy) / cy * y) * scale;
+
+        let rgb = spectrum::xyz_to_film_rgb(&xyz);
+        Vec3::from_f64(rgb.x().max(0.0), rgb.y().max(0.0), rgb.z().max(0.0))
+    }
+
+    pub fn sun(&self) -> DirectionalLight {
+        DirectionalLight::new(-self.sun_dir, self.sun_rgb * self.intensity, SUN_ANGULAR_DIAMETER)
+    }
+}
+
+impl Environment for PhysicalSky {
+    fn radiance(&self, dir: &Vec3) -> Color3 {
+        let dir = Vec3::unit(dir);
+        if dir.y() < 0.0 {
+            return self.ground * self.intensity;
+        }
+
+        self.sky_radiance(&dir) * self.intensity
+    }
+
+    fn sample(&self) -> (Vec3, f64) {
+        let (u1, u2) = util::random_2d();
+        let ((u, v), pdf) = self.distribution.sample(u1, u2);
+
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return (Vec3::from_f64(0.0, 1.0, 0.0), 0.0);
+        }
+
+        (uv_to_direction(u, v, 0.0), pdf / (2.0*PI*PI*sin_theta))
+    }
+
+    fn pdf(&self, dir: &Vec3) -> f64 {
+        let (u, v) = direction_to_uv(dir, 0.0);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        self.distribution.pdf(u, v) / (2.0*PI*PI*sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdf_integrates_to_one_and_favours_the_sun() {
+        let sky = PhysicalSky::new(35.0, 60.0, 3.0, Color3::from_f64(0.3, 0.3, 0.3));
+
+        //Midpoint rule on a grid twice as fine as the table
+        let (nu, nv) = (2*TABLE_WIDTH, 2*TABLE_HEIGHT);
+        let mut total = 0.0;
+        for j in 0..nv {
+            let v = (j as f64 + 0.5) / nv as f64;
+            for i in 0..nu {
+                let dir = uv_to_direction((i as f64 + 0.5) / nu as f64, v, 0.0);
+                total += sky.pdf(&dir) * (v * PI).sin();
+            }
+        }
+        total *= 2.0*PI*PI / (nu*nv) as f64;
+        assert!((total - 1.0).abs() < 1e-3, "{}", total);
+
+        //Against the same elevation on the far side of the sky
+        let away = Vec3::from_f64(-sky.sun_dir.x(), sky.sun_dir.y(), -sky.sun_dir.z());
+        assert!(sky.pdf(&sky.sun_dir) > 4.0 * sky.pdf(&away), "{} vs {}", sky.pdf(&sky.sun_dir), sky.pdf(&away));
+    }
+
+    #[test]
+    fn sampled_directions_match_pdf() {
+        let sky = PhysicalSky::new(20.0, -30.0, 5.0, Color3::from_f64(0.2, 0.2, 0.2));
+
+        for _ in 0..2000 {
+            let (dir, pdf) = sky.sample();
+            assert!(pdf > 0.0);
+            assert!((dir.length() - 1.0).abs() < 1e-9);
+            assert!((pdf - sky.pdf(&dir)).abs() < 1e-6 * pdf, "{} vs {}", pdf, sky.pdf(&dir));
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::vec3::*;
+use std::sync::OnceLock;
+
+// Spectral mode reuses Color3: each of the three channels carries radiance at
+// one wavelength. The first is the hero wavelength, the other two are spread
+// evenly across the visible range from it (Wilkie et al. 2014).
+
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+
+pub fn sample_wavelengths(u: f64) -> [f64; 3] {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let hero = LAMBDA_MIN + u*range;
+
+    let mut out = [hero; 3];
+    for (i, l) in out.iter_mut().enumerate().skip(1) {
+        *l = hero + range*(i as f64)/3.0;
+        if *l > LAMBDA_MAX {
+            *l -= range;
+        }
+    }
+
+    out
+}
+
+fn lobe(x: f64, mu: f64, sigma_lo: f64, sigma_hi: f64) -> f64 {
+    let t = (x - mu) / if x < mu { sigma_lo } else { sigma_hi };
+    (-0.5*t*t).exp()
+}
+
+// CIE 1931 2 degree color matching functions, multi-lobe fit by Wyman et al. 2013.
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let x = 1.056*lobe(lambda, 599.8, 37.9, 31.0) + 0.362*lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065*lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821*lobe(lambda, 568.8, 46.9, 40.5) + 0.286*lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217*lobe(lambda, 437.0, 11.8, 36.0) + 0.681*lobe(lambda, 459.0, 26.0, 13.8);
+
+    Vec3::from_f64(x, y, z)
+}
+
+pub fn xyz_to_linear_srgb(xyz: &Vec3) -> Color3 {
+    Vec3::from_f64(3.2404542*xyz.x() - 1.5371385*xyz.y() - 0.4985314*xyz.z(),
+                    -0.9692660*xyz.x() + 1.8760108*xyz.y() + 0.0415560*xyz.z(),
+                    0.0556434*xyz.x() - 0.2040259*xyz.y() + 1.0572252*xyz.z())
+}
+
+// Relative luminance of a linear sRGB color.
+pub fn luminance(rgb: &Color3) -> f64 {
+    0.2126*rgb.x() + 0.7152*rgb.y() + 0.0722*rgb.z()
+}
+
+fn smoothstep(e0: f64, e1: f64, x: f64) -> f64 {
+    let t = ((x - e0) / (e1 - e0)).clamp(0.0, 1.0);
+    t*t*(3.0 - 2.0*t)
+}
+
+// Upsamples an RGB value to the given wavelengths with three smooth basis
+// spectra that sum to one everywhere. White stays flat and values in [0, 1]
+// stay in [0, 1], so reflectances remain energy-conserving.
+pub fn upsample(rgb: &Color3, lambdas: &[f64; 3]) -> Vec3 {
+    let mut out = Vec3::new();
+    for (i, &l) in lambdas.iter().enumerate() {
+        let b = 1.0 - smoothstep(475.0, 515.0, l);
+        let r = smoothstep(565.0, 605.0, l);
+        let g = 1.0 - b - r;
+
+        out[i] = r*rgb.x() + g*rgb.y() + b*rgb.z();
+    }
+
+    out
+}
+
+// RGB of the constant unit spectrum, used to white balance the film so an
+// upsampled white comes back as white.
+fn white_rgb() -> Color3 {
+    static WHITE: OnceLock<Color3> = OnceLock::new();
+    *WHITE.get_or_init(|| xyz_to_linear_srgb(&integrate_xyz(|_| 1.0)))
+}
+
+// RGB as seen by the film, which is balanced to the equal-energy white: a
+// constant spectrum of 1 W/(sr m^2 nm) comes out as (1, 1, 1).
+pub fn xyz_to_film_rgb(xyz: &Vec3) -> Color3 {
+    let rgb = xyz_to_linear_srgb(xyz);
+    let white = white_rgb();
+    Vec3::from_f64(rgb.x()/white.x(), rgb.y()/white.y(), rgb.z()/white.z())
+}
+
+// Integrates a spectral distribution against the color matching functions.
+pub fn integrate_xyz(f: impl Fn(f64) -> f64) -> Vec3 {
+    let mut xyz = Vec3::new();
+    let steps = 4000;
+    let dl = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+    for i in 0..steps {
+        let l = LAMBDA_MIN + (i as f64 + 0.5)*dl;
+        xyz += cie_xyz(l) * (f(l) * dl);
+    }
+
+    xyz
+}
+
+// Converts the radiance carried at the sampled wavelengths into an RGB
+// estimate for the film, going through CIE XYZ.
+pub fn to_rgb(values: &Vec3, lambdas: &[f64; 3]) -> Color3 {
+    let inv_pdf = LAMBDA_MAX - LAMBDA_MIN;
+
+    let mut xyz = Vec3::new();
+    for (i, &l) in lambdas.iter().enumerate() {
+        xyz += cie_xyz(l) * (values[i] * inv_pdf / 3.0);
+    }
+
+    xyz_to_film_rgb(&xyz)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn grey_round_trip() {
+        let grey = Vec3::from_f64(0.5, 0.5, 0.5);
+        let n = 20000;
+        let mut sum = Vec3::new();
+
+        for _ in 0..n {
+            let lambdas = sample_wavelengths(util::random_double());
+            sum += to_rgb(&upsample(&grey, &lambdas), &lambdas);
+        }
+
+        let avg = sum / n as f64;
+        assert!((avg - grey).length() < 0.02);
+    }
+
+    #[test]
+    fn wavelengths_stay_in_range() {
+        for &u in &[0.0, 0.3, 0.999] {
+            for l in sample_wavelengths(u) {
+                assert!((LAMBDA_MIN..=LAMBDA_MAX).contains(&l));
+            }
+        }
+    }
+}
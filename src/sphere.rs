@@ -3,6 +3,7 @@ use hittable::*;
 use material::Material;
 use std::sync::Arc;
 use aabb::*;
+use std::f64::consts::PI;
 
 pub struct Sphere {
     center: Vec3,
@@ -20,6 +21,13 @@ impl Sphere {
     }
 }
 
+// Texture coordinates of a point on the unit sphere.
+fn sphere_uv(p: &Vec3) -> (f64, f64) {
+    let theta = f64::acos(-p.y());
+    let phi = f64::atan2(-p.z(), p.x()) + PI;
+    (phi / (2.0*PI), theta / PI)
+}
+
 impl Hittable for Sphere {
     fn hit(&self, ray: Ray, min: f64, max: f64, hit_record: &mut HitRecord) -> bool {
         let a = Vec3::dot(&ray.direction(), &ray.direction());
@@ -33,24 +41,35 @@ impl Hittable for Sphere {
         }
 
         let s_disc = disc.sqrt();
-        let mut root = (-b - s_disc) / (2.0*a);
-
-        if root < min || root > max {
-            root = (-b + s_disc) / (2.0*a);
 
+        //The far root is still visible through a cut out near side. Without a
+        //cutout the first root in range is the hit.
+        let cutout = self.material.has_cutout();
+        for root in [(-b - s_disc) / (2.0*a), (-b + s_disc) / (2.0*a)] {
             if root < min || root > max {
-                return false;
+                continue;
             }
-        }
 
-        hit_record.t = root;
-        hit_record.p = ray.at(root);
-        hit_record.material = self.material.clone();
+            let p = ray.at(root);
+            let outward_normal = (p - self.center) / self.radius;
+            let (u, v) = sphere_uv(&outward_normal);
+            if cutout && !accept_hit(self.material.as_ref(), u, v, &p) {
+                continue;
+            }
 
-        let outward_normal = (hit_record.p - self.center) / self.radius;
-        hit_record.set_face_normal(&ray, &outward_normal);
+            hit_record.t = root;
+            hit_record.p = p;
+            hit_record.material = self.material.clone();
+            hit_record.area_light = None;
+            hit_record.wavelengths = ray.wavelengths();
+            hit_record.set_face_normal(&ray, &outward_normal);
+            hit_record.u = u;
+            hit_record.v = v;
+            hit_record.tangent = Vec3::from_f64(outward_normal.z(), 0.0, -outward_normal.x());
+            return true;
+        }
 
-        return true;
+        false
     }
 
     fn bounding_box(&self) -> Option<AABB> {
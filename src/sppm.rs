@@ -0,0 +1,489 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use hittable::*;
+use scene::Scene;
+use camera::Camera;
+use light::Light;
+use onb::ONB;
+use aabb::AABB;
+use distribution::Distribution1D;
+use film::AtomicSum;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::f64::consts::PI;
+
+// Stochastic progressive photon mapping (Hachisuka and Jensen 2009, following
+// pbrt-v3). Every iteration follows one camera path per pixel through specular
+// bounces to a visible point, shoots photons from the lights and gathers those
+// landing within each pixel's radius. The radii shrink as photons accumulate,
+// so the estimate converges, and caustics come out as easily as diffuse light.
+//
+// Photons carry RGB, so the integrator ignores --spectral, and paths end where
+// they would enter a participating medium.
+
+struct VisiblePoint {
+    rec: HitRecord,
+    wo: Vec3,
+    beta: Color3
+}
+
+struct Pixel {
+    radius: f64,
+    // Emission and direct lighting summed over iterations
+    ld: Color3,
+    vp: Option<VisiblePoint>,
+    // Photon flux gathered in the current iteration
+    phi: [AtomicSum; 3],
+    m: AtomicU64,
+    n: f64,
+    tau: Color3
+}
+
+impl Pixel {
+    fn new(radius: f64) -> Self {
+        Self {
+            radius,
+            ld: Vec3::new(),
+            vp: None,
+            phi: Default::default(),
+            m: AtomicU64::new(0),
+            n: 0.0,
+            tau: Vec3::new()
+        }
+    }
+}
+
+// Uniform grid over the visible points, hashed so only occupied cells take memory.
+struct Grid {
+    min: Point3,
+    cell_size: f64,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>
+}
+
+impl Grid {
+    fn new(pixels: &[Pixel]) -> Self {
+        let mut bounds: Option<AABB> = None;
+        let mut max_radius: f64 = 0.0;
+        for px in pixels {
+            if let Some(vp) = &px.vp {
+                let r = Vec3::from_f64(px.radius, px.radius, px.radius);
+                let b = Some(AABB::new(vp.rec.p - r, vp.rec.p + r));
+                bounds = if bounds.is_some() { AABB::union(bounds, b) } else { b };
+                max_radius = max_radius.max(px.radius);
+            }
+        }
+
+        let mut grid = Self {
+            min: bounds.map(|b| b.min()).unwrap_or_else(Vec3::new),
+            cell_size: 2.0*max_radius,
+            cells: HashMap::new()
+        };
+
+        for (i, px) in pixels.iter().enumerate() {
+            if let Some(vp) = &px.vp {
+                let r = Vec3::from_f64(px.radius, px.radius, px.radius);
+                let (lo, hi) = (grid.cell(&(vp.rec.p - r)), grid.cell(&(vp.rec.p + r)));
+                for x in lo.0..=hi.0 {
+                    for y in lo.1..=hi.1 {
+                        for z in lo.2..=hi.2 {
+                            grid.cells.entry((x, y, z)).or_default().push(i);
+                        }
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+
+    fn cell(&self, p: &Point3) -> (i64, i64, i64) {
+        let q = (*p - self.min) / self.cell_size;
+        (q.x().floor() as i64, q.y().floor() as i64, q.z().floor() as i64)
+    }
+}
+
+// Tags of the random number streams for camera paths and photons.
+const CAMERA_STREAM: u64 = 1;
+const PHOTON_STREAM: u64 = 2;
+
+#[derive(Clone)]
+enum Source {
+    Light(Arc<dyn Light>),
+    Environment
+}
+
+pub struct SppmIntegrator {
+    pub photons: usize,
+    pub initial_radius: f64,
+    pub max_depth: usize,
+    pub iterations: usize,
+    pub time_limit: Option<Duration>,
+    // Fraction of new photons kept when shrinking the radius
+    pub alpha: f64,
+    sources: Vec<Source>,
+    // Integral of the environment radiance over the sphere
+    environment_power: Color3,
+    world_radius: f64
+}
+
+impl SppmIntegrator {
+    pub fn new(scene: &Scene, photons: usize, initial_radius: f64) -> Self {
+        let mut sources: Vec<Source> = scene.lights.lights().iter().map(|l| Source::Light(l.clone())).collect();
+        sources.push(Source::Environment);
+
+        let samples = 256;
+        let mut environment_power = Vec3::new();
+        for _ in 0..samples {
+            let (wi, pdf) = scene.environment.sample();
+            if pdf > 0.0 {
+                environment_power += scene.environment.radiance(&wi) / (pdf * samples as f64);
+            }
+        }
+
+        let world_radius = scene.world.bounding_box().map(|b| (b.max() - b.min()).length()).unwrap_or(1.0);
+
+        Self {
+            photons,
+            initial_radius,
+            max_depth: 10,
+            iterations: 64,
+            time_limit: None,
+            alpha: 2.0/3.0,
+            sources,
+            environment_power,
+            world_radius
+        }
+    }
+
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn with_iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    pub fn with_time_limit(mut self, limit: Option<Duration>) -> Self {
+        self.time_limit = limit;
+        self
+    }
+
+    // Next event estimation without MIS. Photons only count from their second
+    // vertex on, so direct lighting at visible points comes entirely from here.
+    fn direct(r: &Ray, rec: &HitRecord, scene: &Scene) -> Color3 {
+        let wo = -Vec3::unit(&r.direction());
+        let mut direct = Vec3::new();
+
+        if let Some((light, pmf)) = scene.lights.sample(&rec.p, util::random_double()) {
+            if let Some(ls) = light.sample_li(&rec.p, None) {
+                let f = rec.material.eval(&wo, &ls.wi, rec);
+                if !f.near_zero() && scene.unoccluded(&rec.p, &ls.wi, ls.distance) {
+                    direct += f * ls.li / (pmf * ls.pdf);
+                }
+            }
+        }
+
+        let (wi, pdf) = scene.environment.sample();
+        let f = rec.material.eval(&wo, &wi, rec);
+        if pdf > 0.0 && !f.near_zero() && scene.unoccluded(&rec.p, &wi, f64::INFINITY) {
+            direct += f * scene.environment.radiance(&wi) / pdf;
+        }
+
+        direct
+    }
+
+    // Follows a camera ray through specular bounces to the first surface that
+    // scatters diffusely or glossily, collecting what it sees on the way.
+    fn visible_point(&self, mut r: Ray, scene: &Scene) -> (Color3, Option<VisiblePoint>) {
+        let mut beta = Vec3::from_f64(1.0, 1.0, 1.0);
+        let mut ld = Vec3::new();
+
+        for _ in 0..self.max_depth {
+            let mut rec = HitRecord::new();
+            if !scene.world.hit(r, 0.001, f64::INFINITY, &mut rec) {
+                let dir = Vec3::unit(&r.direction());
+                ld += beta * scene.environment.radiance(&dir);
+                for light in scene.lights.infinite() {
+                    ld += beta * light.le(&dir, None);
+                }
+                break;
+            }
+
+            let mat = rec.material.clone();
+            ld += beta * mat.emitted(&rec);
+
+            let mut attenuation = Vec3::new();
+            let mut scattered = Ray::new(&Vec3::new(), &Vec3::new());
+            let Some(lobe) = mat.scatter(&r, &rec, &mut attenuation, &mut scattered) else {
+                break;
+            };
+
+            //Materials mixing delta and non-delta lobes are classified by the
+            //lobe scatter() happened to pick
+            let wo = -Vec3::unit(&r.direction());
+            let wi = Vec3::unit(&scattered.direction());
+            if !lobe.is_specular() {
+                ld += beta * SppmIntegrator::direct(&r, &rec, scene);
+                return (ld, Some(VisiblePoint { rec, wo, beta }));
+            }
+
+            if Vec3::dot(&wi, &rec.geo_normal) < 0.0 && rec.front_face && mat.interior().is_some() {
+                break;
+            }
+            beta *= attenuation;
+            r = scattered;
+        }
+
+        (ld, None)
+    }
+
+    // Photon power of each source. Lights at infinity illuminate the disk
+    // (center, radius), which covers the visible points.
+    fn source_power(&self, source: &Source, center: &Point3, radius: f64) -> f64 {
+        let area = PI*radius*radius;
+        match source {
+            Source::Light(light) => match light.bounds() {
+                Some(b) => b.phi,
+                None => light.sample_li(center, None).map(|ls| spectrum::luminance(&(ls.li / ls.pdf)) * area).unwrap_or(0.0)
+            },
+            Source::Environment => spectrum::luminance(&self.environment_power) * area
+        }
+    }
+
+    // A photon ray and its power, before dividing by the source's probability.
+    fn emit(&self, scene: &Scene, source: &Source, center: &Point3, radius: f64) -> Option<(Ray, Color3)> {
+        let (wi, power) = match source {
+            Source::Light(light) if light.bounds().is_some() => {
+                let le = light.sample_le(None)?;
+                if le.pdf_pos <= 0.0 || le.pdf_dir <= 0.0 {
+                    return None;
+                }
+                let cos = if le.n.near_zero() { 1.0 } else { Vec3::dot(&le.n, &le.dir).abs() };
+                return Some((Ray::new(&le.p, &le.dir), le.le * (cos / (le.pdf_pos * le.pdf_dir))));
+            },
+            Source::Light(light) => {
+                let ls = light.sample_li(center, None)?;
+                (ls.wi, ls.li / ls.pdf)
+            },
+            Source::Environment => {
+                let (wi, pdf) = scene.environment.sample();
+                if pdf <= 0.0 {
+                    return None;
+                }
+                (wi, scene.environment.radiance(&wi) / pdf)
+            }
+        };
+
+        //Parallel rays through a disk facing the light, started outside the scene
+        let d = radius*util::random_unit_disk();
+        let frame = ONB::from_w(&wi);
+        let origin = *center + wi*self.world_radius + frame.local_to_world(&Vec3::from_f64(d.x(), d.y(), 0.0));
+        Some((Ray::new(&origin, &-wi), power * (PI*radius*radius)))
+    }
+
+    fn deposit(&self, grid: &Grid, pixels: &[Pixel], p: &Point3, wi: &Vec3, beta: &Color3) {
+        let list = match grid.cells.get(&grid.cell(p)) {
+            Some(list) => list,
+            None => return
+        };
+
+        for &i in list {
+            let px = &pixels[i];
+            let vp = px.vp.as_ref().unwrap();
+            if (vp.rec.p - *p).length_squared() > px.radius*px.radius {
+                continue;
+            }
+
+            //eval() includes the cosine, which the density estimate already accounts for
+            let cos = Vec3::dot(wi, &vp.rec.normal).abs();
+            if cos < 1e-8 {
+                continue;
+            }
+            let phi = *beta * vp.rec.material.eval(&vp.wo, wi, &vp.rec) / cos;
+            for c in 0..3 {
+                px.phi[c].add(phi[c]);
+            }
+            px.m.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn trace_photon(&self, scene: &Scene, grid: &Grid, pixels: &[Pixel], mut r: Ray, mut beta: Color3) {
+        for depth in 0..self.max_depth {
+            let mut rec = HitRecord::new();
+            if !scene.world.hit(r, 0.001, f64::INFINITY, &mut rec) {
+                break;
+            }
+
+            //The first hit is direct lighting, which visible points sample themselves
+            let wi = -Vec3::unit(&r.direction());
+            if depth > 0 {
+                self.deposit(grid, pixels, &rec.p, &wi, &beta);
+            }
+
+            let mat = rec.material.clone();
+            let mut attenuation = Vec3::new();
+            let mut scattered = Ray::new(&Vec3::new(), &Vec3::new());
+            if mat.scatter(&r, &rec, &mut attenuation, &mut scattered).is_none() {
+                break;
+            }
+            if Vec3::dot(&scattered.direction(), &rec.geo_normal) < 0.0 && rec.front_face && mat.interior().is_some() {
+                break;
+            }
+
+            //Russian roulette keeps photon power roughly constant
+            let mut next = beta * attenuation;
+            let q = f64::max(0.0, 1.0 - spectrum::luminance(&next) / spectrum::luminance(&beta));
+            if q > 0.0 {
+                if util::random_double() < q {
+                    break;
+                }
+                next /= 1.0 - q;
+            }
+
+            beta = next;
+            r = scattered;
+        }
+    }
+
+    // Renders the whole image and returns the radiance of each pixel, laid out
+    // like main()'s cells. on_iteration gets the number of finished iterations.
+    pub fn render(&self, scene: &Scene, camera: &Camera, width: u32, height: u32, on_iteration: impl Fn(usize)) -> Vec<Color3> {
+        let mut pixels: Vec<Pixel> = (0..width*height).map(|_| Pixel::new(self.initial_radius)).collect();
+        let start = Instant::now();
+        let mut iterations = 0;
+
+        loop {
+            pixels.par_iter_mut().enumerate().for_each(|(i, px)| {
+                sampler::scoped(sampler::stream(CAMERA_STREAM, i as u32, iterations as u32), || {
+                    let x = (i as u32 % width) as f64;
+                    let y = (i as u32 / width) as f64;
+                    let (dx, dy) = util::random_2d();
+                    let u = (x + dx) / (width - 1) as f64;
+                    let v = (y + dy) / (height - 1) as f64;
+
+                    let (ld, vp) = self.visible_point(camera.get_ray(u, v), scene);
+                    px.ld += ld;
+                    px.vp = vp;
+                });
+            });
+
+            let grid = Grid::new(&pixels);
+
+            //Lights at infinity only need to cover what the camera sees
+            let visible: Vec<Point3> = pixels.iter().filter_map(|px| px.vp.as_ref().map(|vp| vp.rec.p)).collect();
+            let center = visible.iter().fold(Vec3::new(), |a, p| a + *p) / visible.len().max(1) as f64;
+            let radius = 2.0*visible.iter().map(|p| (*p - center).length()).fold(0.0, f64::max);
+
+            let weights: Vec<f64> = self.sources.iter().map(|s| self.source_power(s, &center, radius)).collect();
+            if weights.iter().sum::<f64>() > 0.0 && radius > 0.0 {
+                let choice = Distribution1D::new(&weights);
+                (0..self.photons).into_par_iter().for_each(|photon| {
+                    sampler::scoped(sampler::stream(PHOTON_STREAM, photon as u32, iterations as u32), || {
+                        let (_, _, i) = choice.sample(util::random_double());
+                        let pmf = choice.pdf_at(i) / choice.count() as f64;
+                        if let Some((r, power)) = self.emit(scene, &self.sources[i], &center, radius) {
+                            self.trace_photon(scene, &grid, &pixels, r, power / pmf);
+                        }
+                    });
+                });
+            }
+
+            //Shrink each radius so only a fraction alpha of the new photons count
+            pixels.par_iter_mut().for_each(|px| {
+                let m = px.m.swap(0, Ordering::Relaxed) as f64;
+                let phi = Vec3::from_f64(px.phi[0].take(), px.phi[1].take(), px.phi[2].take());
+                let vp = match px.vp.take() {
+                    Some(vp) if m > 0.0 => vp,
+                    _ => return
+                };
+
+                let n = px.n + self.alpha*m;
+                let radius = px.radius * (n / (px.n + m)).sqrt();
+                px.tau = (px.tau + vp.beta*phi) * (radius*radius / (px.radius*px.radius));
+                px.n = n;
+                px.radius = radius;
+            });
+
+            iterations += 1;
+            on_iteration(iterations);
+            if iterations >= self.iterations || self.time_limit.is_some_and(|t| start.elapsed() >= t) {
+                break;
+            }
+        }
+
+        let photons = (iterations * self.photons) as f64;
+        pixels.iter().map(|px| {
+            px.ld / iterations as f64 + px.tau / (photons*PI*px.radius*px.radius)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hittable_list::HittableList;
+    use triangle::Triangle;
+    use lambertian::Lambertian;
+    use dielectric::Dieletric;
+    use material::Material;
+    use light::PointLight;
+    use environment::Gradient;
+
+    fn pixel(p: Point3, radius: f64) -> Pixel {
+        let mut rec = HitRecord::new();
+        rec.p = p;
+        let mut px = Pixel::new(radius);
+        px.vp = Some(VisiblePoint { rec, wo: Vec3::from_f64(0.0, 1.0, 0.0), beta: Vec3::from_f64(1.0, 1.0, 1.0) });
+        px
+    }
+
+    #[test]
+    fn grid_finds_every_visible_point_within_its_radius() {
+        let mut pixels = vec![pixel(Vec3::from_f64(0.0, 0.0, 0.0), 0.5), pixel(Vec3::from_f64(3.0, -1.0, 2.0), 0.25), pixel(Vec3::from_f64(3.2, -1.0, 2.0), 0.1)];
+        pixels.push(Pixel::new(1.0));
+        let grid = Grid::new(&pixels);
+
+        for _ in 0..1000 {
+            let q = Vec3::from_f64(5.0*util::random_double() - 1.0, 2.0*util::random_double() - 2.0, 4.0*util::random_double() - 1.0);
+            let found = grid.cells.get(&grid.cell(&q)).cloned().unwrap_or_default();
+            for (i, px) in pixels.iter().enumerate() {
+                let inside = px.vp.as_ref().is_some_and(|vp| (vp.rec.p - q).length() <= px.radius);
+                assert!(!inside || found.contains(&i), "{} missing at {:?}", i, q);
+            }
+        }
+    }
+
+    fn plane(world: &mut HittableList, y: f64, mat: Arc<dyn Material>) {
+        let (a, b, c, d) = (Vec3::from_f64(-10.0, y, -10.0), Vec3::from_f64(10.0, y, -10.0), Vec3::from_f64(10.0, y, 10.0), Vec3::from_f64(-10.0, y, 10.0));
+        world.add(Arc::new(Triangle::new(a, b, c, mat.clone())));
+        world.add(Arc::new(Triangle::new(a, c, d, mat)));
+    }
+
+    #[test]
+    fn diffuse_floor_converges_to_the_photon_irradiance() {
+        //An index-matched pane between the light and the floor blocks shadow
+        //rays but lets photons through, so the floor is lit by photons alone
+        let (albedo, intensity) = (0.5, 4.0);
+        let mut world = HittableList::new();
+        plane(&mut world, 0.0, Arc::new(Lambertian::new(Vec3::from_f64(albedo, albedo, albedo))));
+        plane(&mut world, 0.5, Arc::new(Dieletric::new(1.0)));
+
+        let mut scene = Scene::new(bvh::BVH::new(world));
+        scene.set_environment(Arc::new(Gradient::new(Vec3::new(), Vec3::new())));
+        scene.add_light(Arc::new(PointLight::new(Vec3::from_f64(0.0, 1.0, 0.0), Vec3::from_f64(intensity, intensity, intensity))));
+
+        let camera = Camera::new(Vec3::from_f64(0.0, 0.3, 0.0), Vec3::new(), Vec3::from_f64(0.0, 0.0, 1.0), 1.0, 5.0, 0.0, 0.3);
+        let sppm = SppmIntegrator::new(&scene, 100_000, 0.05).with_iterations(24);
+        let image = sppm.render(&scene, &camera, 4, 4, |_| {});
+
+        let mean = image.iter().map(|c| c.y()).sum::<f64>() / image.len() as f64;
+        let expected = albedo / PI * intensity;
+        assert!((mean - expected).abs() < 0.05*expected, "{} vs {}", mean, expected);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::*;
+use vec3::*;
+use ray::*;
+use material::{Material, Lobe};
+use medium::Medium;
+use rough_dielectric::RoughDielectric;
+
+// Skin, wax, marble: a dielectric boundary around a scattering medium. The
+// surface itself only reflects or refracts; once a ray is inside, the
+// integrator random-walks it through the medium against the scene geometry
+// until it leaves through the boundary again. Needs closed geometry.
+pub struct Subsurface {
+    ir: f64,
+    boundary: RoughDielectric,
+    medium: Medium
+}
+
+impl Subsurface {
+    // color is the overall albedo the walk converges to, mean_free_path is the
+    // average distance light travels inside before scattering, per channel.
+    pub fn new(ir: f64, color: Color3, mean_free_path: Color3) -> Self {
+        Self {
+            ir,
+            boundary: RoughDielectric::new(ir, 0.0),
+            medium: Medium::from_color(color, mean_free_path, 0.0)
+        }
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Self {
+        self.boundary = RoughDielectric::new(self.ir, roughness);
+        self
+    }
+
+    pub fn with_medium(mut self, medium: Medium) -> Self {
+        self.medium = medium;
+        self
+    }
+}
+
+impl Material for Subsurface {
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> Option<Lobe> {
+        self.boundary.scatter(ray, rec, attenuation, scattered)
+    }
+
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color3 {
+        self.boundary.eval(wo, wi, rec)
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        self.boundary.pdf(wo, wi, rec)
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        Some(self.medium)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use coated::Coated;
+    use cutout::Cutout;
+    use mix::MixMaterial;
+    use normal_map::{NormalMap, BumpMap};
+    use lambertian::Lambertian;
+
+    #[test]
+    fn wrapped_subsurface_keeps_its_medium() {
+        let skin = || Subsurface::new(1.4, Vec3::from_f64(0.8, 0.5, 0.4), Vec3::from_f64(1.0, 0.4, 0.2)).with_roughness(0.3);
+        let expected = skin().interior().unwrap().transmittance(0.5);
+
+        let wrapped: Vec<Box<dyn Material>> = vec![
+            Box::new(Coated::new(skin(), 1.5)),
+            Box::new(Cutout::new(Arc::new(skin()), texture::constant(1.0))),
+            Box::new(MixMaterial::new(Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5))), Arc::new(skin()), texture::constant(0.5))),
+            Box::new(NormalMap::new(Arc::new(skin()), texture::solid(Vec3::from_f64(0.5, 0.5, 1.0)))),
+            Box::new(BumpMap::new(Arc::new(skin()), texture::constant(0.0), 1.0))
+        ];
+        for m in wrapped {
+            let t = m.interior().expect("wrapper dropped the medium").transmittance(0.5);
+            assert_eq!((t.x(), t.y(), t.z()), (expected.x(), expected.y(), expected.z()));
+        }
+    }
+}
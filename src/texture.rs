@@ -0,0 +1,137 @@
+use crate::vec3::*;
+use std::sync::Arc;
+
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f64, v: f64, p: &Point3) -> Color3;
+}
+
+pub struct SolidColor {
+    color: Color3
+}
+
+impl SolidColor {
+    pub fn new(color: Color3) -> Self {
+        Self {
+            color
+        }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f64, _v: f64, _p: &Point3) -> Color3 {
+        self.color
+    }
+}
+
+pub fn solid(color: Color3) -> Arc<dyn Texture> {
+    Arc::new(SolidColor::new(color))
+}
+
+// Scalar parameters are read from the first channel of a texture.
+pub fn constant(value: f64) -> Arc<dyn Texture> {
+    solid(Vec3::from_f64(value, value, value))
+}
+
+// Alternates between two textures in squares of 1/scale in UV space, e.g. a
+// mask for mixing, cutting out or bumping a surface.
+pub struct Checker {
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>,
+    scale: f64
+}
+
+impl Checker {
+    pub fn new(even: Arc<dyn Texture>, odd: Arc<dyn Texture>, scale: f64) -> Self {
+        Self {
+            even,
+            odd,
+            scale
+        }
+    }
+}
+
+impl Texture for Checker {
+    fn value(&self, u: f64, v: f64, p: &Point3) -> Color3 {
+        let square = (u*self.scale).floor() as i64 + (v*self.scale).floor() as i64;
+        if square.rem_euclid(2) == 0 { self.even.value(u, v, p) } else { self.odd.value(u, v, p) }
+    }
+}
+
+pub struct ImageTexture {
+    data: Vec<Color3>,
+    width: u32,
+    height: u32
+}
+
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+impl ImageTexture {
+    // Color images are stored in sRGB and get linearized on load.
+    pub fn new(path: &str) -> Self {
+        ImageTexture::load(path, true)
+    }
+
+    // Data images (roughness, metallic, normals...) are used as-is.
+    pub fn linear(path: &str) -> Self {
+        ImageTexture::load(path, false)
+    }
+
+    // The alpha channel broadcast to all three channels, for cutout masks.
+    pub fn alpha(path: &str) -> Self {
+        let img = image::open(path).expect("Texture load failed.").to_rgba8();
+        let (width, height) = img.dimensions();
+
+        let data = img.pixels().map(|px| {
+            let a = px[3] as f64 / 255.0;
+            Vec3::from_f64(a, a, a)
+        }).collect();
+
+        println!("Loaded alpha mask {}, {}x{}", path, width, height);
+        Self {
+            data,
+            width,
+            height
+        }
+    }
+
+    fn load(path: &str, srgb: bool) -> Self {
+        let img = image::open(path).expect("Texture load failed.").to_rgb8();
+        let (width, height) = img.dimensions();
+
+        let data = img.pixels().map(|px| {
+            let mut c = Vec3::from_f64(px[0] as f64 / 255.0, px[1] as f64 / 255.0, px[2] as f64 / 255.0);
+            if srgb {
+                for i in 0..3 {
+                    c[i] = srgb_to_linear(c[i]);
+                }
+            }
+            c
+        }).collect();
+
+        println!("Loaded texture {}, {}x{}", path, width, height);
+        Self {
+            data,
+            width,
+            height
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: &Point3) -> Color3 {
+        // Textures repeat, and image rows run top to bottom.
+        let u = u.rem_euclid(1.0);
+        let v = 1.0 - v.rem_euclid(1.0);
+
+        let i = u32::min((u * self.width as f64) as u32, self.width - 1);
+        let j = u32::min((v * self.height as f64) as u32, self.height - 1);
+
+        self.data[(j * self.width + i) as usize]
+    }
+}
@@ -0,0 +1,69 @@
+use crate::*;
+use vec3::*;
+use texture::*;
+use std::sync::Arc;
+
+// Thin transparent film (soap, oil, lens coating) on top of a specular
+// interface. Replaces the plain Fresnel term of the material it is attached to.
+#[derive(Clone)]
+pub struct ThinFilm {
+    ior: f64,
+    thickness: Arc<dyn Texture>,
+    scale: f64
+}
+
+impl ThinFilm {
+    // thickness in nm.
+    pub fn new(ior: f64, thickness: f64) -> Self {
+        Self {
+            ior,
+            thickness: constant(1.0),
+            scale: thickness
+        }
+    }
+
+    // Thickness read from the first channel of a texture and mapped from [0, 1]
+    // to [0, max_thickness] nm.
+    pub fn textured(ior: f64, thickness: Arc<dyn Texture>, max_thickness: f64) -> Self {
+        Self {
+            ior,
+            thickness,
+            scale: max_thickness
+        }
+    }
+
+    // In spectral mode the interference is resolved at the ray's wavelengths,
+    // with the substrate's RGB constants upsampled to them.
+    pub fn reflectance(&self, rec: &HitRecord, cos_i: f64, eta: &Vec3, k: &Vec3) -> Color3 {
+        let d = self.thickness.value(rec.u, rec.v, &rec.p).x().max(0.0) * self.scale;
+        match rec.wavelengths {
+            Some(l) => fresnel::thin_film(cos_i, self.ior, d, &spectrum::upsample(eta, &l), &spectrum::upsample(k, &l), &l),
+            None => fresnel::thin_film(cos_i, self.ior, d, eta, k, &fresnel::RGB_WAVELENGTHS)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spectral_reflectance_uses_the_ray_wavelengths() {
+        let film = ThinFilm::new(1.33, 400.0);
+        let (eta, k) = (Vec3::from_f64(1.5, 1.5, 1.5), Vec3::new());
+        let mut rec = HitRecord::new();
+        let rgb = film.reflectance(&rec, 0.8, &eta, &k);
+
+        //The RGB wavelengths reproduce the RGB result
+        rec.wavelengths = Some([650.0, 532.0, 450.0]);
+        let spectral = film.reflectance(&rec, 0.8, &eta, &k);
+        assert!((spectral - rgb).length() < 1e-12);
+
+        //Moving the other two wavelengths leaves the green one alone, but
+        //changes the interference the others see
+        rec.wavelengths = Some([600.0, 532.0, 500.0]);
+        let moved = film.reflectance(&rec, 0.8, &eta, &k);
+        assert_eq!(moved.y(), rgb.y());
+        assert!((moved.x() - rgb.x()).abs() > 1e-4 && (moved.z() - rgb.z()).abs() > 1e-4);
+    }
+}
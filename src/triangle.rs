@@ -4,29 +4,58 @@ use crate::hittable::*;
 use crate::material::Material;
 use std::sync::Arc;
 use crate::aabb::*;
+use crate::light::Light;
+use crate::area_light::TriangleLight;
 
 pub struct Triangle {
     v0: Vec3,
     v1: Vec3,
     v2: Vec3,
     n: Vec3,
-    material: Arc<dyn Material>
+    tangent: Vec3,
+    uv: [(f64, f64); 3],
+    material: Arc<dyn Material>,
+    area_light: Option<Arc<dyn Light>>
 }
 
 impl Triangle {
     pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Arc<dyn Material>) -> Self {
+        Triangle::with_uvs(v0, v1, v2, [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)], material)
+    }
+
+    pub fn with_uvs(v0: Vec3, v1: Vec3, v2: Vec3, uv: [(f64, f64); 3], material: Arc<dyn Material>) -> Self {
         let e10 = v1-v0;
         let e20 = v2-v0;
         let c = Vec3::cross(&e10, &e20);
 
         let n = Vec3::unit(&c);
 
+        //Direction of increasing u, falling back to an edge for degenerate uvs
+        let (du1, dv1) = (uv[1].0 - uv[0].0, uv[1].1 - uv[0].1);
+        let (du2, dv2) = (uv[2].0 - uv[0].0, uv[2].1 - uv[0].1);
+        let det = du1*dv2 - dv1*du2;
+        let tangent = if det.abs() < 1e-12 {
+            Vec3::unit(&e10)
+        } else {
+            (dv2*e10 - dv1*e20) / det
+        };
+
+        //Emissive triangles register themselves as lights
+        let area_light: Option<Arc<dyn Light>> = if material.is_emissive() && c.length() > 0.0 {
+            Some(Arc::new(TriangleLight::new([v0, v1, v2], uv, material.clone())))
+        } else {
+            None
+        };
+
         Self {
             v0,
             v1,
             v2,
             n,
-            material
+            tangent,
+            uv,
+            material,
+            area_light
         }
     }
 }
@@ -58,13 +87,13 @@ impl Hittable for Triangle {
         let e10 = self.v1 - self.v0;
         let ep0 = p - self.v0;
         let c = Vec3::cross(&e10, &ep0);
-        let a = Vec3::dot(&c, &n);
-        if a < 0.0 { return false; }
+        let a2 = Vec3::dot(&c, &n);
+        if a2 < 0.0 { return false; }
 
         let e20 = self.v2 - self.v0;
         let c = Vec3::cross(&ep0, &e20);
-        let a = Vec3::dot(&c, &n);
-        if a < 0.0 { return false; }
+        let a1 = Vec3::dot(&c, &n);
+        if a1 < 0.0 { return false; }
 
         let e21 = self.v2 - self.v1;
         let ep1 = p - self.v1;
@@ -72,16 +101,32 @@ impl Hittable for Triangle {
         let a = Vec3::dot(&c, &n);
         if a < 0.0 { return false; }
 
+        //Barycentric weights from the sub-triangle areas
+        let area = Vec3::dot(&Vec3::cross(&e10, &e20), &n);
+        let b1 = a1 / area;
+        let b2 = a2 / area;
+        let b0 = 1.0 - b1 - b2;
+
+        let u = b0*self.uv[0].0 + b1*self.uv[1].0 + b2*self.uv[2].0;
+        let v = b0*self.uv[0].1 + b1*self.uv[1].1 + b2*self.uv[2].1;
+        if !accept_hit(self.material.as_ref(), u, v, &p) {
+            return false;
+        }
+
         hit_record.p = p;
         hit_record.t = t;
+        hit_record.u = u;
+        hit_record.v = v;
         hit_record.set_face_normal(&ray, &n);
+        hit_record.tangent = self.tangent;
         hit_record.material = self.material.clone();
-
-        return true;
+        hit_record.area_light = self.area_light.clone();
+        hit_record.wavelengths = ray.wavelengths();
+        true
     }
 
     fn bounding_box(&self) -> Option<AABB> {
-        let pts = vec![self.v0, self.v1, self.v2];
+        let pts = [self.v0, self.v1, self.v2];
         let x_min = pts.iter().map(|v| v[0]).reduce(f64::min).unwrap();
         let y_min = pts.iter().map(|v| v[1]).reduce(f64::min).unwrap();
         let z_min = pts.iter().map(|v| v[2]).reduce(f64::min).unwrap();
@@ -90,6 +135,37 @@ impl Hittable for Triangle {
         let y_max = pts.iter().map(|v| v[1]).reduce(f64::max).unwrap();
         let z_max = pts.iter().map(|v| v[2]).reduce(f64::max).unwrap();
 
-        Some(AABB::new(Vec3::from_f64(x_min, y_min, z_min), Vec3::from_f64(x_max, y_max, z_max)))
+        //Axis aligned triangles would get a flat box that the slab test never hits
+        let pad = Vec3::from_f64(1e-4, 1e-4, 1e-4);
+        Some(AABB::new(Vec3::from_f64(x_min, y_min, z_min) - pad, Vec3::from_f64(x_max, y_max, z_max) + pad))
+    }
+
+    fn lights(&self) -> Vec<Arc<dyn Light>> {
+        self.area_light.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::BVH;
+    use crate::hittable_list::HittableList;
+    use crate::lambertian::Lambertian;
+
+    #[test]
+    fn axis_aligned_triangles_can_be_hit_through_the_bvh() {
+        let mat = Arc::new(Lambertian::new(Vec3::from_f64(0.5, 0.5, 0.5)));
+        let tri = Triangle::new(Vec3::from_f64(0.0, 1.0, 0.0), Vec3::from_f64(1.0, 1.0, 0.0), Vec3::from_f64(0.0, 1.0, 1.0), mat.clone());
+        let down = Ray::new(&Vec3::from_f64(0.25, 2.0, 0.25), &Vec3::from_f64(0.0, -1.0, 0.0));
+        assert!(tri.bounding_box().unwrap().hit(&down, 0.001, f64::INFINITY));
+
+        let mut world = HittableList::new();
+        world.add(Arc::new(tri));
+        world.add(Arc::new(Triangle::new(Vec3::from_f64(5.0, 1.0, 0.0), Vec3::from_f64(6.0, 1.0, 0.0), Vec3::from_f64(5.0, 1.0, 1.0), mat)));
+        let bvh = BVH::new(world);
+
+        let mut rec = HitRecord::new();
+        assert!(bvh.hit(down, 0.001, f64::INFINITY, &mut rec));
+        assert!((rec.t - 1.0).abs() < 1e-9);
     }
 }
@@ -1,19 +1,19 @@
-use rand::{thread_rng, Rng, distributions::Uniform};
 use crate::vec3::Vec3;
+use crate::sampler;
 use std::f64::consts::PI;
 
+// All randomness goes through the thread's current sampler.
 pub fn random_double() -> f64 {
-    thread_rng().gen()
+    sampler::next_1d()
 }
 
-pub fn random_int(min: i32, max: i32) -> i32 {
-    let between = Uniform::new(min, max);
-    thread_rng().sample(between)
+pub fn random_range(min: f64, max: f64) -> f64 {
+    min + (max - min)*random_double()
 }
 
-pub fn random_range(min: f64, max: f64) -> f64 {
-    let between = Uniform::new(min, max);
-    thread_rng().sample(between)
+// Two dimensions that belong together, such as a point on the lens.
+pub fn random_2d() -> (f64, f64) {
+    sampler::next_2d()
 }
 
 pub fn refract(uv: &Vec3, n: &Vec3, k: f64) -> Vec3 {
@@ -30,11 +30,38 @@ pub fn deg_to_rad(deg: f64) -> f64 {
     deg / 180.0 * PI
 }
 
+// Shirley-Chiu concentric mapping, which keeps the strata of the 2D sample.
 pub fn random_unit_disk() -> Vec3 {
-    loop {
-        let p = Vec3::from_f64(random_range(-1.0, 1.0), random_range(-1.0, 1.0), 0.0);
-        if p.length_squared() <= 1.0 {
-            return p;
-        }
+    let (u1, u2) = random_2d();
+    let (a, b) = (2.0*u1 - 1.0, 2.0*u2 - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return Vec3::new();
     }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI/4.0 * (b / a))
+    } else {
+        (b, PI/2.0 - PI/4.0 * (a / b))
+    };
+    Vec3::from_f64(r*theta.cos(), r*theta.sin(), 0.0)
+}
+
+pub fn uniform_sphere_direction() -> Vec3 {
+    let (u1, u2) = random_2d();
+    let z = 1.0 - 2.0*u1;
+    let r = (1.0 - z*z).max(0.0).sqrt();
+    let phi = 2.0*PI*u2;
+
+    Vec3::from_f64(r*phi.cos(), r*phi.sin(), z)
+}
+
+// Cosine-weighted direction around +z, for use with an ONB.
+pub fn random_cosine_direction() -> Vec3 {
+    let (r1, r2) = random_2d();
+
+    let phi = 2.0*PI*r1;
+    let z = (1.0 - r2).sqrt();
+    let r = r2.sqrt();
+
+    Vec3::from_f64(phi.cos()*r, phi.sin()*r, z)
 }
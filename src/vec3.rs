@@ -73,7 +73,7 @@ impl Vec3 {
     }
 
     pub fn random_unit_vector() -> Vec3 {
-        Vec3::unit(&Vec3::random_unit_sphere())
+        util::uniform_sphere_direction()
     }
 
     pub fn random_in_hemisphere(normal: &Vec3) -> Vec3 {